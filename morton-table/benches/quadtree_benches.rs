@@ -1,9 +1,13 @@
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use morton_table::arena_quadtree::ArenaQuadtree;
+use morton_table::morton_table::morton_key::MortonKey;
+use morton_table::morton_table::sorting::{radix_sort, sort, sort_indirect, sort_sequential};
 use morton_table::morton_table::MortonTable;
 use morton_table::quadtree::Quadtree;
 use morton_table::{Point, Value};
 use rand::RngCore;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rayon::prelude::*;
 #[cfg(target_arch = "x86")]
 use std::arch::x86::*;
 #[cfg(target_arch = "x86_64")]
@@ -48,6 +52,34 @@ fn contains_rand(c: &mut Criterion) {
     group.finish();
 }
 
+fn contains_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contains_batch");
+    let mut rng = get_rand();
+    for size in (8..16).step_by(2) {
+        let size = 1 << size;
+
+        let table = MortonTable::from_iterator((0..size).map(|i| {
+            let p = Point::new(rng.gen_range(0, 3000), rng.gen_range(0, 3000));
+            (p, Value(i))
+        }));
+        // the tiles around a few objects, as in a broad phase
+        let points = (0..16)
+            .flat_map(|_| {
+                let [x, y] = [rng.gen_range(0, 2990), rng.gen_range(0, 2990)];
+                (0..64).map(move |i| Point::new(x + i % 8, y + i / 8))
+            })
+            .collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("contains_key", size), &size, |b, _| {
+            b.iter(|| points.iter().filter(|p| table.contains_key(p)).count())
+        });
+        group.bench_with_input(BenchmarkId::new("contains_bitset", size), &size, |b, _| {
+            b.iter(|| table.contains_bitset(&points))
+        });
+    }
+    group.finish();
+}
+
 fn get_entities_in_range_sparse(c: &mut Criterion) {
     let mut group = c.benchmark_group("find_in_range_sparse");
     let mut rng = get_rand();
@@ -90,6 +122,19 @@ fn get_entities_in_range_sparse(c: &mut Criterion) {
             let mut rng = get_rand();
             let table = Quadtree::from_iterator(items.iter().cloned());
 
+            let mut res = Vec::new();
+            b.iter(|| {
+                let table = &table;
+                let p = Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800));
+                table.find_in_range(&p, radius, &mut res);
+                black_box(&res);
+                res.clear();
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("ArenaQuadtree", size), &size, |b, _| {
+            let mut rng = get_rand();
+            let table = ArenaQuadtree::from_iterator(items.iter().cloned());
+
             let mut res = Vec::new();
             b.iter(|| {
                 let table = &table;
@@ -151,6 +196,23 @@ fn get_entities_in_range_sparse_cold_cache(c: &mut Criterion) {
                 }
             });
         });
+        group.bench_with_input(BenchmarkId::new("ArenaQuadtree", size), &size, |b, _| {
+            let mut rng = get_rand();
+            let table = ArenaQuadtree::from_iterator(items.iter().cloned());
+            let mut res = Vec::new();
+
+            b.iter(|| {
+                let p = Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800));
+                table.find_in_range(&p, radius, &mut res);
+                black_box(&res);
+                res.clear();
+                // flush the cache
+                unsafe {
+                    _mm_clflush(table.nodes.as_ptr() as *const u8);
+                    _mm_clflush(&table as *const _ as *const u8);
+                }
+            });
+        });
     }
     group.finish();
 }
@@ -208,6 +270,145 @@ fn get_entities_in_range_dense(c: &mut Criterion) {
     group.finish();
 }
 
+fn find_in_aabb(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_in_aabb");
+    let mut rng = get_rand();
+    // the same number of items spread over the whole table and packed into a corner
+    for (name, extent) in [("sparse", 1 << 15), ("dense", 512)].iter() {
+        let extent = *extent;
+        let table = MortonTable::from_iterator((0..1 << 16).map(|i| {
+            let p = Point::new(rng.gen_range(0, extent), rng.gen_range(0, extent));
+            (p, Value(i))
+        }));
+        // the number of Morton intervals of a box grows with its perimeter
+        for side in [8, 64, 512, 4096].iter().filter(|side| **side < extent) {
+            group.bench_with_input(BenchmarkId::new(*name, side), side, |b, side| {
+                let mut rng = get_rand();
+                let mut res = Vec::new();
+                b.iter(|| {
+                    let min = Point::new(
+                        rng.gen_range(0, extent - side),
+                        rng.gen_range(0, extent - side),
+                    );
+                    let max = Point::new(min[0] + side - 1, min[1] + side - 1);
+                    res.clear();
+                    table.find_in_aabb(&min, &max, &mut res);
+                    black_box(&res);
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+fn find_in_range_many_centers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_in_range_many_centers");
+    let mut rng = get_rand();
+    for size in (10..20).step_by(3) {
+        let size = 1 << size;
+        let table = MortonTable::from_iterator((0..size).map(|i| {
+            let p = Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800));
+            (p, Value(i))
+        }));
+        let centers = (0..4096)
+            .map(|_| Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800)))
+            .collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("loop", size), &size, |b, _| {
+            let mut out = vec![Vec::new(); centers.len()];
+            b.iter(|| {
+                for (center, res) in centers.iter().zip(out.iter_mut()) {
+                    res.clear();
+                    table.find_in_range(center, 50, res);
+                }
+                black_box(&out);
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("multi", size), &size, |b, _| {
+            let mut out = Vec::new();
+            b.iter(|| {
+                table.find_in_range_multi(&centers, 50, &mut out);
+                black_box(&out);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn find_in_range_split_threshold(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_in_range_split_threshold");
+    let mut rng = get_rand();
+    let radius = 50;
+    let size = 1 << 14;
+    let items: Vec<_> = (0..size)
+        .map(|_| {
+            let p = Point::new(rng.gen_range(0, 400), rng.gen_range(0, 400));
+            (p, Value(rng.gen()))
+        })
+        .collect();
+    for threshold in [4, 8, 16, 32, 64, 128, 256].iter() {
+        group.bench_with_input(
+            BenchmarkId::new("MortonTable", threshold),
+            threshold,
+            |b, &threshold| {
+                let table = MortonTable::from_iterator(items.iter().cloned())
+                    .with_split_threshold(threshold);
+                let mut rng = get_rand();
+
+                let mut res = Vec::new();
+                b.iter(|| {
+                    let table = &table;
+                    let p = Point::new(rng.gen_range(0, 400), rng.gen_range(0, 400));
+                    table.find_in_range(&p, radius, &mut res);
+                    black_box(&res);
+                    res.clear();
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn find_in_range_after_churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_in_range_after_churn");
+    let mut rng = get_rand();
+    let radius = 50;
+    for size in 8..16 {
+        let size = 1 << size;
+        let items: Vec<_> = (0..size)
+            .map(|_| {
+                let p = Point::new(rng.gen_range(0, 400), rng.gen_range(0, 400));
+                (p, Value(rng.gen()))
+            })
+            .collect();
+        // insert and delete a bunch of items one by one
+        let mut churned = MortonTable::new();
+        for (p, v) in items.iter() {
+            churned.insert(*p, *v).unwrap();
+        }
+        for (p, _) in items.iter().step_by(2) {
+            churned.delete(p);
+        }
+        let mut defragmented = churned.clone();
+        defragmented.defragment();
+
+        for (name, table) in [("churned", &churned), ("defragmented", &defragmented)].iter() {
+            group.bench_with_input(BenchmarkId::new(*name, size), &size, |b, _| {
+                let mut rng = get_rand();
+
+                let mut res = Vec::new();
+                b.iter(|| {
+                    let p = Point::new(rng.gen_range(0, 400), rng.gen_range(0, 400));
+                    table.find_in_range(&p, radius, &mut res);
+                    black_box(&res);
+                    res.clear();
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
 fn make_table(c: &mut Criterion) {
     let mut group = c.benchmark_group("make_table");
     let mut rng = get_rand();
@@ -227,6 +428,28 @@ fn make_table(c: &mut Criterion) {
                 table
             });
         });
+        group.bench_with_input(
+            BenchmarkId::new("MortonTable_from_slice", size),
+            &size,
+            |b, _| {
+                b.iter(|| {
+                    let mut table = MortonTable::new();
+                    table.extend_from_slice(&items);
+                    table
+                });
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("MortonTable_par_extend", size),
+            &size,
+            |b, _| {
+                b.iter(|| {
+                    let mut table = MortonTable::new();
+                    table.par_extend(items.par_iter().cloned());
+                    table
+                });
+            },
+        );
         group.bench_with_input(BenchmarkId::new("Quadtree", size), &size, |b, _| {
             b.iter(|| {
                 let table = Quadtree::from_iterator(items.iter().cloned());
@@ -237,6 +460,147 @@ fn make_table(c: &mut Criterion) {
     group.finish();
 }
 
+fn quadtree_to_morton_table(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quadtree_to_morton_table");
+    let mut rng = get_rand();
+    for size in (8..16).step_by(2) {
+        let size = 1 << size;
+        let items: Vec<_> = (0..size)
+            .map(|_| {
+                (
+                    Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800)),
+                    Value(rng.next_u32()),
+                )
+            })
+            .collect();
+        let tree = Quadtree::from_iterator(items.iter().cloned());
+
+        group.bench_with_input(BenchmarkId::new("to_morton_table", size), &size, |b, _| {
+            b.iter(|| tree.to_morton_table());
+        });
+        // the same items in random order
+        group.bench_with_input(BenchmarkId::new("from_slice", size), &size, |b, _| {
+            b.iter(|| {
+                let mut table = MortonTable::new();
+                table.extend_from_slice(&items);
+                table
+            });
+        });
+    }
+    group.finish();
+}
+
+fn sort_keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sort_keys");
+    let mut rng = get_rand();
+    for size in 8..16 {
+        let size = 1 << size;
+        let items: Vec<_> = (0..size)
+            .map(|_| {
+                let p = Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800));
+                (
+                    MortonKey::new(p[0] as u16, p[1] as u16),
+                    p,
+                    Value(rng.next_u32()),
+                )
+            })
+            .collect();
+        let keys = items.iter().map(|(k, _, _)| *k).collect::<Vec<_>>();
+        let positions = items.iter().map(|(_, p, _)| *p).collect::<Vec<_>>();
+        let values = items.iter().map(|(_, _, v)| *v).collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("quicksort", size), &size, |b, _| {
+            b.iter_batched_ref(
+                || (keys.clone(), positions.clone(), values.clone()),
+                |(k, p, v)| sort(k, p, v),
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_with_input(
+            BenchmarkId::new("quicksort_sequential", size),
+            &size,
+            |b, _| {
+                b.iter_batched_ref(
+                    || (keys.clone(), positions.clone(), values.clone()),
+                    |(k, p, v)| sort_sequential(k, p, v),
+                    BatchSize::SmallInput,
+                );
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("sort_indirect", size), &size, |b, _| {
+            b.iter_batched_ref(
+                || (keys.clone(), positions.clone(), values.clone()),
+                |(k, p, v)| sort_indirect(k, p, v),
+                BatchSize::SmallInput,
+            );
+        });
+        group.bench_with_input(BenchmarkId::new("radix_sort", size), &size, |b, _| {
+            b.iter_batched_ref(
+                || (keys.clone(), positions.clone(), values.clone()),
+                |(k, p, v)| radix_sort(k, p, v),
+                BatchSize::SmallInput,
+            );
+        });
+
+        // a lightly mutated table: sorted, then every 64th key is replaced by a random one
+        let mut nearly_sorted = items.clone();
+        nearly_sorted.sort_by_key(|(k, _, _)| *k);
+        for (k, p, _) in nearly_sorted.iter_mut().step_by(64) {
+            *p = Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800));
+            *k = MortonKey::new(p[0] as u16, p[1] as u16);
+        }
+        let keys = nearly_sorted.iter().map(|(k, _, _)| *k).collect::<Vec<_>>();
+        let positions = nearly_sorted.iter().map(|(_, p, _)| *p).collect::<Vec<_>>();
+        let values = nearly_sorted.iter().map(|(_, _, v)| *v).collect::<Vec<_>>();
+
+        group.bench_with_input(
+            BenchmarkId::new("quicksort_nearly_sorted", size),
+            &size,
+            |b, _| {
+                b.iter_batched_ref(
+                    || (keys.clone(), positions.clone(), values.clone()),
+                    |(k, p, v)| sort(k, p, v),
+                    BatchSize::SmallInput,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+fn sort_large_values(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sort_large_values");
+    let mut rng = get_rand();
+    for size in (8..16).step_by(2) {
+        let size = 1 << size;
+        let positions = (0..size)
+            .map(|_| Point::new(rng.gen_range(0, 7800), rng.gen_range(0, 7800)))
+            .collect::<Vec<_>>();
+        let keys = positions
+            .iter()
+            .map(|p| MortonKey::new(p[0] as u16, p[1] as u16))
+            .collect::<Vec<_>>();
+        // 256 bytes per value
+        let values = (0..size).map(|i| [i as u64; 32]).collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("quicksort", size), &size, |b, _| {
+            b.iter_batched_ref(
+                || (keys.clone(), positions.clone(), values.clone()),
+                |(k, p, v)| sort(k, p, v),
+                BatchSize::LargeInput,
+            );
+        });
+        group.bench_with_input(BenchmarkId::new("sort_indirect", size), &size, |b, _| {
+            b.iter_batched_ref(
+                || (keys.clone(), positions.clone(), values.clone()),
+                |(k, p, v)| sort_indirect(k, p, v),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
 fn rebuild_table(c: &mut Criterion) {
     let mut group = c.benchmark_group("rebuild_table");
     let mut rng = get_rand();
@@ -385,17 +749,55 @@ fn random_insert(c: &mut Criterion) {
     group.finish();
 }
 
+fn delete_rand(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete_random");
+    let mut rng = get_rand();
+    for size in 8..16 {
+        let size = 1 << size;
+        let items: Vec<_> = (0..size)
+            .map(|_| {
+                let pos = Point::new(rng.gen_range(0, 3900), rng.gen_range(0, 3900));
+                (pos, Value(rng.next_u32()))
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("MortonTable", size), &size, |b, _| {
+            let mut rng = get_rand();
+            let table = MortonTable::from_iterator(items.iter().cloned());
+
+            b.iter_batched_ref(
+                || {
+                    let i = rng.gen_range(0, items.len());
+                    (table.clone(), items[i].0)
+                },
+                |(table, pos)| table.delete(pos),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     quadtree_benches,
     contains_rand,
+    contains_batch,
     get_entities_in_range_sparse,
     get_entities_in_range_sparse_cold_cache,
     get_entities_in_range_dense,
+    find_in_aabb,
+    find_in_range_many_centers,
+    find_in_range_split_threshold,
+    find_in_range_after_churn,
     make_table,
+    quadtree_to_morton_table,
+    sort_keys,
+    sort_large_values,
     random_insert,
     rebuild_table,
     get_by_id_in_table_rand,
     get_by_id_rand,
+    delete_rand,
 );
 
 criterion_main!(quadtree_benches);
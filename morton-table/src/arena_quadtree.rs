@@ -0,0 +1,402 @@
+//! Quadtree storing all of its nodes in a single `Vec`.
+//! Children are referenced by index instead of `Box`, so the tree does not chase pointers all
+//! over the heap and the four children of a node are always adjacent in memory.
+use crate::{bounds, Point, Value};
+use arrayvec::ArrayVec;
+
+const LEN_CHILDREN: usize = 16;
+
+#[derive(Debug, Clone)]
+pub enum Body<T> {
+    /// Index of the first of the 4 consecutive children
+    Children(u32),
+    Items(ArrayVec<[(Point, T); LEN_CHILDREN]>),
+    /// Leaf of a node that is too small to be split, e.g. because many items share a coordinate.
+    Overflow(Vec<(Point, T)>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Node<T> {
+    // bounds as an AABB
+    from: Point,
+    to: Point,
+    body: Body<T>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArenaQuadtree<T = Value> {
+    // the root is always the first node
+    // public so I can flush the cache in benchmarks
+    pub nodes: Vec<Node<T>>,
+}
+
+impl<T> Default for ArenaQuadtree<T> {
+    fn default() -> Self {
+        Self::new(Point::new(0, 0), Point::new(0xffff, 0xffff))
+    }
+}
+
+impl<T> Node<T> {
+    fn new(from: Point, to: Point) -> Self {
+        Self {
+            from,
+            to,
+            body: Body::Items(ArrayVec::new()),
+        }
+    }
+
+    fn intersects(&self, point: &Point) -> bool {
+        bounds::contains(&self.from, &self.to, point)
+    }
+
+    fn intersects_aabb(&self, from: &Point, to: &Point) -> bool {
+        bounds::intersects(&self.from, &self.to, from, to)
+    }
+
+    fn can_split(&self) -> bool {
+        bounds::can_split(&self.from, &self.to)
+    }
+
+    fn items(&self) -> Option<&[(Point, T)]> {
+        match &self.body {
+            Body::Items(items) => Some(items),
+            Body::Overflow(items) => Some(items),
+            Body::Children(_) => None,
+        }
+    }
+}
+
+impl<T> ArenaQuadtree<T> {
+    pub fn new(from: Point, to: Point) -> Self {
+        assert!(from[0] <= to[0]);
+        assert!(from[1] <= to[1]);
+        Self {
+            nodes: vec![Node::new(from, to)],
+        }
+    }
+
+    /// Like `new`, but stores the nodes in `nodes`, reusing its allocation, e.g. the buffer of a
+    /// tree of the previous frame returned by `into_nodes`. The nodes in the buffer are dropped.
+    ///
+    /// This is the stable stand-in for an allocator parameter, which needs the unstable allocator
+    /// API.
+    pub fn new_in(from: Point, to: Point, mut nodes: Vec<Node<T>>) -> Self {
+        assert!(from[0] <= to[0]);
+        assert!(from[1] <= to[1]);
+        nodes.clear();
+        nodes.push(Node::new(from, to));
+        Self { nodes }
+    }
+
+    /// Consume the tree and return the buffer of its nodes, to build the next tree in it with
+    /// `new_in`.
+    pub fn into_nodes(self) -> Vec<Node<T>> {
+        self.nodes
+    }
+
+    /// Remove all items, keeping the bounds of the tree.
+    pub fn clear(&mut self) {
+        self.nodes.truncate(1);
+        self.nodes[0].body = Body::Items(ArrayVec::new());
+    }
+
+    pub fn from_iterator<It>(it: It) -> Self
+    where
+        It: Iterator<Item = (Point, T)>,
+    {
+        let values = it.collect::<Vec<_>>();
+        // calculate the minimum bounding box to speed up queries by having a more balanced tree
+        let mut tree = match bounds::tight_bounds(&values) {
+            Some((min, max)) => Self::new(min, max),
+            None => Self::default(),
+        };
+        tree.extend(values.into_iter());
+        tree
+    }
+
+    pub fn extend<It>(&mut self, it: It)
+    where
+        It: Iterator<Item = (Point, T)>,
+    {
+        for (p, v) in it {
+            self.insert(p, v).unwrap();
+        }
+    }
+
+    /// Returns `Err` if the insertion failed.
+    pub fn insert(&mut self, point: Point, value: T) -> Result<(), Point> {
+        if !self.intersects(&point) {
+            // point is out of bounds
+            return Err(point);
+        }
+        self.insert_at(0, point, value);
+        Ok(())
+    }
+
+    /// Insert into the subtree at `index`, the node must contain the point.
+    fn insert_at(&mut self, mut index: usize, point: Point, value: T) {
+        loop {
+            let node = &mut self.nodes[index];
+            debug_assert!(node.intersects(&point));
+            match &mut node.body {
+                Body::Children(first) => {
+                    let first = *first as usize;
+                    // descend into the first child that can accept this node.
+                    index = (first..first + 4)
+                        .find(|i| self.nodes[*i].intersects(&point))
+                        .expect("All insertions failed");
+                }
+                Body::Items(items) => {
+                    let (point, value) = match items.try_push((point, value)) {
+                        // there was capacity left in this node. We're done.
+                        Ok(()) => return,
+                        Err(err) => err.element(),
+                    };
+                    if node.can_split() {
+                        self.split(index);
+                    } else {
+                        let items = match &mut node.body {
+                            Body::Items(items) => items.drain(..).collect(),
+                            _ => unreachable!(),
+                        };
+                        node.body = Body::Overflow(items);
+                    }
+                    return self.insert_at(index, point, value);
+                }
+                Body::Overflow(items) => {
+                    items.push((point, value));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn split(&mut self, index: usize) {
+        let first = self.nodes.len();
+        let node = &mut self.nodes[index];
+
+        let [fromx, fromy] = *node.from;
+        let [tox, toy] = *node.to;
+
+        let radius_x = (tox - fromx) / 2;
+        let radius_y = (toy - fromy) / 2;
+
+        let mut body = Body::Children(first as u32);
+        std::mem::swap(&mut body, &mut node.body);
+
+        // split each axis of the bounds in half.
+        // | child3 | child0 |
+        // | ------ | ------ |
+        // | child2 | child1 |
+        self.nodes.extend(vec![
+            Node::new(
+                Point::new(fromx + radius_x, fromy),
+                Point::new(tox, fromy + radius_y),
+            ),
+            Node::new(
+                Point::new(fromx + radius_x, fromy + radius_y),
+                Point::new(tox, toy),
+            ),
+            Node::new(
+                Point::new(fromx, fromy + radius_y),
+                Point::new(fromx + radius_x, toy),
+            ),
+            Node::new(
+                Point::new(fromx, fromy),
+                Point::new(fromx + radius_x, fromy + radius_y),
+            ),
+        ]);
+
+        if let Body::Items(items) = body {
+            for (p, v) in items.into_iter() {
+                self.insert_at(index, p, v);
+            }
+        } else {
+            unreachable!("Trying to split a node that's not a bounded leaf")
+        }
+    }
+
+    pub fn intersects(&self, point: &Point) -> bool {
+        self.nodes[0].intersects(point)
+    }
+
+    pub fn find_in_range<'a>(&'a self, center: &Point, radius: u32, out: &mut Vec<&'a (Point, T)>) {
+        // calculate the bounding box of the circle
+        let aabb = [
+            Point::new(
+                center[0].saturating_sub(radius),
+                center[1].saturating_sub(radius),
+            ),
+            Point::new(
+                center[0].saturating_add(radius),
+                center[1].saturating_add(radius),
+            ),
+        ];
+        let radius_sq = radius as u64 * radius as u64;
+
+        self.find_in_range_impl(0, center, radius_sq, &aabb, out);
+    }
+
+    fn find_in_range_impl<'a>(
+        &'a self,
+        index: usize,
+        center: &Point,
+        radius_sq: u64,
+        aabb: &[Point; 2],
+        out: &mut Vec<&'a (Point, T)>,
+    ) {
+        let node = &self.nodes[index];
+        if !node.intersects_aabb(&aabb[0], &aabb[1]) {
+            // if the node does not contain the aabb, then it can't intersect this circle either
+            return;
+        }
+
+        match node.items() {
+            Some(items) => {
+                // insert all items that are within the circle
+                for p in items.iter() {
+                    if p.0.dist_sq(center) <= radius_sq {
+                        out.push(p);
+                    }
+                }
+            }
+            None => {
+                if let Body::Children(first) = node.body {
+                    let first = first as usize;
+                    for child in first..first + 4 {
+                        self.find_in_range_impl(child, center, radius_sq, aabb, out);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the leaf that would hold `point`, if `point` is in bounds.
+    fn find_leaf(&self, point: &Point) -> Option<&Node<T>> {
+        let mut node = &self.nodes[0];
+        if !node.intersects(point) {
+            return None;
+        }
+        while let Body::Children(first) = node.body {
+            let first = first as usize;
+            node = self.nodes[first..first + 4]
+                .iter()
+                .find(|child| child.intersects(point))?;
+        }
+        Some(node)
+    }
+
+    pub fn get_by_id<'a>(&'a self, point: &Point) -> Option<&'a T> {
+        self.find_leaf(point)?
+            .items()?
+            .iter()
+            .find(|p| p.0 == *point)
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, point: &Point) -> bool {
+        self.get_by_id(point).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quadtree::Quadtree;
+    use rand::prelude::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn find_in_range_matches_quadtree() {
+        let mut rng = rand::thread_rng();
+
+        let items = (0..1024)
+            .map(|i| {
+                let p = Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512));
+                (p, Value(i))
+            })
+            .collect::<Vec<_>>();
+        let arena = ArenaQuadtree::from_iterator(items.iter().cloned());
+        let tree = Quadtree::from_iterator(items.iter().cloned());
+
+        let mut expected = Vec::new();
+        let mut res = Vec::new();
+        for _ in 0..256 {
+            let center = Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512));
+            let radius = rng.gen_range(0, 128);
+
+            tree.find_in_range(&center, radius, &mut expected);
+            arena.find_in_range(&center, radius, &mut res);
+
+            let expected_set = expected.drain(..).cloned().collect::<HashSet<_>>();
+            let res_set = res.drain(..).cloned().collect::<HashSet<_>>();
+            assert_eq!(res_set, expected_set);
+        }
+    }
+
+    #[test]
+    fn get_by_id() {
+        let mut rng = rand::thread_rng();
+
+        let mut table = ArenaQuadtree::new(Point::new(0, 0), Point::new(128, 128));
+
+        let mut points = HashSet::with_capacity(64);
+        while points.len() < 64 {
+            let p = Point::new(rng.gen_range(0, 128), rng.gen_range(0, 128));
+            points.insert(p);
+        }
+
+        for p in points.iter() {
+            table.insert(*p, Value(1000 * p[0] + p[1])).unwrap();
+        }
+
+        for p in points {
+            assert_eq!(table.get_by_id(&p), Some(&Value(1000 * p[0] + p[1])));
+        }
+        assert_eq!(table.get_by_id(&Point::new(200, 0)), None);
+    }
+
+    #[test]
+    fn coincident_points_do_not_split_forever() {
+        let mut table = ArenaQuadtree::new(Point::new(0, 0), Point::new(128, 128));
+
+        for i in 0..100 {
+            table.insert(Point::new(5, 5), Value(i)).unwrap();
+        }
+
+        let mut res = Vec::new();
+        table.find_in_range(&Point::new(5, 5), 0, &mut res);
+        assert_eq!(res.len(), 100);
+
+        table.clear();
+        assert_eq!(table.nodes.len(), 1);
+        assert!(!table.contains_key(&Point::new(5, 5)));
+    }
+
+    #[test]
+    fn new_in_reuses_the_buffer() {
+        let mut tree = ArenaQuadtree::new(Point::new(0, 0), Point::new(128, 128));
+        for i in 0..128 {
+            tree.insert(Point::new(i, 128 - i), Value(i)).unwrap();
+        }
+        let nodes = tree.into_nodes();
+        let (ptr, capacity) = (nodes.as_ptr(), nodes.capacity());
+        assert!(capacity > 1);
+
+        let mut tree = ArenaQuadtree::new_in(Point::new(0, 0), Point::new(64, 64), nodes);
+        assert_eq!(tree.nodes.len(), 1);
+        assert!(!tree.contains_key(&Point::new(0, 128)));
+        for i in 0..64 {
+            tree.insert(Point::new(i, i), Value(i)).unwrap();
+        }
+        assert_eq!(tree.get_by_id(&Point::new(5, 5)), Some(&Value(5)));
+        assert_eq!(
+            tree.insert(Point::new(65, 0), Value(0)),
+            Err(Point::new(65, 0))
+        );
+
+        let nodes = tree.into_nodes();
+        assert!(nodes.len() <= capacity);
+        assert_eq!(nodes.as_ptr(), ptr);
+    }
+}
@@ -0,0 +1,38 @@
+//! Inclusive axis aligned bounding boxes, shared by `Quadtree` and `ArenaQuadtree`.
+use crate::Point;
+
+/// Whether `point` is in the box `[from, to]`.
+pub fn contains(from: &Point, to: &Point, point: &Point) -> bool {
+    let [x, y] = **point;
+
+    from[0] <= x && from[1] <= y && x <= to[0] && y <= to[1]
+}
+
+/// Whether the boxes `[from, to]` and `[min, max]` overlap.
+pub fn intersects(from: &Point, to: &Point, min: &Point, max: &Point) -> bool {
+    // separating axis test
+    if to[0] < min[0] || from[0] > max[0] {
+        return false;
+    }
+    if to[1] < min[1] || from[1] > max[1] {
+        return false;
+    }
+    true
+}
+
+/// Splitting a node of at most 2x2 would produce a child with the same bounds as the parent.
+pub fn can_split(from: &Point, to: &Point) -> bool {
+    to[0] - from[0] >= 2 || to[1] - from[1] >= 2
+}
+
+/// The minimum bounding box of the items, `None` if there are none.
+pub fn tight_bounds<T>(items: &[(Point, T)]) -> Option<(Point, Point)> {
+    let (first, _) = items.first()?;
+    let (min, max) = items.iter().fold((**first, **first), |(min, max), (p, _)| {
+        (
+            [min[0].min(p[0]), min[1].min(p[1])],
+            [max[0].max(p[0]), max[1].max(p[1])],
+        )
+    });
+    Some((Point(min), Point(max)))
+}
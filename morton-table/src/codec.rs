@@ -0,0 +1,73 @@
+//! 2D Morton encoding, independent of any container.
+
+/// Interleave the bits of `x` and `y` into a single Morton code.
+/// The bits of `x` take the even, the bits of `y` the odd positions.
+pub fn morton_encode_2d(x: u16, y: u16) -> u32 {
+    partition(x as u32) | (partition(y as u32) << 1)
+}
+
+/// Inverse of `morton_encode_2d`.
+pub fn morton_decode_2d(key: u32) -> (u16, u16) {
+    let x = reconstruct(key);
+    let y = reconstruct(key >> 1);
+    (x as u16, y as u16)
+}
+
+fn partition(mut n: u32) -> u32 {
+    // n = ----------------fedcba9876543210 : Bits initially
+    // n = --------fedcba98--------76543210 : After (1)
+    // n = ----fedc----ba98----7654----3210 : After (2)
+    // n = --fe--dc--ba--98--76--54--32--10 : After (3)
+    // n = -f-e-d-c-b-a-9-8-7-6-5-4-3-2-1-0 : After (4)
+    n = (n ^ (n << 8)) & 0x00ff00ff; // (1)
+    n = (n ^ (n << 4)) & 0x0f0f0f0f; // (2)
+    n = (n ^ (n << 2)) & 0x33333333; // (3)
+    (n ^ (n << 1)) & 0x55555555 // (4)
+}
+
+fn reconstruct(mut n: u32) -> u32 {
+    // -f-e-d-c-b-a-9-8-7-6-5-4-3-2-1-0 : After (1)
+    // -ffeeddccbbaa9988776655443322110 : After (2)
+    // --fe--dc--ba--98--76--54--32--10 : After (3)
+    // --fefedcdcbaba989876765454323210 : After (4)
+    // ----fedc----ba98----7654----3210 : After (5)
+    // ----fedcfedcba98ba98765476543210 : After (6)
+    // --------fedcba98--------76543210 : After (7)
+    // --------fedcba98fedcba9876543210 : After (8)
+    // ----------------fedcba9876543210 : After (9)
+    n &= 0x55555555; // (1)
+    n |= n >> 1; // (2)
+    n &= 0x33333333; // (3)
+    n |= n >> 2; // (4)
+    n &= 0x0f0f0f0f; // (5)
+    n |= n >> 4; // (6)
+    n &= 0x00ff00ff; // (7)
+    n |= n >> 8; // (8)
+    n & 0x0000ffff // (9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    #[test]
+    fn encode_interleaves_bits() {
+        assert_eq!(morton_encode_2d(0, 0), 0);
+        assert_eq!(morton_encode_2d(1, 0), 0b01);
+        assert_eq!(morton_encode_2d(0, 1), 0b10);
+        assert_eq!(morton_encode_2d(0b11, 0b01), 0b0111);
+        assert_eq!(morton_encode_2d(0xffff, 0xffff), 0xffffffff);
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..(1 << 12) {
+            let x = rng.gen();
+            let y = rng.gen();
+            assert_eq!(morton_decode_2d(morton_encode_2d(x, y)), (x, y));
+        }
+    }
+}
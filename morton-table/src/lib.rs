@@ -2,14 +2,52 @@
 //! # Contracts:
 //! - Key axis must be an integer in the interval [0, 2^16)
 //!
-pub mod quadtree;
+pub mod arena_quadtree;
+mod bounds;
+pub mod codec;
 pub mod morton_table;
+pub mod quadtree;
 
+use codec::morton_encode_2d;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
 use std::ops::{Add, AddAssign, Deref};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Point(pub [u32; 2]);
 
+impl Hash for Point {
+    /// Points within the key space feed their Morton key to the hasher, a single word instead of
+    /// two plus the length of the array.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let [x, y] = self.0;
+        if x <= 0xffff && y <= 0xffff {
+            state.write_u32(morton_encode_2d(x as u16, y as u16));
+        } else {
+            state.write_u32(x);
+            state.write_u32(y);
+        }
+    }
+}
+
+/// Points are ordered by their Morton key, i.e. in Z-order, not lexicographically. Sorting a list
+/// of points, or using them as the keys of a `BTreeMap`, keeps nearby points close together.
+///
+/// Points outside of the 16 bit key space are ordered by the Morton keys of their high halves
+/// first, so the order is total and consistent with `Eq`.
+impl Ord for Point {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.z_order_key().cmp(&other.z_order_key())
+    }
+}
+
+impl PartialOrd for Point {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl AddAssign for Point {
     fn add_assign(&mut self, p: Self) {
         self.0[0] += p.0[0];
@@ -38,6 +76,138 @@ impl Point {
         Self([x, y])
     }
 
+    /// The 64 bit interleaving of both axes. Equals the Morton key for points in the key space.
+    fn z_order_key(&self) -> u64 {
+        let [x, y] = self.0;
+        let high = morton_encode_2d((x >> 16) as u16, (y >> 16) as u16);
+        let low = morton_encode_2d(x as u16, y as u16);
+        (high as u64) << 32 | low as u64
+    }
+
+    /// Add `rhs` to this point, clamping each axis at `u32::MAX` instead of overflowing
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self([
+            self.0[0].saturating_add(rhs.0[0]),
+            self.0[1].saturating_add(rhs.0[1]),
+        ])
+    }
+
+    /// Add `rhs` to this point, returning `None` if either axis overflows
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        let x = self.0[0].checked_add(rhs.0[0])?;
+        let y = self.0[1].checked_add(rhs.0[1])?;
+        Some(Self([x, y]))
+    }
+
+    /// Floor each axis to a multiple of `cell`. `cell` does not have to be a power of two.
+    /// Returns the point unchanged if `cell` is 0.
+    pub fn snap_to_grid(self, cell: u32) -> Self {
+        if cell == 0 {
+            return self;
+        }
+        Self([self.0[0] - self.0[0] % cell, self.0[1] - self.0[1] % cell])
+    }
+
+    /// Mirror the point across the vertical line `x = axis`.
+    ///
+    /// Saturates at the edges of the coordinate space, e.g. at 0 if the mirrored point would be
+    /// negative. A saturated point does not reflect back to the original, use `checked_reflect_x`
+    /// to detect this.
+    pub fn reflect_x(self, axis: u32) -> Self {
+        Self([saturate(reflect(self.0[0], axis)), self.0[1]])
+    }
+
+    /// Mirror the point across the horizontal line `y = axis`.
+    ///
+    /// Saturates at the edges of the coordinate space like `reflect_x`.
+    pub fn reflect_y(self, axis: u32) -> Self {
+        Self([self.0[0], saturate(reflect(self.0[1], axis))])
+    }
+
+    /// Mirror the point across the vertical line `x = axis`, `None` if the mirrored point is out
+    /// of the coordinate space.
+    pub fn checked_reflect_x(self, axis: u32) -> Option<Self> {
+        let x = u32::try_from(reflect(self.0[0], axis)).ok()?;
+        Some(Self([x, self.0[1]]))
+    }
+
+    /// Mirror the point across the horizontal line `y = axis`, `None` if the mirrored point is
+    /// out of the coordinate space.
+    pub fn checked_reflect_y(self, axis: u32) -> Option<Self> {
+        let y = u32::try_from(reflect(self.0[1], axis)).ok()?;
+        Some(Self([self.0[0], y]))
+    }
+
+    /// Rotate the point by 90 degrees counter-clockwise around `center`.
+    /// Each axis saturates at the edges of the coordinate space.
+    pub fn rotate90(self, center: Point) -> Self {
+        let [x, y] = [self.0[0] as i64, self.0[1] as i64];
+        let [cx, cy] = [center.0[0] as i64, center.0[1] as i64];
+        let clamp = |n: i64| n.max(0).min(u32::MAX as i64) as u32;
+        Self([clamp(cx - (y - cy)), clamp(cy + (x - cx))])
+    }
+
+    /// Linearly interpolate between `self` and `other` at `t_num / t_den`, rounding towards
+    /// `self`. Values of `t` outside of `[0, 1]` extrapolate, saturating at the edges of the
+    /// coordinate space.
+    ///
+    /// Panics if `t_den` is 0.
+    pub fn lerp(self, other: Point, t_num: u32, t_den: u32) -> Self {
+        assert!(t_den != 0);
+        let lerp = |a: u32, b: u32| {
+            let d = (b as i128 - a as i128) * t_num as i128 / t_den as i128;
+            (a as i128 + d).max(0).min(u32::MAX as i128) as u32
+        };
+        Self([lerp(self.0[0], other.0[0]), lerp(self.0[1], other.0[1])])
+    }
+
+    /// The point halfway between `self` and `other`, rounding each axis down.
+    /// Does not overflow for any pair of points.
+    pub fn midpoint(self, other: Point) -> Self {
+        let mid = |a: u32, b: u32| ((a as u64 + b as u64) / 2) as u32;
+        Self([mid(self.0[0], other.0[0]), mid(self.0[1], other.0[1])])
+    }
+
+    /// Iterate over the grid points on the line from `self` to `other` using Bresenham's
+    /// algorithm. Both end points are included.
+    pub fn line_iter(self, other: Point) -> impl Iterator<Item = Point> {
+        let [mut x, mut y] = [self.0[0] as i64, self.0[1] as i64];
+        let [x1, y1] = [other.0[0] as i64, other.0[1] as i64];
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let sx = if x < x1 { 1 } else { -1 };
+        let sy = if y < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let res = Point::new(x as u32, y as u32);
+            if x == x1 && y == y1 {
+                done = true;
+                return Some(res);
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+            Some(res)
+        })
+    }
+
+    /// Exact squared euclidean distance
+    pub fn dist_sq(&self, rhs: &Self) -> u64 {
+        let x = self[0].abs_diff(rhs[0]) as u64;
+        let y = self[1].abs_diff(rhs[1]) as u64;
+        x * x + y * y
+    }
+
     pub fn dist(&self, rhs: &Self) -> u32 {
         let x = self[0] as i32 - rhs[0] as i32;
         let y = self[1] as i32 - rhs[1] as i32;
@@ -47,5 +217,287 @@ impl Point {
     }
 }
 
+/// The average of `points`, rounding each axis down. `None` if `points` is empty.
+///
+/// Sums in 64 bits, so it does not overflow for any realistic number of points.
+pub fn centroid(points: &[Point]) -> Option<Point> {
+    if points.is_empty() {
+        return None;
+    }
+    let [x, y] = points.iter().fold([0u64; 2], |[x, y], p| {
+        [x + p.0[0] as u64, y + p.0[1] as u64]
+    });
+    let len = points.len() as u64;
+    Some(Point::new((x / len) as u32, (y / len) as u32))
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct Value(pub u32);
+
+fn reflect(n: u32, axis: u32) -> i64 {
+    2 * axis as i64 - n as i64
+}
+
+fn saturate(n: i64) -> u32 {
+    n.max(0).min(u32::MAX as i64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashSet;
+
+    fn hash_of(p: &Point) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        p.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn hash_matches_eq() {
+        let points = [
+            Point::new(0, 0),
+            Point::new(1, 0),
+            Point::new(0, 1),
+            Point::new(0xffff, 0xffff),
+            Point::new(0x10000, 0),
+            Point::new(0, 0x10000),
+            Point::new(u32::MAX, u32::MAX),
+        ];
+        for p in points.iter() {
+            assert_eq!(hash_of(p), hash_of(&Point(p.0)));
+        }
+        let hashes = points.iter().map(hash_of).collect::<HashSet<_>>();
+        assert_eq!(hashes.len(), points.len());
+
+        let grid = (0..64)
+            .flat_map(|x| (0..64).map(move |y| Point::new(x, y)))
+            .collect::<HashSet<_>>();
+        assert_eq!(grid.len(), 64 * 64);
+        assert!(grid.contains(&Point::new(12, 34)));
+        assert!(!grid.contains(&Point::new(12, 64)));
+    }
+
+    #[test]
+    fn ord_is_z_order() {
+        let mut points = (0..16)
+            .flat_map(|x| (0..16).map(move |y| Point::new(x, y)))
+            .collect::<Vec<_>>();
+        points.sort();
+        for w in points.windows(2) {
+            assert!(w[0].to_morton_u32() < w[1].to_morton_u32());
+        }
+        assert_eq!(
+            &points[..4],
+            &[
+                Point::new(0, 0),
+                Point::new(1, 0),
+                Point::new(0, 1),
+                Point::new(1, 1),
+            ]
+        );
+
+        // consistent with Eq outside of the key space too
+        let big = [
+            Point::new(0x10000, 0),
+            Point::new(0, 0x10000),
+            Point::new(0x10001, 0),
+            Point::new(u32::MAX, u32::MAX),
+            Point::new(0xffff, 0xffff),
+        ];
+        for a in big.iter() {
+            for b in big.iter() {
+                assert_eq!(a.cmp(b) == Ordering::Equal, a == b);
+                assert_eq!(a.cmp(b), b.cmp(a).reverse());
+            }
+        }
+        assert!(Point::new(0xffff, 0xffff) < Point::new(0x10000, 0));
+
+        let map = points
+            .iter()
+            .rev()
+            .map(|p| (*p, p[0]))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert!(map.keys().eq(points.iter()));
+    }
+
+    #[test]
+    fn midpoint() {
+        let a = Point::new(2, 9);
+        assert_eq!(a.midpoint(Point::new(6, 4)), Point::new(4, 6));
+        assert_eq!(Point::new(6, 4).midpoint(a), Point::new(4, 6));
+        assert_eq!(a.midpoint(a), a);
+        let max = Point::new(u32::MAX, u32::MAX);
+        assert_eq!(max.midpoint(max), max);
+        assert_eq!(
+            max.midpoint(Point::new(0, 1)),
+            Point::new(u32::MAX / 2, u32::MAX / 2 + 1)
+        );
+    }
+
+    #[test]
+    fn centroid_of_points() {
+        assert_eq!(centroid(&[]), None);
+        assert_eq!(centroid(&[Point::new(3, 4)]), Some(Point::new(3, 4)));
+        let square = [
+            Point::new(0, 0),
+            Point::new(10, 0),
+            Point::new(0, 10),
+            Point::new(10, 11),
+        ];
+        assert_eq!(centroid(&square), Some(Point::new(5, 5)));
+        // the sum of the coordinates overflows 32 bits
+        let many = vec![Point::new(u32::MAX - 1, 1 << 31); 10_000];
+        assert_eq!(centroid(&many), Some(Point::new(u32::MAX - 1, 1 << 31)));
+    }
+
+    #[test]
+    fn saturating_add() {
+        let p = Point::new(u32::MAX - 1, 5);
+        assert_eq!(p.saturating_add(Point::new(3, 3)), Point::new(u32::MAX, 8));
+    }
+
+    #[test]
+    fn checked_add() {
+        let p = Point::new(u32::MAX - 1, 5);
+        assert_eq!(
+            p.checked_add(Point::new(1, 3)),
+            Some(Point::new(u32::MAX, 8))
+        );
+        assert_eq!(p.checked_add(Point::new(3, 3)), None);
+        assert_eq!(p.checked_add(Point::new(0, u32::MAX)), None);
+    }
+
+    #[test]
+    fn snap_to_grid() {
+        let p = Point::new(17, 40);
+        assert_eq!(p.snap_to_grid(0), p);
+        assert_eq!(p.snap_to_grid(1), p);
+        assert_eq!(p.snap_to_grid(8), Point::new(16, 40));
+        assert_eq!(p.snap_to_grid(6), Point::new(12, 36));
+        assert_eq!(p.snap_to_grid(100), Point::new(0, 0));
+    }
+
+    #[test]
+    fn dist_sq() {
+        let p = Point::new(3, 0);
+        assert_eq!(p.dist_sq(&Point::new(0, 4)), 25);
+        assert_eq!(
+            Point::new(0, 0).dist_sq(&Point::new(u32::MAX, 0)),
+            (u32::MAX as u64).pow(2)
+        );
+    }
+
+    #[test]
+    fn reflect_is_an_involution() {
+        let center = 64;
+        for x in 0..=128 {
+            for y in (0..=128).step_by(7) {
+                let p = Point::new(x, y);
+                assert_eq!(p.reflect_x(center).reflect_x(center), p);
+                assert_eq!(p.reflect_y(center).reflect_y(center), p);
+            }
+        }
+        assert_eq!(Point::new(10, 20).reflect_x(64), Point::new(118, 20));
+        assert_eq!(Point::new(10, 20).reflect_y(64), Point::new(10, 108));
+    }
+
+    #[test]
+    fn reflect_saturates_at_zero() {
+        assert_eq!(Point::new(10, 3).reflect_x(2), Point::new(0, 3));
+        assert_eq!(Point::new(10, 3).reflect_y(1), Point::new(10, 0));
+        assert_eq!(
+            Point::new(0, u32::MAX).reflect_y(u32::MAX - 1),
+            Point::new(0, u32::MAX - 2)
+        );
+        assert_eq!(
+            Point::new(0, 3).reflect_y(u32::MAX),
+            Point::new(0, u32::MAX)
+        );
+
+        // a saturated point is not reflected back to the original
+        let p = Point::new(10, 3);
+        assert_eq!(p.reflect_x(2).reflect_x(2), Point::new(4, 3));
+        assert_eq!(p.checked_reflect_x(2), None);
+        assert_eq!(p.checked_reflect_y(1), None);
+        assert_eq!(Point::new(0, 3).checked_reflect_y(u32::MAX), None);
+
+        assert_eq!(p.checked_reflect_x(5), Some(Point::new(0, 3)));
+        assert_eq!(p.checked_reflect_y(5), Some(Point::new(10, 7)));
+        let back = p.checked_reflect_x(5).and_then(|p| p.checked_reflect_x(5));
+        assert_eq!(back, Some(p));
+    }
+
+    #[test]
+    fn rotate90() {
+        let center = Point::new(64, 64);
+        let p = Point::new(70, 61);
+        assert_eq!(p.rotate90(center), Point::new(67, 70));
+        assert_eq!(
+            p.rotate90(center)
+                .rotate90(center)
+                .rotate90(center)
+                .rotate90(center),
+            p
+        );
+        assert_eq!(
+            Point::new(0, 10).rotate90(Point::new(0, 0)),
+            Point::new(0, 0)
+        );
+    }
+
+    #[test]
+    fn lerp() {
+        let a = Point::new(10, 20);
+        let b = Point::new(20, 0);
+        assert_eq!(a.lerp(b, 0, 4), a);
+        assert_eq!(a.lerp(b, 4, 4), b);
+        assert_eq!(a.lerp(b, 1, 2), Point::new(15, 10));
+        assert_eq!(a.lerp(b, 1, 3), Point::new(13, 14));
+        // extrapolation saturates
+        assert_eq!(a.lerp(b, 2, 1), Point::new(30, 0));
+    }
+
+    #[test]
+    fn line_iter_horizontal_and_vertical() {
+        let line = Point::new(2, 5)
+            .line_iter(Point::new(6, 5))
+            .collect::<Vec<_>>();
+        assert_eq!(line, (2..=6).map(|x| Point::new(x, 5)).collect::<Vec<_>>());
+
+        let line = Point::new(3, 4)
+            .line_iter(Point::new(3, 0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            line,
+            (0..=4).rev().map(|y| Point::new(3, y)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn line_iter_diagonal() {
+        let line = Point::new(0, 0)
+            .line_iter(Point::new(3, 3))
+            .collect::<Vec<_>>();
+        assert_eq!(line, (0..=3).map(|i| Point::new(i, i)).collect::<Vec<_>>());
+
+        let line = Point::new(0, 0)
+            .line_iter(Point::new(4, 2))
+            .collect::<Vec<_>>();
+        assert_eq!(line.len(), 5);
+        assert_eq!(line.first(), Some(&Point::new(0, 0)));
+        assert_eq!(line.last(), Some(&Point::new(4, 2)));
+        // every step moves to a neighbouring cell
+        for w in line.windows(2) {
+            assert_eq!(w[1][0] - w[0][0], 1);
+            assert!(w[1][1] - w[0][1] <= 1);
+        }
+    }
+
+    #[test]
+    fn line_iter_same_point() {
+        let p = Point::new(7, 7);
+        assert_eq!(p.line_iter(p).collect::<Vec<_>>(), vec![p]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Compare the range queries against a brute-force scan over every inserted point.
+use super::*;
+use crate::quadtree::Quadtree;
+use rand::prelude::*;
+use std::collections::HashSet;
+
+const TABLES: usize = 32;
+const QUERIES: usize = 256;
+
+fn random_points(rng: &mut impl Rng, len: usize, extent: u32) -> Vec<Point> {
+    let mut points = HashSet::with_capacity(len);
+    while points.len() < len {
+        points.insert(Point::new(
+            rng.gen_range(0, extent),
+            rng.gen_range(0, extent),
+        ));
+    }
+    points.into_iter().collect()
+}
+
+fn brute_force(points: &[Point], center: &Point, radius: u32) -> HashSet<Point> {
+    let radius_sq = radius as u64 * radius as u64;
+    points
+        .iter()
+        .filter(|p| center.dist_sq(p) <= radius_sq)
+        .cloned()
+        .collect()
+}
+
+/// Run `query` against many random tables and compare its output to the brute-force reference.
+fn check_range_query<F>(query: F)
+where
+    F: for<'a> Fn(&'a MortonTable, &Point, u32, &mut Vec<(Point, &'a Value)>),
+{
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..TABLES {
+        let extent = rng.gen_range(16, 2048);
+        let len = rng.gen_range(1, 1024.min(extent * extent / 2) as usize);
+        let points = random_points(&mut rng, len, extent);
+        let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(p[0] ^ p[1]))));
+
+        let mut res = Vec::new();
+        for _ in 0..QUERIES {
+            let center = Point::new(rng.gen_range(0, extent), rng.gen_range(0, extent));
+            let radius = rng.gen_range(0, extent / 4 + 2);
+
+            res.clear();
+            query(&table, &center, radius, &mut res);
+
+            let found = res.iter().map(|(p, _)| *p).collect::<HashSet<_>>();
+            assert_eq!(
+                found.len(),
+                res.len(),
+                "there were duplicates in the output"
+            );
+            for (p, v) in res.iter() {
+                assert_eq!(**v, Value(p[0] ^ p[1]), "value does not belong to {:?}", p);
+            }
+            let expected = brute_force(&points, &center, radius);
+            assert_eq!(
+                found,
+                expected,
+                "center: {:?} radius: {}\nDifference: {:?}",
+                center,
+                radius,
+                found.symmetric_difference(&expected)
+            );
+        }
+    }
+}
+
+#[test]
+fn find_in_range_matches_brute_force() {
+    check_range_query(|table, center, radius, out| table.find_in_range(center, radius, out));
+}
+
+#[test]
+fn find_in_range_2_matches_brute_force() {
+    check_range_query(|table, center, radius, out| table.find_in_range_2(center, radius, out));
+}
+
+#[test]
+fn quadtree_find_in_range_matches_brute_force() {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..TABLES {
+        let extent = rng.gen_range(16, 2048);
+        let len = rng.gen_range(1, 1024.min(extent * extent / 2) as usize);
+        let points = random_points(&mut rng, len, extent);
+        let table = Quadtree::from_iterator(points.iter().map(|p| (*p, Value(0))));
+
+        let mut res = Vec::new();
+        for _ in 0..QUERIES {
+            let center = Point::new(rng.gen_range(0, extent), rng.gen_range(0, extent));
+            let radius = rng.gen_range(0, extent / 4 + 2);
+
+            res.clear();
+            table.find_in_range(&center, radius, &mut res);
+
+            let found = res.iter().map(|(p, _)| *p).collect::<HashSet<_>>();
+            assert_eq!(
+                found.len(),
+                res.len(),
+                "there were duplicates in the output"
+            );
+            assert_eq!(found, brute_force(&points, &center, radius));
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Smallest enclosing circle of a set of points by Welzl's algorithm, in its iterative form.
+use crate::Point;
+
+/// Return the center and the squared radius of the smallest circle enclosing every point.
+/// Assumes that `points` is not empty.
+///
+/// Expected `O(n)` as the points are visited in a shuffled order.
+pub fn enclosing_circle(points: &[Point]) -> ([f64; 2], f64) {
+    debug_assert!(!points.is_empty());
+    let mut points = points
+        .iter()
+        .map(|p| [p[0] as f64, p[1] as f64])
+        .collect::<Vec<_>>();
+    shuffle(&mut points);
+
+    let mut circle = (points[0], 0.0);
+    for i in 1..points.len() {
+        if contains(&circle, &points[i]) {
+            continue;
+        }
+        // points[i] is on the boundary of the circle of points[..=i]
+        circle = (points[i], 0.0);
+        for j in 0..i {
+            if contains(&circle, &points[j]) {
+                continue;
+            }
+            // so are points[i] and points[j]
+            circle = diameter(&points[i], &points[j]);
+            for k in 0..j {
+                if !contains(&circle, &points[k]) {
+                    circle = circumcircle(&points[i], &points[j], &points[k]);
+                }
+            }
+        }
+    }
+    circle
+}
+
+fn contains((center, radius_sq): &([f64; 2], f64), p: &[f64; 2]) -> bool {
+    // tolerate rounding errors of the circles computed from the points on their boundary
+    dist_sq(center, p) <= radius_sq * (1.0 + 1e-12) + 1e-9
+}
+
+fn dist_sq(a: &[f64; 2], b: &[f64; 2]) -> f64 {
+    let (x, y) = (a[0] - b[0], a[1] - b[1]);
+    x * x + y * y
+}
+
+fn diameter(a: &[f64; 2], b: &[f64; 2]) -> ([f64; 2], f64) {
+    let center = [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0];
+    (center, dist_sq(&center, a))
+}
+
+fn circumcircle(a: &[f64; 2], b: &[f64; 2], c: &[f64; 2]) -> ([f64; 2], f64) {
+    let (bx, by) = (b[0] - a[0], b[1] - a[1]);
+    let (cx, cy) = (c[0] - a[0], c[1] - a[1]);
+    let d = 2.0 * (bx * cy - by * cx);
+    if d == 0.0 {
+        // collinear, the circle of the 2 points furthest apart
+        let circles = [diameter(a, b), diameter(a, c), diameter(b, c)];
+        return circles
+            .iter()
+            .cloned()
+            .max_by(|x, y| x.1.partial_cmp(&y.1).unwrap())
+            .unwrap();
+    }
+    let (b2, c2) = (bx * bx + by * by, cx * cx + cy * cy);
+    let ux = (cy * b2 - by * c2) / d;
+    let uy = (bx * c2 - cx * b2) / d;
+    let center = [a[0] + ux, a[1] + uy];
+    (center, ux * ux + uy * uy)
+}
+
+/// Fisher-Yates shuffle by a xorshift generator with a fixed seed.
+/// Only needs to break up adversarial orders, e.g. points sorted along a line.
+fn shuffle<T>(items: &mut [T]) {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
@@ -1,35 +1,251 @@
-#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-
 #[cfg(target_arch = "x86")]
 use std::arch::x86::*;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
-use std::mem;
 
+#[cfg(test)]
+mod brute_force_tests;
+mod enclosing_circle;
 pub mod litmax_bigmin;
 pub mod morton_key;
+pub mod range_iter;
 pub mod sorting;
 #[cfg(test)]
 mod tests;
 
+use crate::codec::morton_decode_2d;
 use crate::{Point, Value};
+use arrayvec::ArrayVec;
 use litmax_bigmin::litmax_bigmin;
 use morton_key::*;
-use sorting::sort;
+use range_iter::{AabbRanges, RangeIter, RangeStep};
+use rayon::prelude::*;
+use sorting::{radix_sort, sort_indirect, sort_sequential, RADIX_SORT_THRESHOLD};
 use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
 
 // at most 15 bits long non-negative integers
 // having the 16th bit set might create problems in find_key
 const POS_MASK: u32 = 0b0111111111111111;
 
-const SKIP_LEN: usize = 8;
-type SkipList = [u32; SKIP_LEN];
+// see `MortonTable::with_split_threshold`
+const DEFAULT_SPLIT_THRESHOLD: usize = 32;
+
+// the skiplist grows with the table, see `skip_len`
+const MIN_SKIP_LEN: usize = 8;
+const MAX_SKIP_LEN: usize = 32;
+// target number of keys between two items of the skiplist
+const SKIP_BUCKET_LEN: usize = 512;
+type SkipList = [u32; MAX_SKIP_LEN];
+
+// random picks `sample_in_range` makes from the Morton interval before it collects the
+// items in range instead
+#[cfg(feature = "rand")]
+const SAMPLE_ATTEMPTS: usize = 16;
+
+// number of items following each item in Morton order that `closest_pair` compares it to for
+// its first guess
+const CLOSEST_PAIR_WINDOW: usize = 8;
+
+// number of Morton intervals above which the box queries split the box like `find_in_range`
+// instead of decomposing it exactly, see `MortonTable::aabb_index_ranges`. In the `find_in_aabb`
+// benchmark splitting is as fast or faster than the exact decomposition for any box needing more
+// intervals, on sparse and dense tables, and about 60x faster for 4096x4096 boxes.
+const MAX_AABB_RANGES: usize = 4;
+
+// smallest cells of `MortonTable::histogram`, a grid of 1024 by 1024 cells
+const MIN_HISTOGRAM_CELL_BITS: u8 = 5;
+
+// number of items `MortonTable::insert_staged` buffers before merging them into the table
+const STAGING_CAPACITY: usize = 256;
+
+// header of the binary format of `MortonTable::write_to`
+const FORMAT_MAGIC: [u8; 4] = *b"MRTN";
+const FORMAT_VERSION: u32 = 1;
+
+/// A position and a reference to its value in a `MortonTable`.
+pub type Entry<'a> = (Point, &'a Value);
+
+/// Reasons `MortonTable::from_parts` may reject its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartsError {
+    /// `keys`, `positions` and `values` are not of equal length.
+    LengthMismatch,
+    /// The key at the index is not the Morton key of an in-bounds position at the same index.
+    InvalidKey(usize),
+    /// The key at the index is less than the one before it.
+    NotSorted(usize),
+}
+
+impl std::fmt::Display for PartsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PartsError::LengthMismatch => write!(f, "parts are not of equal length"),
+            PartsError::InvalidKey(i) => {
+                write!(f, "key at index {} does not match its position", i)
+            }
+            PartsError::NotSorted(i) => write!(f, "key at index {} is out of order", i),
+        }
+    }
+}
+
+impl std::error::Error for PartsError {}
+
+/// The first broken invariant `MortonTable::verify_contract` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContractViolation {
+    /// The items are inconsistent, see `PartsError`.
+    Parts(PartsError),
+    /// Items were inserted by `insert_deferred` without a `flush` since.
+    StaleSkipList,
+    /// The length or the step of the skiplist does not fit the number of items.
+    SkipListLayout,
+    /// The skiplist entry at the index does not sample the key it should.
+    SkipListEntry(usize),
+}
+
+impl std::fmt::Display for ContractViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ContractViolation::Parts(err) => err.fmt(f),
+            ContractViolation::StaleSkipList => write!(f, "skiplist is not flushed"),
+            ContractViolation::SkipListLayout => {
+                write!(f, "skiplist layout does not match the number of items")
+            }
+            ContractViolation::SkipListEntry(i) => {
+                write!(f, "skiplist entry {} does not match the keys", i)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ContractViolation {}
+
+/// Reasons `MortonTable::insert_unique` may reject an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    /// The position is already taken by this value.
+    Occupied(Value),
+    /// The position is outside of the `[min, max)` bounds of the table.
+    OutOfBounds { id: Point, bounds: (Point, Point) },
+}
+
+impl std::fmt::Display for InsertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InsertError::Occupied(value) => write!(f, "position is occupied by {:?}", value),
+            InsertError::OutOfBounds { id, bounds } => write!(
+                f,
+                "{:?} is out of the bounds [{:?}, {:?})",
+                id, bounds.0, bounds.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+/// Cost of a single `MortonTable::insert_tracked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsertStats {
+    /// Number of items moved by one to make room for the new one.
+    pub shifted: usize,
+    /// Whether the whole skiplist was rebuilt, instead of updating the entries after the new
+    /// item.
+    pub skiplist_rebuilt: bool,
+}
+
+/// Reasons `MortonTable::try_find_in_range` may reject a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryError {
+    /// The center is outside of the `[min, max)` bounds of the table.
+    CenterOutOfBounds {
+        center: Point,
+        bounds: (Point, Point),
+    },
+    /// The radius does not fit into 31 bits.
+    RadiusTooLarge(u32),
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryError::CenterOutOfBounds { center, bounds } => write!(
+                f,
+                "{:?} is out of the bounds [{:?}, {:?})",
+                center, bounds.0, bounds.1
+            ),
+            QueryError::RadiusTooLarge(radius) => {
+                write!(f, "radius {} does not fit into 31 bits", radius)
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Reasons `MortonTable::histogram` may reject a grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistogramError {
+    /// `cell_bits` is in `0..5`, the grid would take more than 4 MiB.
+    CellsTooSmall(u8),
+    /// `cell_bits` is larger than 15, a cell would be larger than the table.
+    CellsTooLarge(u8),
+}
+
+impl std::fmt::Display for HistogramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HistogramError::CellsTooSmall(bits) => write!(
+                f,
+                "cell_bits {} is less than {}, use cell_counts for small cells",
+                bits, MIN_HISTOGRAM_CELL_BITS
+            ),
+            HistogramError::CellsTooLarge(bits) => {
+                write!(f, "cell_bits {} is larger than 15", bits)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HistogramError {}
+
+/// Distance metrics of `MortonTable::find_in_range_metric`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Straight line distance, the circle of `find_in_range`.
+    Euclidean,
+    /// `|dx| + |dy|`, a diamond.
+    Manhattan,
+    /// `max(|dx|, |dy|)`, a square.
+    Chebyshev,
+}
 
-#[derive(Debug, Clone, Default)]
+/// Linear quadtree of `Value`s keyed by `Point`s, stored in Morton order.
+///
+/// # Allocation
+///
+/// The items live in three `Vec`s in the global allocator. Custom allocators are not supported
+/// while the allocator API is unstable. To avoid allocating every frame keep a table around and
+/// `clear` it instead, `clear` keeps the capacity of the table. `defragment` releases the unused
+/// capacity.
+#[derive(Debug, Clone)]
 pub struct MortonTable {
     skipstep: u32,
+    // number of items of `skiplist` in use
+    skiplen: u32,
     skiplist: SkipList,
-    // ---- 9 * 4 bytes so far
+    // set by `insert_deferred` while the skiplist is out of date
+    dirty: bool,
+    // `false` if the table was created by `without_skiplist`
+    use_skiplist: bool,
+    // see `set_parallel`
+    parallel: bool,
+    split_threshold: usize,
+    // world coordinates of the position (0, 0), see `with_origin`
+    origin: [i32; 2],
+    // ---- 40 * 4 bytes so far
     // `keys` is 24 bytes in memory
     // I'll make these public to be able to flush them from the cache in benchmarks
     // However in practice you'll want to make these private as there are contracts that when
@@ -37,24 +253,408 @@ pub struct MortonTable {
     pub keys: Vec<MortonKey>,
     pub positions: Vec<Point>,
     pub values: Vec<Value>,
+    // positions touched since the last `take_dirty`, `None` if change tracking is disabled
+    changes: Option<Vec<Point>>,
+    // unsorted items of `insert_staged` waiting for `compact`
+    staged: Vec<(MortonKey, Point, Value)>,
+}
+
+impl Default for MortonTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MortonTable {
     pub fn new() -> Self {
         Self {
-            skiplist: Default::default(),
+            skiplist: [0; MAX_SKIP_LEN],
             skipstep: 0,
+            skiplen: MIN_SKIP_LEN as u32,
+            dirty: false,
+            use_skiplist: true,
+            parallel: true,
+            split_threshold: DEFAULT_SPLIT_THRESHOLD,
+            origin: [0, 0],
+            changes: None,
+            staged: vec![],
             keys: vec![],
             values: vec![],
             positions: vec![],
         }
     }
 
+    /// Create a table that never builds a skiplist and always looks up keys by a plain binary
+    /// search over all keys.
+    pub fn without_skiplist() -> Self {
+        Self {
+            use_skiplist: false,
+            ..Self::new()
+        }
+    }
+
+    /// Create a table for signed world coordinates, mapping the world position `origin` to the
+    /// position `(0, 0)` of the table.
+    ///
+    /// The origin is applied by `to_local`, `to_world` and the `*_world` methods. Every other
+    /// method works in the unsigned coordinates of the table.
+    pub fn with_origin(origin: [i32; 2]) -> Self {
+        Self {
+            origin,
+            ..Self::new()
+        }
+    }
+
+    pub fn origin(&self) -> [i32; 2] {
+        self.origin
+    }
+
+    /// Map a world position into the table, `None` if it is out of the bounds of the table.
+    pub fn to_local(&self, world: [i32; 2]) -> Option<Point> {
+        let axis = |n: i32, origin: i32| {
+            u32::try_from(n as i64 - origin as i64)
+                .ok()
+                .filter(|n| *n <= POS_MASK)
+        };
+        Some(Point::new(
+            axis(world[0], self.origin[0])?,
+            axis(world[1], self.origin[1])?,
+        ))
+    }
+
+    /// Map a position of the table into the world. Wraps around if the result does not fit into
+    /// an `i32`.
+    pub fn to_world(&self, local: &Point) -> [i32; 2] {
+        [
+            (local[0] as i32).wrapping_add(self.origin[0]),
+            (local[1] as i32).wrapping_add(self.origin[1]),
+        ]
+    }
+
+    /// `insert` at a world position. Returns the position if it is out of bounds.
+    pub fn insert_world(&mut self, id: [i32; 2], row: Value) -> Result<(), [i32; 2]> {
+        let local = self.to_local(id).ok_or(id)?;
+        self.insert(local, row).map_err(|_| id)
+    }
+
+    /// `get_by_id` at a world position.
+    pub fn get_world(&self, id: [i32; 2]) -> Option<&Value> {
+        self.get_by_id(&self.to_local(id)?)
+    }
+
+    /// `find_in_range` around a world position, yielding world positions.
+    ///
+    /// The center may be out of the bounds of the table. Like in `find_in_range` the radius must
+    /// fit into 31 bits.
+    pub fn find_in_range_world<'a>(
+        &'a self,
+        center: [i32; 2],
+        radius: u32,
+        out: &mut Vec<([i32; 2], &'a Value)>,
+    ) {
+        let r = i32::try_from(radius).expect("radius to fit into 31 bits") as i64;
+        let cx = center[0] as i64 - self.origin[0] as i64;
+        let cy = center[1] as i64 - self.origin[1] as i64;
+        // the AABB of the circle in table coordinates, clamped to the bounds of the table
+        let max = POS_MASK as i64;
+        let (x1, y1) = ((cx - r).max(0), (cy - r).max(0));
+        let (x2, y2) = ((cx + r).min(max), (cy + r).min(max));
+        if x1 > x2 || y1 > y2 {
+            return;
+        }
+        let min = Point::new(x1 as u32, y1 as u32);
+        let max = Point::new(x2 as u32, y2 as u32);
+        let in_range = move |p: &Point| {
+            let (dx, dy) = (p[0] as i64 - cx, p[1] as i64 - cy);
+            dx * dx + dy * dy <= r * r
+        };
+        for range in self.aabb_index_ranges(&min, &max) {
+            for i in range {
+                if in_range(&self.positions[i]) {
+                    out.push((self.to_world(&self.positions[i]), &self.values[i]));
+                }
+            }
+        }
+        for (p, value) in self.staged_where(in_range) {
+            out.push((self.to_world(&p), value));
+        }
+    }
+
+    /// Set the number of items in a range above which `find_in_range` splits the range instead
+    /// of scanning it.
+    /// Higher values scan more garbage items but split less. Defaults to 32.
+    pub fn with_split_threshold(mut self, n: usize) -> Self {
+        self.split_threshold = n;
+        self
+    }
+
+    /// Choose whether `extend` and `rebuild` sort the items on the rayon thread pool. Defaults
+    /// to `true`.
+    ///
+    /// The sequential sort avoids the overhead of the thread pool, e.g. on single threaded or
+    /// wasm targets. Note that rayon is still a dependency of the crate either way.
+    pub fn set_parallel(&mut self, enabled: bool) {
+        self.parallel = enabled;
+    }
+
+    /// Record the positions touched by `insert`, `delete`, `extend`, `translate`, `dedup_by` and
+    /// `clear`, to be retrieved by `take_dirty`.
+    pub fn with_change_tracking(mut self) -> Self {
+        self.changes = Some(Vec::new());
+        self
+    }
+
+    /// Return the positions touched since the last call and reset the log.
+    ///
+    /// `extend` marks every new item dirty and `clear` marks every removed item dirty.
+    /// Direct mutations of the public fields are not tracked.
+    /// Always empty if the table was not created `with_change_tracking`.
+    pub fn take_dirty(&mut self) -> Vec<Point> {
+        self.changes
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn log_change(&mut self, id: Point) {
+        if let Some(changes) = self.changes.as_mut() {
+            changes.push(id);
+        }
+    }
+
+    /// Remove all items, keeping the allocated capacity.
     pub fn clear(&mut self) {
+        if let Some(changes) = self.changes.as_mut() {
+            changes.extend_from_slice(&self.positions);
+            changes.extend(self.staged.iter().map(|(_, id, _)| *id));
+        }
         self.keys.clear();
-        self.skiplist = [Default::default(); SKIP_LEN];
         self.values.clear();
         self.positions.clear();
+        self.staged.clear();
+        self.rebuild_skip_list();
+    }
+
+    /// Overwrite `dst` with a copy of this table, reusing the allocations of `dst`.
+    pub fn clone_into(&self, dst: &mut MortonTable) {
+        dst.skipstep = self.skipstep;
+        dst.skiplen = self.skiplen;
+        dst.skiplist = self.skiplist;
+        dst.dirty = self.dirty;
+        dst.use_skiplist = self.use_skiplist;
+        dst.parallel = self.parallel;
+        dst.split_threshold = self.split_threshold;
+        dst.origin = self.origin;
+        dst.keys.clear();
+        dst.keys.extend_from_slice(&self.keys);
+        dst.positions.clear();
+        dst.positions.extend_from_slice(&self.positions);
+        dst.values.clear();
+        dst.values.extend_from_slice(&self.values);
+        dst.changes.clone_from(&self.changes);
+        dst.staged.clear();
+        dst.staged.extend_from_slice(&self.staged);
+    }
+
+    /// Restore the invariants of the table after the public fields have been mutated directly.
+    ///
+    /// `keys`, `positions` and `values` must still be of equal length and each key must be the
+    /// Morton key of its position.
+    pub fn rebuild(&mut self) {
+        assert_eq!(self.keys.len(), self.positions.len());
+        assert_eq!(self.keys.len(), self.values.len());
+        self.compact();
+        // quicksort performs poorly on already sorted input
+        if !self.keys.windows(2).all(|w| w[0] <= w[1]) {
+            self.sort_items();
+        }
+        self.rebuild_skip_list();
+    }
+
+    /// Consume the table and return its `keys`, `positions` and `values`.
+    pub fn into_parts(mut self) -> (Vec<MortonKey>, Vec<Point>, Vec<Value>) {
+        self.compact();
+        (self.keys, self.positions, self.values)
+    }
+
+    /// Build a table from the output of `into_parts`.
+    ///
+    /// Checks that the parts are of equal length, every key matches its position and the keys
+    /// are sorted.
+    pub fn from_parts(
+        keys: Vec<MortonKey>,
+        positions: Vec<Point>,
+        values: Vec<Value>,
+    ) -> Result<Self, PartsError> {
+        Self::check_parts(&keys, &positions, &values)?;
+        let mut res = Self::new();
+        res.keys = keys;
+        res.positions = positions;
+        res.values = values;
+        res.rebuild_skip_list();
+        Ok(res)
+    }
+
+    // see `PartsError`
+    fn check_parts(
+        keys: &[MortonKey],
+        positions: &[Point],
+        values: &[Value],
+    ) -> Result<(), PartsError> {
+        if keys.len() != positions.len() || keys.len() != values.len() {
+            return Err(PartsError::LengthMismatch);
+        }
+        for (i, (key, id)) in keys.iter().zip(positions.iter()).enumerate() {
+            if Self::key_of(id) != Some(*key) {
+                return Err(PartsError::InvalidKey(i));
+            }
+            if i > 0 && *key < keys[i - 1] {
+                return Err(PartsError::NotSorted(i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the invariants the queries rely on, e.g. after modifying the public fields directly.
+    /// Returns the first violation found.
+    ///
+    /// Checks that `keys`, `positions` and `values` are of equal length, that each key is the
+    /// Morton key of its position, that the keys are sorted and that the skiplist samples the
+    /// current keys. Runs in `O(n)`.
+    pub fn verify_contract(&self) -> Result<(), ContractViolation> {
+        Self::check_parts(&self.keys, &self.positions, &self.values)
+            .map_err(ContractViolation::Parts)?;
+        if self.dirty {
+            return Err(ContractViolation::StaleSkipList);
+        }
+        let len = self.keys.len();
+        let skiplen = skip_len(len);
+        let step = if self.use_skiplist { len / skiplen } else { 0 };
+        if self.skiplen as usize != skiplen || self.skipstep as usize != step {
+            return Err(ContractViolation::SkipListLayout);
+        }
+        if len == 0 {
+            // no lookup reads the skiplist
+            return Ok(());
+        }
+        // see `rebuild_skip_list`
+        for i in 0..MAX_SKIP_LEN {
+            let expected = match step {
+                0 if i == 0 => self.keys[len - 1].0,
+                0 => u32::MAX >> 1,
+                _ if i < skiplen && (i + 1) * step < len => self.keys[(i + 1) * step].0,
+                _ => u32::MAX >> 1,
+            };
+            if self.skiplist[i] != expected {
+                return Err(ContractViolation::SkipListEntry(i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the table in a compact binary format, read it back with `read_from`.
+    ///
+    /// The format is the magic bytes `MRTN`, the format version and the number of items, followed
+    /// by the positions and then the values as little-endian arrays. The keys are not written,
+    /// they are recomputed on load. Makes many small writes, wrap unbuffered writers in a
+    /// `BufWriter`. Items staged by `insert_staged` are written as if the table was compacted.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&FORMAT_MAGIC)?;
+        w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        w.write_all(&((self.keys.len() + self.staged.len()) as u64).to_le_bytes())?;
+        for (p, _) in self.merged_items() {
+            w.write_all(&p[0].to_le_bytes())?;
+            w.write_all(&p[1].to_le_bytes())?;
+        }
+        for (_, v) in self.merged_items() {
+            w.write_all(&v.0.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The items merged with the staged ones, in the order `compact` would leave them in.
+    fn merged_items(&self) -> impl Iterator<Item = (Point, Value)> + '_ {
+        let mut staged = self.staged.clone();
+        staged.sort_by_key(|(key, _, _)| *key);
+        let (mut i, mut j) = (0, 0);
+        std::iter::from_fn(move || {
+            // on equal keys the items of the table go first, like in `compact`
+            if j < staged.len() && (i == self.keys.len() || staged[j].0 < self.keys[i]) {
+                let (_, id, value) = staged[j];
+                j += 1;
+                Some((id, value))
+            } else if i < self.keys.len() {
+                i += 1;
+                Some((self.positions[i - 1], self.values[i - 1]))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Read a table written by `write_to`.
+    ///
+    /// Fails with `InvalidData` if the header does not match, a position is out of bounds or the
+    /// items are not sorted.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+            let mut buf = [0; 4];
+            r.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        }
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != FORMAT_MAGIC {
+            return Err(invalid("not a MortonTable"));
+        }
+        if read_u32(r)? != FORMAT_VERSION {
+            return Err(invalid("unsupported format version"));
+        }
+        let mut len = [0; 8];
+        r.read_exact(&mut len)?;
+        let len =
+            usize::try_from(u64::from_le_bytes(len)).map_err(|_| invalid("too many items"))?;
+
+        // don't trust the length with the allocation, the input may be truncated
+        let capacity = len.min(1 << 16);
+        let mut keys = Vec::with_capacity(capacity);
+        let mut positions = Vec::with_capacity(capacity);
+        for _ in 0..len {
+            let id = Point::new(read_u32(r)?, read_u32(r)?);
+            keys.push(Self::key_of(&id).ok_or_else(|| invalid("position out of bounds"))?);
+            positions.push(id);
+        }
+        let mut values = Vec::with_capacity(capacity);
+        for _ in 0..len {
+            values.push(Value(read_u32(r)?));
+        }
+        Self::from_parts(keys, positions, values)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Move the contents of the table into freshly allocated, exactly sized and sorted arrays,
+    /// dropping any slack left behind by `insert` and `delete`.
+    pub fn defragment(&mut self) {
+        self.rebuild();
+        self.keys = self.keys.to_vec();
+        self.positions = self.positions.to_vec();
+        self.values = self.values.to_vec();
+    }
+
+    /// Sort the items by their keys, using the faster algorithm for the size of the table.
+    fn sort_items(&mut self) {
+        let keys = self.keys.as_mut_slice();
+        let positions = self.positions.as_mut_slice();
+        let values = self.values.as_mut_slice();
+        if !self.parallel {
+            sort_sequential(keys, positions, values);
+        } else if keys.len() >= RADIX_SORT_THRESHOLD {
+            radix_sort(keys, positions, values);
+        } else {
+            sort_indirect(keys, positions, values);
+        }
     }
 
     fn rebuild_skip_list(&mut self) {
@@ -72,41 +672,200 @@ impl MortonTable {
             }
         }
 
+        self.dirty = false;
         let len = self.keys.len();
-        let step = len / SKIP_LEN;
+        let skiplen = skip_len(len);
+        // a step of 0 disables the skiplist in find_key_morton
+        let step = if self.use_skiplist { len / skiplen } else { 0 };
         self.skipstep = step as u32;
+        self.skiplen = skiplen as u32;
         // leaving items 0 will cause errors in find_key_morton
-        self.skiplist = [std::u32::MAX >> 1; SKIP_LEN];
+        self.skiplist = [u32::MAX >> 1; MAX_SKIP_LEN];
         if step == 0 {
             if let Some(key) = self.keys.last() {
                 self.skiplist[0] = key.0;
             }
             return;
         }
-        for (i, k) in (0..len).step_by(step).skip(1).take(SKIP_LEN).enumerate() {
+        for (i, k) in (0..len).step_by(step).skip(1).take(skiplen).enumerate() {
             self.skiplist[i] = self.keys[k].0;
         }
     }
 
+    /// Update the skiplist after inserting a single key at `ind`.
+    ///
+    /// The keys after `ind` shifted by one, so only the entries sampling them need to be
+    /// reloaded. Falls back to `rebuild_skip_list` if the insertion changed the layout of the
+    /// skiplist. Returns whether the skiplist was rebuilt.
+    fn update_skip_list_after_insert(&mut self, ind: usize) -> bool {
+        let len = self.keys.len();
+        let skiplen = skip_len(len);
+        let step = self.skipstep as usize;
+        if self.dirty || step == 0 || skiplen != self.skiplen as usize || len / skiplen != step {
+            self.rebuild_skip_list();
+            return true;
+        }
+        // entry `i` samples `keys[(i + 1) * step]`, skip the ones before the insertion
+        let first = ind.div_ceil(step).saturating_sub(1);
+        for i in first..skiplen {
+            let k = (i + 1) * step;
+            if k >= len {
+                break;
+            }
+            self.skiplist[i] = self.keys[k].0;
+        }
+        false
+    }
+
     /// May trigger reordering of items, if applicable prefer `extend` and insert many keys at once.
     pub fn insert(&mut self, id: Point, row: Value) -> Result<(), Point> {
-        if !self.intersects(&id) {
-            return Err(id);
+        self.insert_tracked(id, row).map(|_| ())
+    }
+
+    /// Like `insert`, but reports the cost of the insertion, e.g. to decide whether to switch to
+    /// `insert_deferred` or `extend`.
+    pub fn insert_tracked(&mut self, id: Point, row: Value) -> Result<InsertStats, Point> {
+        let ind = self.insert_at(id, row)?;
+        let skiplist_rebuilt = self.update_skip_list_after_insert(ind);
+        Ok(InsertStats {
+            shifted: self.keys.len() - 1 - ind,
+            skiplist_rebuilt,
+        })
+    }
+
+    /// Like `insert` but refuses to insert an item if its position is already taken.
+    pub fn insert_unique(&mut self, id: Point, row: Value) -> Result<(), InsertError> {
+        let key = Self::key_of(&id).ok_or_else(|| InsertError::OutOfBounds {
+            id,
+            bounds: self.bounds(),
+        })?;
+        let ind = match self.find_key_morton(&key) {
+            Ok(ind) => return Err(InsertError::Occupied(self.values[ind])),
+            Err(ind) => ind,
+        };
+        if let Some((_, value)) = self.get_staged(&id) {
+            return Err(InsertError::Occupied(*value));
         }
-        let [x, y] = id.0;
-        let [x, y] = [x as u32, y as u32];
+        self.keys.insert(ind, key);
+        self.positions.insert(ind, id);
+        self.values.insert(ind, row);
+        self.log_change(id);
+        self.update_skip_list_after_insert(ind);
+        Ok(())
+    }
 
-        let ind = self
-            .keys
-            .binary_search(&MortonKey::new_u32(x, y))
-            .unwrap_or_else(|i| i);
-        self.keys.insert(ind, MortonKey::new_u32(x, y));
+    /// Insert without rebuilding the skiplist. Call `flush` after a burst of insertions.
+    ///
+    /// Queries made before `flush` remain correct, but fall back to a plain binary search over
+    /// the keys.
+    pub fn insert_deferred(&mut self, id: Point, row: Value) -> Result<(), Point> {
+        self.insert_at(id, row)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Insert the item keeping the keys sorted, without touching the skiplist.
+    /// Returns the index of the new item.
+    fn insert_at(&mut self, id: Point, row: Value) -> Result<usize, Point> {
+        let key = match Self::key_of(&id) {
+            Some(key) => key,
+            None => return Err(id),
+        };
+
+        let ind = self.keys.binary_search(&key).unwrap_or_else(|i| i);
+        self.keys.insert(ind, key);
         self.positions.insert(ind, id);
         self.values.insert(ind, row);
-        self.rebuild_skip_list();
+        self.log_change(id);
+        Ok(ind)
+    }
+
+    /// Rebuild the skiplist after `insert_deferred`. Does nothing if the table is up to date.
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.rebuild_skip_list();
+        }
+    }
+
+    /// Insert an item into a small unsorted staging buffer, merging the buffer into the table once
+    /// it holds `STAGING_CAPACITY` items. Amortizes the cost of shifting the items of the table
+    /// over many inserts, for sustained high insert rates.
+    ///
+    /// Until the next `compact`, the lookups and range queries scan the whole buffer on top of
+    /// the sorted items, and the methods mutating the items compact first. Methods exposing the
+    /// sorted arrays or indices into them, e.g. `keys`, `points`, `iter_cells` or
+    /// `find_in_range_indices`, and the whole-table statistics like `extent` or `histogram` only
+    /// cover the sorted items, call `compact` before using them.
+    pub fn insert_staged(&mut self, id: Point, row: Value) -> Result<(), Point> {
+        let key = Self::key_of(&id).ok_or(id)?;
+        self.staged.push((key, id, row));
+        self.log_change(id);
+        if self.staged.len() >= STAGING_CAPACITY {
+            self.compact();
+        }
         Ok(())
     }
 
+    /// Merge the items staged by `insert_staged` into the table. Does nothing if there are none.
+    ///
+    /// Sorts the staged items, then merges them with the table in a single pass from the back,
+    /// moving every item of the table at most once.
+    pub fn compact(&mut self) {
+        if self.staged.is_empty() {
+            return;
+        }
+        let mut staged = std::mem::take(&mut self.staged);
+        staged.sort_by_key(|(key, _, _)| *key);
+
+        let len = self.keys.len();
+        let total = len + staged.len();
+        self.keys.resize(total, MortonKey::default());
+        self.positions.resize(total, Point::new(0, 0));
+        self.values.resize(total, Value(0));
+        // `i` items of the table and `j` staged items are left to place below `k`
+        let (mut i, mut j, mut k) = (len, staged.len(), total);
+        while j > 0 {
+            k -= 1;
+            if i > 0 && self.keys[i - 1] > staged[j - 1].0 {
+                i -= 1;
+                self.keys[k] = self.keys[i];
+                self.positions[k] = self.positions[i];
+                self.values[k] = self.values[i];
+            } else {
+                j -= 1;
+                let (key, id, value) = staged[j];
+                self.keys[k] = key;
+                self.positions[k] = id;
+                self.values[k] = value;
+            }
+        }
+
+        staged.clear();
+        // keep the allocation of the buffer
+        self.staged = staged;
+        self.rebuild_skip_list();
+    }
+
+    /// The item at `id` in the staging buffer, see `insert_staged`.
+    fn get_staged(&self, id: &Point) -> Option<(&Point, &Value)> {
+        self.staged
+            .iter()
+            .find(|(_, p, _)| p == id)
+            .map(|(_, p, value)| (p, value))
+    }
+
+    /// The items in the staging buffer whose positions match `pred`, see `insert_staged`.
+    fn staged_where<'a, 'p, F>(&'a self, pred: F) -> impl Iterator<Item = (Point, &'a Value)> + 'p
+    where
+        'a: 'p,
+        F: Fn(&Point) -> bool + 'p,
+    {
+        self.staged
+            .iter()
+            .filter(move |(_, id, _)| pred(id))
+            .map(|(_, id, value)| (*id, value))
+    }
+
     pub fn from_iterator<It>(it: It) -> Self
     where
         It: Iterator<Item = (Point, Value)>,
@@ -116,149 +875,848 @@ impl MortonTable {
         res
     }
 
+    /// Build a table from a `width` x `height` grid, inserting an item at `[x, y]` wherever `f`
+    /// returns `Some`.
+    ///
+    /// The grid is visited in Morton order, so the items need no sorting afterwards.
+    /// Panics if the grid does not fit in the bounds of the table.
+    ///
+    /// ```
+    /// use morton_table::morton_table::MortonTable;
+    /// use morton_table::{Point, Value};
+    ///
+    /// // a checkerboard
+    /// let table = MortonTable::from_grid(8, 8, |x, y| {
+    ///     if (x + y) % 2 == 0 {
+    ///         Some(Value(x as u32 * 8 + y as u32))
+    ///     } else {
+    ///         None
+    ///     }
+    /// });
+    ///
+    /// assert_eq!(table.get_by_id(&Point::new(2, 4)), Some(&Value(20)));
+    /// assert_eq!(table.get_by_id(&Point::new(2, 3)), None);
+    /// ```
+    pub fn from_grid(
+        width: u16,
+        height: u16,
+        mut f: impl FnMut(u16, u16) -> Option<Value>,
+    ) -> Self {
+        assert!(width as u32 <= POS_MASK + 1);
+        assert!(height as u32 <= POS_MASK + 1);
+
+        let mut res = Self::default();
+        if width == 0 || height == 0 {
+            return res;
+        }
+        // the ranges are disjoint, sorted and every key in them is inside the grid
+        for (lo, hi) in morton_ranges([0, 0], [width - 1, height - 1]) {
+            for key in lo.0..=hi.0 {
+                let (x, y) = morton_decode_2d(key);
+                if let Some(value) = f(x, y) {
+                    let id = Point::new(x as u32, y as u32);
+                    res.keys.push(MortonKey(key));
+                    res.positions.push(id);
+                    res.values.push(value);
+                }
+            }
+        }
+        res.rebuild_skip_list();
+        res
+    }
+
     /// Extend the map by the items provided. Panics on invalid items.
+    ///
+    /// Duplicate positions are all stored, `get_by_id` finds only one of them. Use
+    /// `extend_dedup` to keep only the last value of each position.
     pub fn extend<It>(&mut self, it: It)
     where
         It: Iterator<Item = (Point, Value)>,
     {
         for (id, value) in it {
-            assert!(self.intersects(&id));
-
-            let [x, y] = id.0;
-            let [x, y] = [x as u16, y as u16];
-            let key = MortonKey::new(x, y);
+            let key = Self::key_of(&id)
+                .unwrap_or_else(|| panic!("{:?} is out of the bounds of the table", id));
             self.keys.push(key);
             self.positions.push(id);
             self.values.push(value);
+            self.log_change(id);
         }
-        sort(
-            self.keys.as_mut_slice(),
-            self.positions.as_mut_slice(),
-            self.values.as_mut_slice(),
-        );
+        self.sort_items();
+        self.rebuild_skip_list();
+    }
+
+    /// Extend the map by the items provided, reserving space for all of them up front.
+    /// Panics on invalid items.
+    pub fn extend_sized<It>(&mut self, it: It)
+    where
+        It: ExactSizeIterator<Item = (Point, Value)>,
+    {
+        let len = it.len();
+        self.keys.reserve(len);
+        self.positions.reserve(len);
+        self.values.reserve(len);
+        self.extend(it);
+    }
+
+    /// Like `extend`, but computes the keys and validates the items in parallel.
+    /// Panics on invalid items, leaving the table untouched.
+    ///
+    /// The sort dominates building a table, so this only pays off for large batches, see the
+    /// `make_table` benchmark.
+    pub fn par_extend<It>(&mut self, it: It)
+    where
+        It: IntoParallelIterator<Item = (Point, Value)>,
+    {
+        let items = it
+            .into_par_iter()
+            .map(|(id, value)| {
+                let key = Self::key_of(&id)
+                    .unwrap_or_else(|| panic!("{:?} is out of the bounds of the table", id));
+                (key, id, value)
+            })
+            .collect::<Vec<_>>();
+        if let Some(changes) = self.changes.as_mut() {
+            changes.extend(items.iter().map(|(_, id, _)| *id));
+        }
+        self.keys
+            .par_extend(items.par_iter().map(|(key, _, _)| *key));
+        self.positions
+            .par_extend(items.par_iter().map(|(_, id, _)| *id));
+        self.values
+            .par_extend(items.par_iter().map(|(_, _, value)| *value));
+        self.sort_items();
+        self.rebuild_skip_list();
+    }
+
+    /// Like `extend` but never creates duplicate entries. Panics on invalid items.
+    ///
+    /// If a position occurs multiple times the last value wins, this includes positions already
+    /// in the table. Duplicates already present in the table are left as they are.
+    pub fn extend_dedup<It>(&mut self, it: It)
+    where
+        It: Iterator<Item = (Point, Value)>,
+    {
+        // staged items must be overwritten too
+        self.compact();
+        let mut items = it
+            .map(|(id, value)| {
+                let key = Self::key_of(&id)
+                    .unwrap_or_else(|| panic!("{:?} is out of the bounds of the table", id));
+                (key, id, value)
+            })
+            .collect::<Vec<_>>();
+        // the sort is stable, so the items of the same key stay in insertion order
+        items.sort_by_key(|(key, _, _)| *key);
+        // `dedup_by` retains the first item of a run, overwrite it with the later ones
+        items.dedup_by(|later, first| {
+            let duplicate = later.0 == first.0;
+            if duplicate {
+                *first = *later;
+            }
+            duplicate
+        });
+
+        let len = self.keys.len();
+        for (key, id, value) in items {
+            self.log_change(id);
+            match self.keys[..len].binary_search(&key) {
+                Ok(ind) => self.values[ind] = value,
+                Err(_) => {
+                    self.keys.push(key);
+                    self.positions.push(id);
+                    self.values.push(value);
+                }
+            }
+        }
+        self.sort_items();
+        self.rebuild_skip_list();
+    }
+
+    /// Extend the map by the items in the slice. Panics on invalid items.
+    ///
+    /// Validates the whole batch before touching the table and reserves the exact capacity up
+    /// front, prefer this over `extend` when the items are already in a slice.
+    pub fn extend_from_slice(&mut self, items: &[(Point, Value)]) {
+        assert!(items.iter().all(|(id, _)| self.intersects(id)));
+
+        self.keys.reserve(items.len());
+        self.positions.reserve(items.len());
+        self.values.reserve(items.len());
+        for (id, value) in items {
+            self.keys.push(Self::key_of(id).unwrap());
+            self.positions.push(*id);
+            self.values.push(*value);
+            self.log_change(*id);
+        }
+        self.sort_items();
         self.rebuild_skip_list();
     }
 
     /// Returns the first item with given id, if any
     pub fn get_by_id<'a>(&'a self, id: &Point) -> Option<&'a Value> {
-        if !self.intersects(&id) {
+        if !self.intersects(id) {
+            return None;
+        }
+
+        self.find_key(id)
+            .map(|ind| &self.values[ind])
+            .ok()
+            .or_else(|| self.get_staged(id).map(|(_, value)| value))
+    }
+
+    /// Returns the first item with given id and its stored position, if any
+    pub fn get_entry<'a>(&'a self, id: &Point) -> Option<(&'a Point, &'a Value)> {
+        if !self.intersects(id) {
             return None;
         }
 
-        self.find_key(id).map(|ind| &self.values[ind]).ok()
+        self.find_key(id)
+            .map(|ind| (&self.positions[ind], &self.values[ind]))
+            .ok()
+            .or_else(|| self.get_staged(id))
+    }
+
+    /// Values of the orthogonal neighbours of `p`, in the order
+    /// `[x - 1, x + 1, y - 1, y + 1]`. `None` where the neighbour is empty or out of bounds.
+    pub fn neighbors_4<'a>(&'a self, p: &Point) -> [Option<&'a Value>; 4] {
+        self.get_offsets(p, &[[-1, 0], [1, 0], [0, -1], [0, 1]])
+    }
+
+    /// Values of the orthogonal and diagonal neighbours of `p`, row by row:
+    /// `[(x-1, y-1), (x, y-1), (x+1, y-1), (x-1, y), (x+1, y), (x-1, y+1), (x, y+1), (x+1, y+1)]`.
+    /// `None` where the neighbour is empty or out of bounds.
+    pub fn neighbors_8<'a>(&'a self, p: &Point) -> [Option<&'a Value>; 8] {
+        self.get_offsets(
+            p,
+            &[
+                [-1, -1],
+                [0, -1],
+                [1, -1],
+                [-1, 0],
+                [1, 0],
+                [-1, 1],
+                [0, 1],
+                [1, 1],
+            ],
+        )
+    }
+
+    /// Look up the positions at `offsets` from `p` in a single walk over the keys.
+    fn get_offsets<'a, const N: usize>(
+        &'a self,
+        p: &Point,
+        offsets: &[[i32; 2]; N],
+    ) -> [Option<&'a Value>; N] {
+        let mut keys = [None; N];
+        for (i, [dx, dy]) in offsets.iter().enumerate() {
+            let x = p[0].checked_add_signed(*dx);
+            let y = p[1].checked_add_signed(*dy);
+            if let (Some(x), Some(y)) = (x, y) {
+                keys[i] = Self::key_of(&Point::new(x, y)).map(|key| (key, i));
+            }
+        }
+        // visit the keys in ascending order, so each search can start where the last one ended
+        keys.sort_unstable();
+
+        let mut res = [None; N];
+        let mut lo = 0;
+        for (key, i) in keys.iter().flatten() {
+            // neighbours are usually close in Morton order, gallop from the previous hit
+            match self.gallop(lo, key) {
+                Ok(j) => {
+                    res[*i] = Some(&self.values[j]);
+                    lo = j;
+                }
+                Err(j) => {
+                    res[*i] = self
+                        .get_staged(&Point(key.as_point()))
+                        .map(|(_, value)| value);
+                    lo = j;
+                }
+            }
+        }
+        res
+    }
+
+    /// Like `find_key_morton`, but searches `keys[lo..]` by doubling the window from `lo`.
+    /// Faster than a plain binary search if `key` is close to `lo`, e.g. when visiting keys in
+    /// ascending order.
+    fn gallop(&self, lo: usize, key: &MortonKey) -> Result<usize, usize> {
+        let rest = &self.keys[lo..];
+        let mut bound = 1;
+        while bound < rest.len() && rest[bound] < *key {
+            bound *= 2;
+        }
+        rest[..rest.len().min(bound + 1)]
+            .binary_search(key)
+            .map(|j| lo + j)
+            .map_err(|j| lo + j)
+    }
+
+    /// Test the membership of many points at once. Bit `i % 64` of word `i / 64` of the result is
+    /// set if `points[i]` is in the table.
+    ///
+    /// The points are visited in Morton order, each search starting where the last one ended, so
+    /// spatially clustered points are cheap to look up. Sorting the points costs `O(k log k)`
+    /// up front, so this only beats a `contains_key` per point on larger tables, see the
+    /// `contains_batch` benchmark.
+    pub fn contains_bitset(&self, points: &[Point]) -> Vec<u64> {
+        let mut res = vec![0u64; points.len().div_ceil(64)];
+        let mut queries = points
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| Self::key_of(p).map(|key| (key, i)))
+            .collect::<Vec<_>>();
+        queries.sort_unstable();
+
+        let mut lo = 0;
+        for (key, i) in queries {
+            let found = match self.gallop(lo, &key) {
+                Ok(j) => {
+                    lo = j;
+                    true
+                }
+                Err(j) => {
+                    lo = j;
+                    self.get_staged(&points[i]).is_some()
+                }
+            };
+            if found {
+                res[i / 64] |= 1 << (i % 64);
+            }
+        }
+        res
     }
 
     pub fn contains_key(&self, id: &Point) -> bool {
-        if !self.intersects(&id) {
+        if !self.intersects(id) {
+            return false;
+        }
+        self.find_key(id).is_ok() || self.get_staged(id).is_some()
+    }
+
+    /// Exchange the values of the items at `a` and `b`. Returns `false` and leaves the table
+    /// unchanged if either item is missing.
+    pub fn swap_values(&mut self, a: &Point, b: &Point) -> bool {
+        if !self.intersects(a) || !self.intersects(b) {
             return false;
         }
-        self.find_key(id).is_ok()
+        self.compact();
+        match (self.find_key(a), self.find_key(b)) {
+            (Ok(ia), Ok(ib)) => {
+                self.values.swap(ia, ib);
+                self.log_change(*a);
+                self.log_change(*b);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return the position of the first item equal to `value` in Morton order, if any.
+    ///
+    /// This is a linear scan over all values, prefer looking items up by their position.
+    pub fn position_of(&self, value: &Value) -> Option<Point> {
+        self.values
+            .iter()
+            .position(|v| v == value)
+            .map(|i| self.positions[i])
+            .or_else(|| {
+                self.staged
+                    .iter()
+                    .find(|(_, _, v)| v == value)
+                    .map(|(_, id, _)| *id)
+            })
+    }
+
+    /// Find the position of `id` or the position where it needs to be inserted to keep the
+    /// container sorted
+    fn find_key(&self, id: &Point) -> Result<usize, usize> {
+        let key = Self::key_of(id).expect("id to be in the bounds of the table");
+
+        self.find_key_morton(&key)
+    }
+
+    /// Morton key of `id`, `None` if `id` is out of the bounds of the table.
+    fn key_of(id: &Point) -> Option<MortonKey> {
+        // checked conversions, bits above POS_MASK must not be silently truncated
+        let axis = |n: u32| u16::try_from(n).ok().filter(|n| *n as u32 <= POS_MASK);
+        Some(MortonKey::new(axis(id[0])?, axis(id[1])?))
+    }
+
+    /// The items of the skiplist in use
+    fn skiplist(&self) -> &[u32] {
+        &self.skiplist[..self.skiplen as usize]
+    }
+
+    /// Find the position of `key` or the position where it needs to be inserted to keep the
+    /// container sorted
+    fn find_key_morton(&self, key: &MortonKey) -> Result<usize, usize> {
+        let step = self.skipstep as usize;
+        if step == 0 || self.dirty {
+            return self.keys.binary_search(key);
+        }
+
+        let skiplen = self.skiplen as usize;
+        let index = find_key_partition(self.skiplist(), key);
+        let (begin, end) = {
+            if index < skiplen {
+                let begin = index * step;
+                let end = self.keys.len().min(begin + step + 1);
+                (begin, end)
+            } else {
+                // the key is greater than the last item in the skiplist, which is
+                // `keys[skiplen * step]`. The tail of the table may be longer than a single step.
+                let begin = skiplen * step;
+                let end = self.keys.len();
+                (begin, end)
+            }
+        };
+        self.keys[begin..end]
+            .binary_search(key)
+            .map(|ind| ind + begin)
+            .map_err(|ind| ind + begin)
+    }
+
+    /// Like `find_key_morton`, but returns the first of the items sharing `key` if it is found.
+    ///
+    /// Use it for the lower bound of an interval of keys, otherwise items at equal positions may
+    /// be skipped.
+    fn find_first_key_morton(&self, key: &MortonKey) -> Result<usize, usize> {
+        self.find_key_morton(key)
+            .map(|i| self.keys[..i].partition_point(|k| k < key))
+    }
+
+    /// Like `find_key_morton`, but returns the last of the items sharing `key` if it is found.
+    ///
+    /// Use it for the upper bound of an interval of keys, otherwise items at equal positions may
+    /// be skipped.
+    fn find_last_key_morton(&self, key: &MortonKey) -> Result<usize, usize> {
+        self.find_key_morton(key)
+            .map(|i| i + self.keys[i + 1..].partition_point(|k| k <= key))
+    }
+
+    /// Return the Morton keys of the corners of the AABB around the circle of the range query.
+    /// Every item within `radius` of `center` has a key in the interval `[min, max]`.
+    ///
+    /// The AABB is clamped to the bounds of the table.
+    pub fn range_keys(center: &Point, radius: u32) -> (MortonKey, MortonKey) {
+        let r = i32::try_from(radius).expect("radius to fit into 31 bits") as i64;
+
+        let [x, y] = **center;
+        let [x, y] = [x as i64, y as i64];
+        let clamp = |n: i64| n.max(0).min(POS_MASK as i64) as u16;
+        let min = MortonKey::new(clamp(x - r), clamp(y - r));
+        let max = MortonKey::new(clamp(x + r), clamp(y + r));
+
+        (min, max)
+    }
+
+    /// Estimate the number of items within `radius` of `center`.
+    ///
+    /// Returns the number of items in the Z-order interval spanned by the bounding box of the
+    /// query, without any distance checks. The interval is a superset of the box, which in turn
+    /// is a superset of the circle, so this overcounts, possibly by a lot if the box straddles a
+    /// high-level quadrant boundary. The estimate is never less than the exact count.
+    pub fn estimate_count_in_range(&self, center: &Point, radius: u32) -> usize {
+        let (min, max) = Self::range_keys(center, radius);
+        let imin = self.find_first_key_morton(&min).unwrap_or_else(|i| i);
+        let imax = self
+            .find_last_key_morton(&max)
+            .map(|i| i + 1)
+            .unwrap_or_else(|i| i);
+        imax.saturating_sub(imin)
+    }
+
+    /// Pick an item uniformly at random, `None` if the table is empty.
+    #[cfg(feature = "rand")]
+    pub fn sample<R: rand::Rng>(&self, rng: &mut R) -> Option<(Point, &Value)> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let i = rng.gen_range(0, self.keys.len());
+        Some((self.positions[i], &self.values[i]))
+    }
+
+    /// Pick an item within `radius` of `center` uniformly at random, `None` if there is none.
+    ///
+    /// Every item in range is equally likely to be picked, regardless of where it is in the
+    /// circle. Picks random items from the Morton interval of the query until one is in range,
+    /// which is uniform over the items in range. If the interval is mostly outside of the
+    /// circle it falls back to collecting the items in range and picking one of them.
+    #[cfg(feature = "rand")]
+    pub fn sample_in_range<R: rand::Rng>(
+        &self,
+        rng: &mut R,
+        center: &Point,
+        radius: u32,
+    ) -> Option<(Point, &Value)> {
+        let (min, max) = Self::range_keys(center, radius);
+        let imin = self.find_first_key_morton(&min).unwrap_or_else(|i| i);
+        let imax = self
+            .find_last_key_morton(&max)
+            .map(|i| i + 1)
+            .unwrap_or_else(|i| i);
+        if imax <= imin {
+            return None;
+        }
+        let radius_sq = radius as u64 * radius as u64;
+        for _ in 0..SAMPLE_ATTEMPTS {
+            let i = rng.gen_range(imin, imax);
+            if center.dist_sq(&self.positions[i]) <= radius_sq {
+                return Some((self.positions[i], &self.values[i]));
+            }
+        }
+        let mut candidates = Vec::new();
+        self.find_in_range_indices(center, radius, &mut candidates);
+        if candidates.is_empty() {
+            return None;
+        }
+        let i = candidates[rng.gen_range(0, candidates.len())];
+        Some((self.positions[i], &self.values[i]))
+    }
+
+    /// Push the items within `radius` of `center` to `out`, in Morton order, followed by the
+    /// matching items staged by `insert_staged`.
+    ///
+    /// Every item is pushed exactly once, including items sharing a position.
+    pub fn find_in_range<'a>(
+        &'a self,
+        center: &Point,
+        radius: u32,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) {
+        let (min, max) = Self::range_keys(center, radius);
+        let radius_sq = radius as u64 * radius as u64;
+        self.find_in_range_impl(center, &(0..=radius_sq), min, max, out, &|i, _| {
+            (self.positions[i], &self.values[i])
+        });
+        let center = *center;
+        out.extend(self.staged_where(move |id| center.dist_sq(id) <= radius_sq));
+    }
+
+    /// Like `find_in_range`, but the items are sorted by their distance to `center`, closest
+    /// first. Items at the same distance stay in the order of `find_in_range`.
+    ///
+    /// The sort adds `O(k log k)` for `k` items found on top of the unsorted query, and needs a
+    /// temporary buffer. The distances computed by the query are reused as the sort keys.
+    pub fn find_in_range_sorted<'a>(
+        &'a self,
+        center: &Point,
+        radius: u32,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) {
+        let (min, max) = Self::range_keys(center, radius);
+        let radius_sq = radius as u64 * radius as u64;
+        let mut found = Vec::new();
+        self.find_in_range_impl(center, &(0..=radius_sq), min, max, &mut found, &|i, d| {
+            (d, self.positions[i], &self.values[i])
+        });
+        let center = *center;
+        found.extend(
+            self.staged_where(move |id| center.dist_sq(id) <= radius_sq)
+                .map(|(id, value)| (center.dist_sq(&id), id, value)),
+        );
+        found.sort_by_key(|(d, _, _)| *d);
+        out.extend(found.into_iter().map(|(_, id, value)| (id, value)));
+    }
+
+    /// Like `find_in_range`, but with the distance measured by `metric`.
+    ///
+    /// The Chebyshev "circle" is its own bounding box, so those queries are a `find_in_aabb`
+    /// without any distance checks.
+    pub fn find_in_range_metric<'a>(
+        &'a self,
+        center: &Point,
+        radius: u32,
+        metric: Metric,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) {
+        let min = Point::new(
+            center[0].saturating_sub(radius),
+            center[1].saturating_sub(radius),
+        );
+        let max = Point::new(
+            center[0].saturating_add(radius),
+            center[1].saturating_add(radius),
+        );
+        match metric {
+            Metric::Euclidean => self.find_in_range(center, radius, out),
+            Metric::Chebyshev => self.find_in_aabb(&min, &max, out),
+            Metric::Manhattan => {
+                let center = *center;
+                let in_range = move |p: &Point| {
+                    p[0].abs_diff(center[0]) as u64 + p[1].abs_diff(center[1]) as u64
+                        <= radius as u64
+                };
+                for range in self.aabb_index_ranges(&min, &max) {
+                    for i in range {
+                        if in_range(&self.positions[i]) {
+                            out.push((self.positions[i], &self.values[i]));
+                        }
+                    }
+                }
+                out.extend(self.staged_where(in_range));
+            }
+        }
+    }
+
+    /// Like `find_in_range`, but in a toroidal world that wraps around at the edges of `bounds`,
+    /// e.g. a query near the left edge also finds the items near the right edge. Distances are
+    /// measured along the shortest way around. Centers out of bounds are wrapped into the world
+    /// first.
+    ///
+    /// The bounding box of the query is split at the edges into up to 4 disjoint boxes, so every
+    /// item is pushed once, even if the circle overlaps itself.
+    pub fn find_in_range_wrapped<'a>(
+        &'a self,
+        center: &Point,
+        radius: u32,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) {
+        let size = self.bounds().1[0];
+        let center = Point::new(center[0] % size, center[1] % size);
+        let radius_sq = radius as u64 * radius as u64;
+        let dist_sq = |p: &Point| {
+            let axis = |a: u32, b: u32| {
+                let d = a.abs_diff(b);
+                d.min(size - d) as u64
+            };
+            let [x, y] = [axis(p[0], center[0]), axis(p[1], center[1])];
+            x * x + y * y
+        };
+
+        let xs = wrapped_intervals(center[0], radius, size);
+        let ys = wrapped_intervals(center[1], radius, size);
+        for (x0, x1) in xs.iter() {
+            for (y0, y1) in ys.iter() {
+                let (min, max) = (Point::new(*x0, *y0), Point::new(*x1, *y1));
+                for range in self.aabb_index_ranges(&min, &max) {
+                    for i in range {
+                        // the ranges of a large box may hold items of the other boxes
+                        let p = &self.positions[i];
+                        if in_aabb(p, &min, &max) && dist_sq(p) <= radius_sq {
+                            out.push((self.positions[i], &self.values[i]));
+                        }
+                    }
+                }
+            }
+        }
+        out.extend(self.staged_where(move |id| dist_sq(id) <= radius_sq));
+    }
+
+    /// Like `find_in_range`, but validates the query up front and returns an error instead of
+    /// panicking on invalid input, e.g. for fuzzing.
+    pub fn try_find_in_range<'a>(
+        &'a self,
+        center: &Point,
+        radius: u32,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) -> Result<(), QueryError> {
+        if !self.intersects(center) {
+            return Err(QueryError::CenterOutOfBounds {
+                center: *center,
+                bounds: self.bounds(),
+            });
+        }
+        if i32::try_from(radius).is_err() {
+            return Err(QueryError::RadiusTooLarge(radius));
+        }
+        self.find_in_range(center, radius, out);
+        Ok(())
+    }
+
+    /// Find the items whose distance to `center` is in `[inner, outer]`, e.g. the shell of a
+    /// shockwave. `inner == 0` is the same as `find_in_range` with a radius of `outer`.
+    ///
+    /// Items in the inner disc are skipped during the scan, instead of being collected and
+    /// filtered afterwards.
+    pub fn find_in_ring<'a>(
+        &'a self,
+        center: &Point,
+        inner: u32,
+        outer: u32,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) {
+        let (min, max) = Self::range_keys(center, outer);
+        let dist_sq = inner as u64 * inner as u64..=outer as u64 * outer as u64;
+        self.find_in_range_impl(center, &dist_sq, min, max, out, &|i, _| {
+            (self.positions[i], &self.values[i])
+        });
+        let center = *center;
+        out.extend(self.staged_where(move |id| dist_sq.contains(&center.dist_sq(id))));
+    }
+
+    /// Run `find_in_range` starting at `start_radius` and doubling the radius until an item is
+    /// found or `max_radius` is reached. Returns the radius of the last query.
+    ///
+    /// Like in `find_in_range` the radius must fit into 31 bits.
+    pub fn find_in_expanding_range<'a>(
+        &'a self,
+        center: &Point,
+        start_radius: u32,
+        max_radius: u32,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) -> u32 {
+        let len = out.len();
+        let mut radius = start_radius.min(max_radius);
+        loop {
+            self.find_in_range(center, radius, out);
+            if out.len() > len || radius >= max_radius {
+                return radius;
+            }
+            radius = radius.saturating_mul(2).max(1).min(max_radius);
+        }
     }
 
-    /// Find the position of `id` or the position where it needs to be inserted to keep the
-    /// container sorted
-    fn find_key(&self, id: &Point) -> Result<usize, usize> {
-        let [x, y] = id.0;
-        let key = MortonKey::new(x as u16, y as u16);
-
-        self.find_key_morton(&key)
+    /// Lazy version of `find_in_range`, yielding the same items in the same order.
+    pub fn iter_in_range<'a>(&'a self, center: &Point, radius: u32) -> RangeIter<'a> {
+        RangeIter::new(self, center, radius)
     }
 
-    /// Find the position of `key` or the position where it needs to be inserted to keep the
-    /// container sorted
-    fn find_key_morton(&self, key: &MortonKey) -> Result<usize, usize> {
-        let step = self.skipstep as usize;
-        if step == 0 {
-            return self.keys.binary_search(&key);
-        }
-
-        let index = if is_x86_feature_detected!("sse2") {
-            unsafe { find_key_partition_sse2(&self.skiplist, &key) }
-        } else {
-            sse_panic()
-        };
-        let (begin, end) = {
-            if index < 8 {
-                let begin = index * step;
-                let end = self.keys.len().min(begin + step + 1);
-                (begin, end)
-            } else {
-                debug_assert!(self.keys.len() >= step + 3);
-                let end = self.keys.len();
-                let begin = end - step - 3;
-                (begin, end)
-            }
-        };
-        self.keys[begin..end]
-            .binary_search(&key)
-            .map(|ind| ind + begin)
-            .map_err(|ind| ind + begin)
+    /// Like `find_in_range` but outputs the indices of the items found instead of the items.
+    ///
+    /// The search only touches `keys` and `positions`, so the values can be fetched lazily
+    /// regardless of their size.
+    /// The indices are invalidated by any mutation of the table. Items staged by `insert_staged`
+    /// have no index and are skipped, `compact` the table first.
+    pub fn find_in_range_indices(&self, center: &Point, radius: u32, out: &mut Vec<usize>) {
+        let (min, max) = Self::range_keys(center, radius);
+        let radius_sq = radius as u64 * radius as u64;
+        self.find_in_range_impl(center, &(0..=radius_sq), min, max, out, &|i, _| i);
     }
 
-    pub fn find_in_range<'a>(
+    /// Like `find_in_range` but stops once `out` holds `limit` items.
+    ///
+    /// The items found are the first ones within `radius` in Morton order, not the closest ones.
+    pub fn find_in_range_limited<'a>(
         &'a self,
         center: &Point,
         radius: u32,
+        limit: usize,
         out: &mut Vec<(Point, &'a Value)>,
     ) {
-        debug_assert!(
-            radius & 0xefff == radius,
-            "Radius must fit into 31 bits!; {} != {}",
-            radius,
-            radius & 0xefff
-        );
-        let r = i32::try_from(radius).expect("radius to fit into 31 bits");
-
-        let [x, y] = **center;
-        let [x, y] = [x as i32, y as i32];
-        let min = MortonKey::new((x - r).max(0) as u16, (y - r).max(0) as u16);
-        let max = MortonKey::new((x + r) as u16, (y + r) as u16);
-
-        self.find_in_range_impl(center, radius, min, max, out);
+        let limit = limit.saturating_sub(out.len());
+        out.extend(self.iter_in_range(center, radius).take(limit));
     }
 
-    fn find_in_range_impl<'a>(
+    /// Like `find_in_range` but examines at most `max_scanned` positions.
+    ///
+    /// Returns `false` if the budget ran out before the query completed, in which case `out`
+    /// holds a partial result.
+    pub fn find_in_range_budgeted<'a>(
         &'a self,
         center: &Point,
         radius: u32,
+        max_scanned: usize,
+        out: &mut Vec<(Point, &'a Value)>,
+    ) -> bool {
+        let (min, max) = Self::range_keys(center, radius);
+        let radius_sq = radius as u64 * radius as u64;
+        let mut budget = max_scanned;
+        if !self.find_in_range_budgeted_impl(center, radius_sq, min, max, &mut budget, out) {
+            return false;
+        }
+        for (_, id, value) in self.staged.iter() {
+            if budget == 0 {
+                return false;
+            }
+            budget -= 1;
+            if center.dist_sq(id) <= radius_sq {
+                out.push((*id, value));
+            }
+        }
+        true
+    }
+
+    fn find_in_range_budgeted_impl<'a>(
+        &'a self,
+        center: &Point,
+        radius_sq: u64,
         min: MortonKey,
         max: MortonKey,
+        budget: &mut usize,
         out: &mut Vec<(Point, &'a Value)>,
+    ) -> bool {
+        match self.range_step(min, max) {
+            RangeStep::Split([litmax, bigmin]) => {
+                self.find_in_range_budgeted_impl(center, radius_sq, min, litmax, budget, out)
+                    && self.find_in_range_budgeted_impl(center, radius_sq, bigmin, max, budget, out)
+            }
+            RangeStep::Scan(range) => {
+                for i in range {
+                    if *budget == 0 {
+                        return false;
+                    }
+                    *budget -= 1;
+                    let id = &self.positions[i];
+                    if center.dist_sq(id) <= radius_sq {
+                        out.push((*id, &self.values[i]));
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Push the items in `[min, max]` whose squared distance to `center` is in `dist_sq`.
+    /// `item` maps the index of an item and its squared distance to the output.
+    fn find_in_range_impl<T>(
+        &self,
+        center: &Point,
+        dist_sq: &RangeInclusive<u64>,
+        min: MortonKey,
+        max: MortonKey,
+        out: &mut Vec<T>,
+        item: &impl Fn(usize, u64) -> T,
     ) {
+        match self.range_step(min, max) {
+            RangeStep::Split([litmax, bigmin]) => {
+                // split and recurse
+                // litmax < bigmin, so the index ranges of the halves are disjoint
+                self.find_in_range_impl(center, dist_sq, min, litmax, out, item);
+                self.find_in_range_impl(center, dist_sq, bigmin, max, out, item);
+            }
+            RangeStep::Scan(range) => {
+                let begin = range.start;
+                for (i, id) in self.positions[range].iter().enumerate() {
+                    let d = center.dist_sq(id);
+                    if dist_sq.contains(&d) {
+                        out.push(item(i + begin, d));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decide whether the keys in `[min, max]` should be scanned or split into two ranges.
+    fn range_step(&self, min: MortonKey, max: MortonKey) -> RangeStep {
         let (imin, pmin) = self
-            .find_key_morton(&min)
+            .find_first_key_morton(&min)
             .map(|i| (i, *self.positions[i]))
             .unwrap_or_else(|i| (i, min.as_point()));
 
         let (imax, pmax) = self
-            .find_key_morton(&max)
+            .find_last_key_morton(&max)
             // add 1 to include this node in the range query as otherwise an element might be
             // missed
             .map(|i| (i + 1, *self.positions[i]))
             .unwrap_or_else(|i| (i, max.as_point()));
 
         if imax < imin {
-            return;
+            return RangeStep::Scan(0..0);
         }
 
         // The original paper counts the garbage items and splits above a threshold.
         // Instead let's speculate if we need a split or if it more beneficial to just scan the
         // range
-        // The number I picked is more or less arbitrary, it is a power of two and I ran the basic
-        // benchmarks to probe a few numbers.
-        if imax - imin > 32 {
-            let [litmax, bigmin] = litmax_bigmin(min.0, pmin, max.0, pmax);
-            // split and recurse
-            self.find_in_range_impl(center, radius, min, litmax, out);
-            self.find_in_range_impl(center, radius, bigmin, max, out);
-            return;
-        }
-
-        for (i, id) in self.positions[imin..imax].iter().enumerate() {
-            if center.dist(&id) < radius {
-                out.push((*id, &self.values[i + imin]));
-            }
+        // The default threshold is more or less arbitrary, it is a power of two and I ran the
+        // basic benchmarks to probe a few numbers.
+        // a range of a single key can not be split any further
+        if imax - imin > self.split_threshold && min < max {
+            return RangeStep::Split(litmax_bigmin(min.0, pmin, max.0, pmax));
         }
+        RangeStep::Scan(imin..imax)
     }
 
     /// This implementation will split after 3 garbage points visited.
@@ -268,20 +1726,11 @@ impl MortonTable {
         radius: u32,
         out: &mut Vec<(Point, &'a Value)>,
     ) {
-        debug_assert!(
-            radius & 0xefff == radius,
-            "Radius must fit into 31 bits!; {} != {}",
-            radius,
-            radius & 0xefff
-        );
-        let r = i32::try_from(radius).expect("radius to fit into 31 bits");
-
-        let [x, y] = **center;
-        let [x, y] = [x as i32, y as i32];
-        let min = MortonKey::new((x - r).max(0) as u16, (y - r).max(0) as u16);
-        let max = MortonKey::new((x + r) as u16, (y + r) as u16);
-
+        let (min, max) = Self::range_keys(center, radius);
         self.find_in_range_impl_2(center, radius, min, max, 0, out);
+        let center = *center;
+        let radius_sq = radius as u64 * radius as u64;
+        out.extend(self.staged_where(move |id| center.dist_sq(id) <= radius_sq));
     }
 
     fn find_in_range_impl_2<'a>(
@@ -290,11 +1739,11 @@ impl MortonTable {
         radius: u32,
         min: MortonKey,
         max: MortonKey,
-        startind: usize, // track visited items
+        startind: usize, // track visited items; an index into `keys`, not into the scanned slice
         out: &mut Vec<(Point, &'a Value)>,
     ) {
         let (imin, pmin) = self
-            .find_key_morton(&min)
+            .find_first_key_morton(&min)
             .map(|i| (i, *self.positions[i]))
             .unwrap_or_else(|i| (i, min.as_point()));
         let imin = imin.max(startind);
@@ -302,7 +1751,7 @@ impl MortonTable {
         // this is used to skip already visited nodes when recursing
 
         let (imax, pmax) = self
-            .find_key_morton(&max)
+            .find_last_key_morton(&max)
             // add 1 to include this node in the range query as otherwise an element might be
             // missed
             .map(|i| (i + 1, *self.positions[i]))
@@ -312,15 +1761,17 @@ impl MortonTable {
             return;
         }
 
+        let radius_sq = radius as u64 * radius as u64;
         let mut miss = 0;
 
         for (i, id) in self.positions[imin..imax].iter().enumerate() {
+            // translate the slice-local index into the index space of the table
             let ind = imin + i;
-            if center.dist(&id) < radius {
+            if center.dist_sq(id) <= radius_sq {
                 out.push((*id, &self.values[ind]));
             } else {
                 miss += 1;
-                if miss >= 3 {
+                if miss >= 3 && min < max {
                     let [litmax, bigmin] = litmax_bigmin(min.0, pmin, max.0, pmax);
                     // split and recurse
                     self.find_in_range_impl_2(center, radius, min, litmax, ind, out);
@@ -331,10 +1782,422 @@ impl MortonTable {
         }
     }
 
+    /// Find the `K` items closest to `center` without allocating.
+    ///
+    /// The result is sorted by distance, closest first. Slots are `None` if the table holds less
+    /// than `K` items. Ties are broken arbitrarily.
+    pub fn nearest_into<const K: usize>(&self, center: &Point) -> [Option<(Point, Value)>; K] {
+        if K == 0 || (self.keys.is_empty() && self.staged.is_empty()) {
+            return [None; K];
+        }
+
+        let [x, y] = **center;
+        let [x, y] = [x.min(POS_MASK), y.min(POS_MASK)];
+        // scan an expanding box around the center until the K closest items found are all
+        // within the radius of the box, so no item outside of it can be closer.
+        let mut radius = 1;
+        loop {
+            let mut nearest = [None; K];
+            let mut dists = [u64::MAX; K];
+            let mut push = |id: Point, value: Value| {
+                let d = center.dist_sq(&id);
+                if d >= dists[K - 1] {
+                    return;
+                }
+                // insert while keeping the buffer sorted, dropping the furthest item
+                let mut j = K - 1;
+                while j > 0 && dists[j - 1] > d {
+                    dists[j] = dists[j - 1];
+                    nearest[j] = nearest[j - 1];
+                    j -= 1;
+                }
+                dists[j] = d;
+                nearest[j] = Some((id, value));
+            };
+
+            let aabb = [
+                [x.saturating_sub(radius), y.saturating_sub(radius)],
+                [(x + radius).min(POS_MASK), (y + radius).min(POS_MASK)],
+            ];
+            let min = MortonKey::new_u32(aabb[0][0], aabb[0][1]);
+            let max = MortonKey::new_u32(aabb[1][0], aabb[1][1]);
+            self.for_each_in_aabb_impl(&aabb, min, max, &mut |i| {
+                push(self.positions[i], self.values[i])
+            });
+            // the staged items are few, visit all of them
+            for (_, id, value) in self.staged.iter() {
+                push(*id, *value);
+            }
+
+            let covers_table = aabb[0] == [0, 0] && aabb[1] == [POS_MASK, POS_MASK];
+            if covers_table || dists[K - 1] <= radius as u64 * radius as u64 {
+                return nearest;
+            }
+            radius *= 2;
+        }
+    }
+
+    /// Call `f` with the index of every item in the box `aabb`.
+    /// `min` and `max` are the Morton keys of the part of the box to visit.
+    fn for_each_in_aabb_impl<F>(
+        &self,
+        aabb: &[[u32; 2]; 2],
+        min: MortonKey,
+        max: MortonKey,
+        f: &mut F,
+    ) where
+        F: FnMut(usize),
+    {
+        match self.range_step(min, max) {
+            RangeStep::Split([litmax, bigmin]) => {
+                self.for_each_in_aabb_impl(aabb, min, litmax, f);
+                self.for_each_in_aabb_impl(aabb, bigmin, max, f);
+            }
+            RangeStep::Scan(range) => {
+                let [[x1, y1], [x2, y2]] = *aabb;
+                let begin = range.start;
+                for (i, [x, y]) in self.positions[range].iter().map(|p| p.0).enumerate() {
+                    if x1 <= x && x <= x2 && y1 <= y && y <= y2 {
+                        f(begin + i);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run a range query for every center in `centers` in parallel and call `f` with every item
+    /// found.
+    /// Items in the intersection of multiple queries are visited once per query.
+    pub fn par_for_each_in_range<F>(&self, centers: &[Point], radius: u32, f: F)
+    where
+        F: Fn(&Point, &Value) + Sync,
+    {
+        centers.par_iter().for_each_init(Vec::new, |out, center| {
+            self.find_in_range(center, radius, out);
+            for (p, v) in out.drain(..) {
+                f(&p, v);
+            }
+        });
+    }
+
+    /// Run a range query for every center in `centers`, the items found around `centers[i]` are
+    /// written to `out[i]`. `out` is resized to the number of centers, reusing the allocations of
+    /// the lists already in it.
+    ///
+    /// The queries run in the Morton order of their centers, so consecutive queries touch nearby
+    /// parts of the table. This only pays off if the queries overlap and the table does not fit
+    /// into the cache, otherwise sorting the centers is pure overhead over calling
+    /// `find_in_range` in a loop, see the `find_in_range_many_centers` benchmark.
+    pub fn find_in_range_multi<'a>(
+        &'a self,
+        centers: &[Point],
+        radius: u32,
+        out: &mut Vec<Vec<(Point, &'a Value)>>,
+    ) {
+        out.resize_with(centers.len(), Vec::new);
+        for res in out.iter_mut() {
+            res.clear();
+        }
+        // the order is only a heuristic, centers out of bounds may share their keys
+        let mut order = centers
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (MortonKey::new_u32(p[0].min(0xffff), p[1].min(0xffff)), i))
+            .collect::<Vec<_>>();
+        order.sort_unstable();
+        for (_, i) in order {
+            self.find_in_range(&centers[i], radius, &mut out[i]);
+        }
+    }
+
+    /// Find all items in the inclusive box `[min, max]`.
+    /// Small boxes are decomposed into contiguous Morton intervals, so every scanned item is a
+    /// hit. Large ones are split like in `find_in_range`.
+    pub fn find_in_aabb<'a>(&'a self, min: &Point, max: &Point, out: &mut Vec<(Point, &'a Value)>) {
+        for range in self.aabb_index_ranges(min, max) {
+            for i in range {
+                if in_aabb(&self.positions[i], min, max) {
+                    out.push((self.positions[i], &self.values[i]));
+                }
+            }
+        }
+        let (min, max) = (*min, *max);
+        out.extend(self.staged_where(move |p| in_aabb(p, &min, &max)));
+    }
+
+    /// Find all items in the convex polygon `verts`, including the items on its edges.
+    ///
+    /// The vertices may be in either winding order. Concave polygons are not supported, their
+    /// results are unspecified. Fewer than 3 vertices, or collinear ones, describe a segment.
+    pub fn find_in_polygon<'a>(&'a self, verts: &[Point], out: &mut Vec<(Point, &'a Value)>) {
+        let first = match verts.first() {
+            Some(p) => *p,
+            None => return,
+        };
+        let (min, max) = verts.iter().fold((first, first), |(min, max), p| {
+            (
+                Point::new(min[0].min(p[0]), min[1].min(p[1])),
+                Point::new(max[0].max(p[0]), max[1].max(p[1])),
+            )
+        });
+        let edges = || {
+            verts
+                .iter()
+                .zip(verts.iter().cycle().skip(1))
+                .map(|(a, b)| [a, b])
+        };
+        // the sign of twice the signed area, positive if counter clockwise
+        let winding = edges()
+            .map(|[a, b]| a[0] as i64 * b[1] as i64 - b[0] as i64 * a[1] as i64)
+            .sum::<i64>()
+            .signum();
+        // the point is on the inner side of, or on, every edge
+        let inside = |p: &Point| {
+            edges().all(|[a, b]| {
+                let cross = cross(a, b, p);
+                if winding == 0 {
+                    cross == 0
+                } else {
+                    cross * winding >= 0
+                }
+            })
+        };
+        for range in self.aabb_index_ranges(&min, &max) {
+            for i in range {
+                if inside(&self.positions[i]) {
+                    out.push((self.positions[i], &self.values[i]));
+                }
+            }
+        }
+        out.extend(self.staged_where(inside));
+    }
+
+    /// Return whether any item is in the inclusive box `[min, max]`.
+    /// Stops at the first hit, intended for AABB-AABB broad phase checks.
+    pub fn contains_any_in_aabb(&self, min: &Point, max: &Point) -> bool {
+        self.aabb_index_ranges(min, max)
+            .any(|range| self.positions[range].iter().any(|p| in_aabb(p, min, max)))
+            || self.staged.iter().any(|(_, p, _)| in_aabb(p, min, max))
+    }
+
+    /// Copy the items in the inclusive box `[min, max]` into a new table.
+    ///
+    /// The items are copied in Morton order, so the new table needs no sorting.
+    pub fn extract_region(&self, min: &Point, max: &Point) -> MortonTable {
+        let mut res = self.empty_like();
+        for range in self.aabb_index_ranges(min, max) {
+            for i in range.filter(|i| in_aabb(&self.positions[*i], min, max)) {
+                res.keys.push(self.keys[i]);
+                res.positions.push(self.positions[i]);
+                res.values.push(self.values[i]);
+            }
+        }
+        res.staged
+            .extend(self.staged.iter().filter(|(_, p, _)| in_aabb(p, min, max)));
+        res.rebuild_skip_list();
+        res
+    }
+
+    /// Split the table into the items with keys less than `pivot` and the rest, e.g. to divide
+    /// the work over threads. Both halves are still sorted and keep the configuration of this
+    /// table.
+    pub fn split_at_key(mut self, pivot: MortonKey) -> (MortonTable, MortonTable) {
+        // the first key not less than the pivot; unlike a binary search this is the first of
+        // its duplicates
+        self.compact();
+        let ind = self.keys.partition_point(|key| *key < pivot);
+        let mut right = self.empty_like();
+        right.keys = self.keys.split_off(ind);
+        right.positions = self.positions.split_off(ind);
+        right.values = self.values.split_off(ind);
+        right.rebuild_skip_list();
+        self.rebuild_skip_list();
+        (self, right)
+    }
+
+    /// Copy the items matching `pred` into a new table, leaving this table untouched.
+    ///
+    /// The items are copied in Morton order, so the new table needs no sorting.
+    pub fn filter<F>(&self, pred: F) -> MortonTable
+    where
+        F: Fn(&Point, &Value) -> bool,
+    {
+        let mut res = self.empty_like();
+        for ((key, pos), value) in self
+            .keys
+            .iter()
+            .zip(self.positions.iter())
+            .zip(self.values.iter())
+        {
+            if pred(pos, value) {
+                res.keys.push(*key);
+                res.positions.push(*pos);
+                res.values.push(*value);
+            }
+        }
+        res.staged.extend(
+            self.staged
+                .iter()
+                .filter(|(_, pos, value)| pred(pos, value)),
+        );
+        res.rebuild_skip_list();
+        res
+    }
+
+    /// An empty table with the configuration of this table, for the tables derived from it.
+    fn empty_like(&self) -> MortonTable {
+        MortonTable {
+            use_skiplist: self.use_skiplist,
+            parallel: self.parallel,
+            split_threshold: self.split_threshold,
+            origin: self.origin,
+            ..Self::new()
+        }
+    }
+
+    /// Indices of the items that may be in the inclusive box `[min, max]`, as disjoint ranges in
+    /// ascending order.
+    ///
+    /// The box is decomposed into contiguous Morton intervals, which cover it exactly. Their
+    /// number grows with the perimeter of the box and each costs two searches, so boxes needing
+    /// more than `MAX_AABB_RANGES` intervals are split like in `find_in_range` instead. Then the
+    /// ranges also hold items out of the box, filter them with `in_aabb`.
+    fn aabb_index_ranges(&self, min: &Point, max: &Point) -> AabbRanges<'_> {
+        if min[0] > POS_MASK || min[1] > POS_MASK {
+            // the box is out of the bounds of the table
+            return AabbRanges::exact(self, Vec::new());
+        }
+        let lo = [min[0] as u16, min[1] as u16];
+        let hi = [max[0].min(POS_MASK) as u16, max[1].min(POS_MASK) as u16];
+        match try_morton_ranges(lo, hi, MAX_AABB_RANGES) {
+            Some(ranges) => AabbRanges::exact(self, ranges),
+            None => AabbRanges::split(
+                self,
+                MortonKey::new(lo[0], lo[1]),
+                MortonKey::new(hi[0], hi[1]),
+            ),
+        }
+    }
+
+    /// The indices of the items in the Morton interval `[min, max]`.
+    fn key_index_range(&self, min: &MortonKey, max: &MortonKey) -> std::ops::Range<usize> {
+        let imin = self.find_first_key_morton(min).unwrap_or_else(|i| i);
+        let imax = self
+            .find_last_key_morton(max)
+            .map(|i| i + 1)
+            .unwrap_or_else(|i| i);
+        imin..imax
+    }
+
+    /// Count the items per cell of a grid of cells with a side length of `2^cell_bits`.
+    ///
+    /// The result is indexed by the Morton id of the cell and holds `4^(15 - cell_bits)` cells.
+    /// Returns an error unless `cell_bits` is in `5..=15`: smaller cells would allocate up to
+    /// 4 GiB, `cell_counts` returns only the non-empty cells of such grids.
+    pub fn histogram(&self, cell_bits: u8) -> Result<Vec<u32>, HistogramError> {
+        if cell_bits < MIN_HISTOGRAM_CELL_BITS {
+            return Err(HistogramError::CellsTooSmall(cell_bits));
+        }
+        if cell_bits > 15 {
+            return Err(HistogramError::CellsTooLarge(cell_bits));
+        }
+        let shift = 2 * cell_bits as u32;
+        let mut res = vec![0; 1 << (30 - shift)];
+        // the keys are sorted, so the items of a cell are adjacent
+        for run in self.keys.chunk_by(|a, b| a.0 >> shift == b.0 >> shift) {
+            res[(run[0].0 >> shift) as usize] = run.len() as u32;
+        }
+        Ok(res)
+    }
+
+    /// Count the items per quadtree cell at `depth`, see `cell_at_depth`. Unlike `histogram` only
+    /// the non-empty cells are returned, as `(cell, count)` pairs in Morton order.
+    pub fn cell_counts(&self, depth: u8) -> Vec<(u32, u32)> {
+        assert!(depth <= 16, "depth must be at most 16");
+        self.keys
+            .chunk_by(|a, b| a.cell(depth) == b.cell(depth))
+            .map(|run| (run[0].cell(depth), run.len() as u32))
+            .collect()
+    }
+
+    /// Iterate over the items grouped by the quadtree cell at `depth` they fall into, see
+    /// `cell_at_depth`. Yields `(cell, positions, values)` for the non-empty cells in Morton
+    /// order.
+    ///
+    /// The items of a cell are adjacent in the table, so this is a single linear scan.
+    pub fn iter_cells<'a>(
+        &'a self,
+        depth: u8,
+    ) -> impl Iterator<Item = (u32, &'a [Point], &'a [Value])> + 'a {
+        assert!(depth <= 16, "depth must be at most 16");
+        let mut begin = 0;
+        std::iter::from_fn(move || {
+            let cell = self.keys.get(begin)?.cell(depth);
+            let len = self.keys[begin..]
+                .iter()
+                .position(|k| k.cell(depth) != cell)
+                .unwrap_or(self.keys.len() - begin);
+            let range = begin..begin + len;
+            begin += len;
+            Some((cell, &self.positions[range.clone()], &self.values[range]))
+        })
+    }
+
+    /// The sorted Morton keys of the items in the table, without the items staged by
+    /// `insert_staged`
+    pub fn keys(&self) -> &[MortonKey] {
+        &self.keys
+    }
+
+    /// The values of the table in Morton order, for bulk in-place updates.
+    ///
+    /// The keys and positions can not be changed through this slice, so the table stays sorted
+    /// and needs no rebuild.
+    pub fn values_mut(&mut self) -> &mut [Value] {
+        self.compact();
+        &mut self.values
+    }
+
+    /// Apply `f` to every value in place, passing its position along, e.g. to apply a field
+    /// that depends on the position. Like `values_mut` the table needs no rebuild afterwards.
+    pub fn map_values<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Point, &mut Value),
+    {
+        self.compact();
+        for (p, v) in self.positions.iter().zip(self.values.iter_mut()) {
+            f(p, v);
+        }
+    }
+
+    /// Iterate over the positions in the table in Morton order
+    pub fn points(&self) -> impl Iterator<Item = &Point> {
+        self.positions.iter()
+    }
+
+    /// Iterate over the items in raster order, sorted by `(y, x)`, e.g. to export an image.
+    ///
+    /// The table is stored in Morton order, so this sorts a temporary index of the items up front,
+    /// costing `O(n log n)` time and `O(n)` memory before the first item is yielded.
+    pub fn iter_raster<'a>(&'a self) -> impl Iterator<Item = (Point, &'a Value)> + 'a {
+        let mut indices = (0..self.positions.len()).collect::<Vec<_>>();
+        indices.sort_by_key(|i| {
+            let [x, y] = *self.positions[*i];
+            (y, x)
+        });
+        indices
+            .into_iter()
+            .map(move |i| (self.positions[i], &self.values[i]))
+    }
+
+    /// Iterate over the values in the table in Morton order
+    pub fn values_iter(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter()
+    }
+
     /// Return wether point is within the bounds of this node
     pub fn intersects(&self, point: &Point) -> bool {
         let [x, y] = point.0;
-        let [x, y] = [x as u32, y as u32];
         (x & POS_MASK) == x && (y & POS_MASK) == y
     }
 
@@ -344,56 +2207,369 @@ impl MortonTable {
         (Point::new(0, 0), Point::new(max, max))
     }
 
+    /// Return the inclusive [min, max] of the stored positions, `None` if the table is empty.
+    /// Unlike `bounds` this reflects the actual contents of the table.
+    pub fn extent(&self) -> Option<(Point, Point)> {
+        let first = *self.positions.first()?;
+        let (min, max) = self.positions.iter().fold((first, first), |(min, max), p| {
+            (
+                Point::new(min[0].min(p[0]), min[1].min(p[1])),
+                Point::new(max[0].max(p[0]), max[1].max(p[1])),
+            )
+        });
+        Some((min, max))
+    }
+
+    /// Find the closest position within `bounds` that is not in the table.
+    ///
+    /// Searches rings of growing Chebyshev distance around `center`, so the result is the
+    /// closest in grid steps, counting diagonal steps as 1. Returns `None` if every position is
+    /// occupied.
+    ///
+    /// A `center` outside of `bounds` is clamped into them first.
+    pub fn closest_empty(&self, center: &Point) -> Option<Point> {
+        let max = POS_MASK as i64;
+        // out of bounds centers would walk rings that never intersect the table
+        let [cx, cy] = [
+            center[0].min(POS_MASK) as i64,
+            center[1].min(POS_MASK) as i64,
+        ];
+        // the last ring that intersects the bounds
+        let max_dist = cx.max(cy).max(max - cx).max(max - cy);
+        (0..=max_dist)
+            .flat_map(|d| ring([cx, cy], d))
+            .filter(|&[x, y]| 0 <= x && x <= max && 0 <= y && y <= max)
+            .map(|[x, y]| Point::new(x as u32, y as u32))
+            .find(|p| !self.contains_key(p))
+    }
+
+    /// Move every item by `delta`, e.g. to scroll the window of an infinite world.
+    ///
+    /// Items pushed out of the bounds of the table are removed and returned at their original
+    /// position. The table is sorted once afterwards, instead of moving the items one by one.
+    pub fn translate(&mut self, delta: [i32; 2]) -> Vec<(Point, Value)> {
+        self.compact();
+        let mut evicted = Vec::new();
+        let mut len = 0;
+        for i in 0..self.keys.len() {
+            let id = self.positions[i];
+            let moved = id[0]
+                .checked_add_signed(delta[0])
+                .zip(id[1].checked_add_signed(delta[1]))
+                .map(|(x, y)| Point::new(x, y));
+            let key = moved.and_then(|p| Self::key_of(&p).map(|key| (p, key)));
+            self.log_change(id);
+            match key {
+                Some((p, key)) => {
+                    self.keys[len] = key;
+                    self.positions[len] = p;
+                    self.values[len] = self.values[i];
+                    self.log_change(p);
+                    len += 1;
+                }
+                None => evicted.push((id, self.values[i])),
+            }
+        }
+        self.keys.truncate(len);
+        self.positions.truncate(len);
+        self.values.truncate(len);
+        self.sort_items();
+        self.rebuild_skip_list();
+        evicted
+    }
+
+    /// Merge the entries sharing a position into one, folding the values of the later entries
+    /// into the first with `combine`, e.g. to sum stacked resources after `extend`.
+    ///
+    /// The entries of a position are visited in table order, which is not necessarily the order
+    /// they were inserted in.
+    pub fn dedup_by<F>(&mut self, mut combine: F)
+    where
+        F: FnMut(&mut Value, Value),
+    {
+        self.compact();
+        if self.keys.is_empty() {
+            return;
+        }
+        // the keys are sorted, so duplicates are adjacent
+        let mut last = 0;
+        for i in 1..self.keys.len() {
+            if self.keys[i] == self.keys[last] {
+                let value = self.values[i];
+                combine(&mut self.values[last], value);
+                self.log_change(self.positions[last]);
+            } else {
+                last += 1;
+                self.keys[last] = self.keys[i];
+                self.positions[last] = self.positions[i];
+                self.values[last] = self.values[i];
+            }
+        }
+        self.keys.truncate(last + 1);
+        self.positions.truncate(last + 1);
+        self.values.truncate(last + 1);
+        self.rebuild_skip_list();
+    }
+
+    /// Return the center and radius of the smallest circle enclosing every position, `None` if
+    /// the table is empty. Complements `extent` for radial framing.
+    ///
+    /// Computes the exact smallest enclosing circle by Welzl's algorithm, then rounds its center
+    /// to the closest position and grows the radius to enclose every position from there. So
+    /// the radius may be up to 2 larger than the exact one.
+    pub fn bounding_circle(&self) -> Option<(Point, u32)> {
+        if self.positions.is_empty() {
+            return None;
+        }
+        let ([x, y], _) = enclosing_circle::enclosing_circle(&self.positions);
+        let max = POS_MASK as f64;
+        let center = Point::new(
+            x.round().max(0.0).min(max) as u32,
+            y.round().max(0.0).min(max) as u32,
+        );
+        let radius_sq = self
+            .positions
+            .iter()
+            .map(|p| center.dist_sq(p))
+            .max()
+            .unwrap_or(0);
+        Some((center, ceil_sqrt(radius_sq) as u32))
+    }
+
+    /// Return the two items closest to each other, `None` if the table holds less than 2 items.
+    /// Ties are broken arbitrarily, items sharing a position are a pair at distance 0.
+    ///
+    /// Items adjacent in Morton order are often close, so the first guess compares each item to
+    /// the next `CLOSEST_PAIR_WINDOW` items. A sweep line along the x axis then refines it to the
+    /// exact answer, only comparing items closer on both axes than the best pair found so far.
+    pub fn closest_pair(&self) -> Option<(Entry<'_>, Entry<'_>)> {
+        let len = self.positions.len();
+        if len < 2 {
+            return None;
+        }
+        let pos = &self.positions;
+        // (distance squared, index, index)
+        let mut best = (u64::MAX, 0, 1);
+        for i in 0..len {
+            for j in i + 1..len.min(i + 1 + CLOSEST_PAIR_WINDOW) {
+                let d = pos[i].dist_sq(&pos[j]);
+                if d < best.0 {
+                    best = (d, i, j);
+                }
+            }
+        }
+
+        let mut order = (0..len).collect::<Vec<_>>();
+        order.sort_unstable_by_key(|i| pos[*i][0]);
+        // the items within the best distance along x of the sweep line, by (y, index)
+        let mut active = std::collections::BTreeSet::new();
+        let mut tail = 0;
+        for &i in order.iter() {
+            if best.0 == 0 {
+                break;
+            }
+            let p = pos[i];
+            let reach = ceil_sqrt(best.0).min(u32::MAX as u64) as u32;
+            while pos[order[tail]][0].saturating_add(reach) < p[0] {
+                active.remove(&(pos[order[tail]][1], order[tail]));
+                tail += 1;
+            }
+            let lo = (p[1].saturating_sub(reach), 0);
+            let hi = (p[1].saturating_add(reach), usize::MAX);
+            for &(_, j) in active.range(lo..=hi) {
+                let d = p.dist_sq(&pos[j]);
+                if d < best.0 {
+                    best = (d, j, i);
+                }
+            }
+            active.insert((p[1], i));
+        }
+
+        let (_, i, j) = best;
+        let (i, j) = (i.min(j), i.max(j));
+        Some(((pos[i], &self.values[i]), (pos[j], &self.values[j])))
+    }
+
     pub fn delete(&mut self, id: &Point) -> Option<Value> {
-        if !self.contains_key(id) {
+        if !self.intersects(id) {
             return None;
         }
+        self.compact();
 
-        self.find_key(&id)
-            .map(|ind| {
-                self.keys.remove(ind);
-                self.positions.remove(ind);
-                self.values.remove(ind)
-            })
-            .ok()
+        let ind = self.find_key(id).ok()?;
+        self.keys.remove(ind);
+        self.positions.remove(ind);
+        let value = self.values.remove(ind);
+        self.rebuild_skip_list();
+        self.log_change(*id);
+        Some(value)
+    }
+
+    /// Remove the items within `radius` of `center` and return them in Morton order, e.g. to
+    /// hand the items in a pickup radius over to an inventory.
+    ///
+    /// The table is compacted in a single pass and the skiplist is rebuilt once, regardless of
+    /// the number of items removed.
+    pub fn drain_in_range(&mut self, center: &Point, radius: u32) -> Vec<(Point, Value)> {
+        self.compact();
+        let mut indices = Vec::new();
+        self.find_in_range_indices(center, radius, &mut indices);
+        self.remove_indices(&indices)
+    }
+
+    /// Remove the items within `radius` of `center` for which `keep` returns `false`, e.g. to
+    /// despawn everything in a blast radius except the bosses. Items outside of the circle are
+    /// left untouched and `keep` is not called for them.
+    ///
+    /// The table is compacted in a single pass and the skiplist is rebuilt once.
+    pub fn retain_in_range<F>(&mut self, center: &Point, radius: u32, mut keep: F)
+    where
+        F: FnMut(&Point, &Value) -> bool,
+    {
+        self.compact();
+        let mut indices = Vec::new();
+        self.find_in_range_indices(center, radius, &mut indices);
+        indices.retain(|i| !keep(&self.positions[*i], &self.values[*i]));
+        self.remove_indices(&indices);
+    }
+
+    /// Remove the items at `indices` in a single pass and return them.
+    /// `indices` must be sorted and unique.
+    fn remove_indices(&mut self, indices: &[usize]) -> Vec<(Point, Value)> {
+        debug_assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        let first = match indices.first() {
+            Some(i) => *i,
+            None => return Vec::new(),
+        };
+        let mut removed = Vec::with_capacity(indices.len());
+        let mut next = indices.iter().peekable();
+        // the items before `first` stay in place
+        let mut last = first;
+        for i in first..self.keys.len() {
+            if next.peek() == Some(&&i) {
+                next.next();
+                removed.push((self.positions[i], self.values[i]));
+            } else {
+                self.keys[last] = self.keys[i];
+                self.positions[last] = self.positions[i];
+                self.values[last] = self.values[i];
+                last += 1;
+            }
+        }
+        self.keys.truncate(last);
+        self.positions.truncate(last);
+        self.values.truncate(last);
+        self.rebuild_skip_list();
+        for (id, _) in removed.iter() {
+            self.log_change(*id);
+        }
+        removed
+    }
+}
+
+/// The cells at Chebyshev distance `dist` from `center`, row by row.
+fn ring(center: [i64; 2], dist: i64) -> impl Iterator<Item = [i64; 2]> {
+    let [cx, cy] = center;
+    (cy - dist..=cy + dist).flat_map(move |y| {
+        // the first and last rows are whole, the rows in between only have their 2 ends
+        let step = if y == cy - dist || y == cy + dist {
+            1
+        } else {
+            2 * dist as usize
+        };
+        (cx - dist..=cx + dist).step_by(step).map(move |x| [x, y])
+    })
+}
+
+/// Split `[c - r, c + r]` on an axis of `size` cells that wraps around into at most 2 disjoint
+/// inclusive intervals in `[0, size)`.
+fn wrapped_intervals(c: u32, r: u32, size: u32) -> ArrayVec<[(u32, u32); 2]> {
+    let mut res = ArrayVec::new();
+    let (lo, hi) = (c as i64 - r as i64, c as i64 + r as i64);
+    let size = size as i64;
+    if hi - lo + 1 >= size {
+        res.push((0, size as u32 - 1));
+    } else if lo < 0 {
+        res.push((0, hi as u32));
+        res.push(((lo + size) as u32, size as u32 - 1));
+    } else if hi >= size {
+        res.push((0, (hi - size) as u32));
+        res.push((lo as u32, size as u32 - 1));
+    } else {
+        res.push((lo as u32, hi as u32));
+    }
+    res
+}
+
+/// The z component of the cross product of `b - a` and `p - a`.
+/// Positive if `p` is to the left of the line from `a` to `b`.
+fn cross(a: &Point, b: &Point, p: &Point) -> i64 {
+    let [ax, ay] = [a[0] as i64, a[1] as i64];
+    (b[0] as i64 - ax) * (p[1] as i64 - ay) - (b[1] as i64 - ay) * (p[0] as i64 - ax)
+}
+
+/// Whether `p` is in the inclusive box `[min, max]`.
+fn in_aabb(p: &Point, min: &Point, max: &Point) -> bool {
+    min[0] <= p[0] && p[0] <= max[0] && min[1] <= p[1] && p[1] <= max[1]
+}
+
+/// The smallest integer whose square is at least `n`.
+fn ceil_sqrt(n: u64) -> u64 {
+    let mut res = (n as f64).sqrt() as u64;
+    while res * res < n {
+        res += 1;
     }
+    res
+}
+
+/// Length of the skiplist of a table holding `len` keys.
+/// Large tables get a longer skiplist to narrow the window of the binary search in
+/// `find_key_morton`. Always a multiple of 4 so the SIMD partition needs no remainder loop.
+fn skip_len(len: usize) -> usize {
+    (len / SKIP_BUCKET_LEN)
+        .next_power_of_two()
+        .clamp(MIN_SKIP_LEN, MAX_SKIP_LEN)
 }
 
 /// Find the index of the partition where `key` _might_ reside.
 /// This is the index of the second to first item in the `skiplist` that is greater than the `key`
 #[inline(always)]
-unsafe fn find_key_partition_sse2(skiplist: &[u32; SKIP_LEN], key: &MortonKey) -> usize {
+fn find_key_partition(skiplist: &[u32], key: &MortonKey) -> usize {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { find_key_partition_sse2(skiplist, key) };
+        }
+    }
+    find_key_partition_scalar(skiplist, key)
+}
+
+#[inline(always)]
+fn find_key_partition_scalar(skiplist: &[u32], key: &MortonKey) -> usize {
+    skiplist.iter().filter(|skip| key.0 > **skip).count()
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline(always)]
+unsafe fn find_key_partition_sse2(skiplist: &[u32], key: &MortonKey) -> usize {
+    debug_assert_eq!(skiplist.len() % 4, 0);
     let key = key.0 as i32;
     let keys4 = _mm_set_epi32(key, key, key, key);
 
-    let [s0, s1, s2, s3, s4, s5, s6, s7]: [i32; SKIP_LEN] = mem::transmute(*skiplist);
-    let skiplist_a: __m128i = _mm_set_epi32(s0, s1, s2, s3);
-    let skiplist_b: __m128i = _mm_set_epi32(s4, s5, s6, s7);
+    let mut index = 0;
+    for chunk in skiplist.chunks_exact(4) {
+        let skip: __m128i = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
 
-    // set every 32 bits to 0xFFFF if key < skip else sets it to 0x0000
-    let results_a: __m128i = _mm_cmpgt_epi32(keys4, skiplist_a);
-    let results_b: __m128i = _mm_cmpgt_epi32(keys4, skiplist_b);
+        // set every 32 bits to 0xFFFF if key < skip else sets it to 0x0000
+        let results: __m128i = _mm_cmpgt_epi32(keys4, skip);
 
-    // create a mask from the most significant bit of each 8bit element
-    let mask_a: i32 = _mm_movemask_epi8(results_a);
-    let mask_b: i32 = _mm_movemask_epi8(results_b);
+        // create a mask from the most significant bit of each 8bit element
+        let mask: i32 = _mm_movemask_epi8(results);
 
-    // count the number of bits set to 1
-    let index: i32 = _popcnt32(mask_a) + _popcnt32(mask_b);
+        // count the number of bits set to 1
+        index += _popcnt32(mask);
+    }
     // because the mask was created from 8 bit wide items every key in skip list is counted
     // 4 times.
     index as usize / 4
 }
-
-#[inline(never)]
-fn sse_panic() -> usize {
-    println!(
-        r#"
-AVX: {}
-SSE: {}
-                "#,
-        is_x86_feature_detected!("avx"),
-        is_x86_feature_detected!("sse"),
-    );
-    unimplemented!("find_key is not implemented for the current CPU")
-}
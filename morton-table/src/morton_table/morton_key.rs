@@ -1,9 +1,27 @@
+use super::litmax_bigmin::msb_de_bruijn;
+use crate::codec::{morton_decode_2d, morton_encode_2d};
+use crate::Point;
+
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord, Default)]
+#[repr(transparent)]
 pub struct MortonKey(pub u32);
 
+// `MortonKey` is a transparent wrapper of `u32`, so every bit pattern is valid
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for MortonKey {}
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for MortonKey {}
+
+/// View the keys as raw bytes without copying, e.g. to upload them into a GPU buffer.
+/// Each key is in the native byte order of the target.
+#[cfg(feature = "bytemuck")]
+pub fn pack_keys(keys: &[MortonKey]) -> &[u8] {
+    bytemuck::cast_slice(keys)
+}
+
 impl MortonKey {
     pub fn new(x: u16, y: u16) -> Self {
-        Self(Self::morton2(x as u32, y as u32))
+        Self(morton_encode_2d(x, y))
     }
 
     pub fn new_u32(x: u32, y: u32) -> Self {
@@ -13,48 +31,219 @@ impl MortonKey {
     }
 
     fn morton2(x: u32, y: u32) -> u32 {
-        Self::partition(x) | (Self::partition(y) << 1)
+        morton_encode_2d(x as u16, y as u16)
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 4] {
+        self.0.to_le_bytes()
     }
 
-    fn partition(mut n: u32) -> u32 {
-        // n = ----------------fedcba9876543210 : Bits initially
-        // n = --------fedcba98--------76543210 : After (1)
-        // n = ----fedc----ba98----7654----3210 : After (2)
-        // n = --fe--dc--ba--98--76--54--32--10 : After (3)
-        // n = -f-e-d-c-b-a-9-8-7-6-5-4-3-2-1-0 : After (4)
-        n = (n ^ (n << 8)) & 0x00ff00ff; // (1)
-        n = (n ^ (n << 4)) & 0x0f0f0f0f; // (2)
-        n = (n ^ (n << 2)) & 0x33333333; // (3)
-        (n ^ (n << 1)) & 0x55555555 // (4)
+    pub fn from_le_bytes(bytes: [u8; 4]) -> Self {
+        Self(u32::from_le_bytes(bytes))
+    }
+
+    /// Return the id of the quadtree cell at `depth` that this key falls into.
+    /// See `cell_at_depth`.
+    pub fn cell(&self, depth: u8) -> u32 {
+        debug_assert!(depth <= 16, "depth must be at most 16");
+        (self.0 as u64 >> (32 - 2 * depth as u32)) as u32
+    }
+
+    /// Test if this key is in the quadtree cell `cell_prefix` at `depth`, i.e. if the top
+    /// `2 * depth` bits of the key equal `cell_prefix`. Depth 0 contains every key.
+    pub fn in_cell(&self, cell_prefix: u32, depth: u8) -> bool {
+        self.cell(depth) == cell_prefix
     }
 
     /// Calculate the original point of this hash key.
     /// In practice it is more beneficial to just store the original key if you need to access it
     /// later.
     pub fn as_point(&self) -> [u32; 2] {
-        let x = Self::reconstruct(self.0);
-        let y = Self::reconstruct(self.0 >> 1);
-        [x, y]
-    }
-
-    fn reconstruct(mut n: u32) -> u32 {
-        // -f-e-d-c-b-a-9-8-7-6-5-4-3-2-1-0 : After (1)
-        // -ffeeddccbbaa9988776655443322110 : After (2)
-        // --fe--dc--ba--98--76--54--32--10 : After (3)
-        // --fefedcdcbaba989876765454323210 : After (4)
-        // ----fedc----ba98----7654----3210 : After (5)
-        // ----fedcfedcba98ba98765476543210 : After (6)
-        // --------fedcba98--------76543210 : After (7)
-        // --------fedcba98fedcba9876543210 : After (8)
-        // ----------------fedcba9876543210 : After (9)
-        n &= 0x55555555; // (1)
-        n |= n >> 1; // (2)
-        n &= 0x33333333; // (3)
-        n |= n >> 2; // (4)
-        n &= 0x0f0f0f0f; // (5)
-        n |= n >> 4; // (6)
-        n &= 0x00ff00ff; // (7)
-        n |= n >> 8; // (8)
-        n & 0x0000ffff // (9)
+        let (x, y) = morton_decode_2d(self.0);
+        [x as u32, y as u32]
+    }
+}
+
+impl Point {
+    /// Pack this point into a single Morton key, e.g. for network transfer.
+    ///
+    /// Each axis must fit into 16 bits, higher bits are lost. Note that `MortonTable` only accepts
+    /// points with 15 bits per axis.
+    pub fn to_morton_u32(&self) -> u32 {
+        MortonKey::new_u32(self[0], self[1]).0
+    }
+
+    /// Unpack a point packed by `to_morton_u32`.
+    pub fn from_morton_u32(key: u32) -> Point {
+        Point(MortonKey(key).as_point())
+    }
+}
+
+/// Return the id of the quadtree cell at `depth` that `point` falls into.
+///
+/// The id is the top `2 * depth` bits of the Morton key of `point`. Depth 0 is the whole 16 bit
+/// coordinate space, depth 16 is a single point.
+pub fn cell_at_depth(point: &Point, depth: u8) -> u32 {
+    debug_assert!(depth <= 16, "depth must be at most 16");
+    MortonKey::new_u32(point[0], point[1]).cell(depth)
+}
+
+/// Return [min, max) of the quadtree cell `cell` at `depth`.
+/// Inverse of `cell_at_depth`.
+pub fn cell_bounds(cell: u32, depth: u8) -> (Point, Point) {
+    debug_assert!(depth <= 16, "depth must be at most 16");
+    let key = ((cell as u64) << (32 - 2 * depth as u32)) as u32;
+    let [x, y] = MortonKey(key).as_point();
+    let size = 1 << (16 - depth as u32);
+    (Point::new(x, y), Point::new(x + size, y + size))
+}
+
+/// Return the Chebyshev distance of the positions of `a` and `b`, the number of king moves between
+/// them on the grid. A lower bound of their Euclidean distance.
+pub fn min_grid_distance(a: MortonKey, b: MortonKey) -> u32 {
+    let [ax, ay] = a.as_point();
+    let [bx, by] = b.as_point();
+    ax.abs_diff(bx).max(ay.abs_diff(by))
+}
+
+/// Return the smallest Chebyshev distance from `point` to any position in the quadtree cell
+/// `cell_prefix` at `depth`, see `cell_at_depth`. 0 if the cell contains `point`.
+///
+/// A lower bound of the Euclidean distance to every position in the cell, so a best-first
+/// search may skip cells farther away than the best candidate found so far.
+pub fn cell_min_distance(cell_prefix: u32, depth: u8, point: &Point) -> u32 {
+    let (min, max) = cell_bounds(cell_prefix, depth);
+    // `max` is exclusive
+    let axis = |i: usize| {
+        if point[i] < min[i] {
+            min[i] - point[i]
+        } else {
+            point[i].saturating_sub(max[i] - 1)
+        }
+    };
+    axis(0).max(axis(1))
+}
+
+/// Return the `(cell, depth)` of the smallest quadtree cell that contains both keys, see
+/// `cell_at_depth`.
+///
+/// The depth is the number of leading bit pairs `a` and `b` have in common.
+pub fn common_ancestor(a: MortonKey, b: MortonKey) -> (u32, u8) {
+    let diff = a.0 ^ b.0;
+    let depth = if diff == 0 {
+        16
+    } else {
+        // the pair of bits holding the most significant difference and every pair below it
+        // belong to the children of the common cell
+        15 - msb_de_bruijn(diff) as u8 / 2
+    };
+    (a.cell(depth), depth)
+}
+
+// the bits of the x and y axes of a Morton key
+const X_MASK: u32 = 0x5555_5555;
+const Y_MASK: u32 = 0xaaaa_aaaa;
+
+/// Return the keys of the 4-connected grid neighbours of `key`, in the order
+/// `[x - 1, x + 1, y - 1, y + 1]`. `None` where the neighbour is outside of the 16 bit coordinate
+/// space.
+///
+/// Works on the interleaved bits directly, without decoding the key.
+pub fn morton_neighbors(key: MortonKey) -> [Option<MortonKey>; 4] {
+    [
+        morton_dec(key.0, X_MASK),
+        morton_inc(key.0, X_MASK),
+        morton_dec(key.0, Y_MASK),
+        morton_inc(key.0, Y_MASK),
+    ]
+}
+
+/// Add 1 to the axis of `key` selected by `mask`.
+fn morton_inc(key: u32, mask: u32) -> Option<MortonKey> {
+    if key & mask == mask {
+        return None;
+    }
+    // setting the bits of the other axis carries the addition through them
+    let axis = ((key | !mask) + 1) & mask;
+    Some(MortonKey(axis | (key & !mask)))
+}
+
+/// Subtract 1 from the axis of `key` selected by `mask`.
+fn morton_dec(key: u32, mask: u32) -> Option<MortonKey> {
+    if key & mask == 0 {
+        return None;
+    }
+    // the borrow is carried through the cleared bits of the other axis
+    let axis = ((key & mask) - 1) & mask;
+    Some(MortonKey(axis | (key & !mask)))
+}
+
+/// Decompose the rectangle `[min, max]` (inclusive) into the minimal set of contiguous Morton
+/// intervals that cover it exactly.
+///
+/// The returned intervals are inclusive, sorted in Z-order and non-overlapping. Adjacent
+/// intervals are merged.
+pub fn morton_ranges(min: [u16; 2], max: [u16; 2]) -> Vec<(MortonKey, MortonKey)> {
+    try_morton_ranges(min, max, usize::MAX).expect("the number of ranges to be unbounded")
+}
+
+/// Like `morton_ranges`, but gives up and returns `None` if the rectangle needs more than
+/// `max_ranges` intervals. The number of intervals grows with the perimeter of the rectangle.
+pub fn try_morton_ranges(
+    min: [u16; 2],
+    max: [u16; 2],
+    max_ranges: usize,
+) -> Option<Vec<(MortonKey, MortonKey)>> {
+    let mut out = Vec::new();
+    if min[0] > max[0] || min[1] > max[1] {
+        return Some(out);
+    }
+    let min = [min[0] as u32, min[1] as u32];
+    let max = [max[0] as u32, max[1] as u32];
+    // the smallest quadtree cell anchored at the origin that contains the rectangle
+    let level = 32 - (max[0] | max[1]).leading_zeros();
+    if morton_ranges_impl([0, 0], level, min, max, max_ranges, &mut out) {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Visit the cell at `origin` with a side length of `2^level` in Z-order.
+/// Returns `false` if `out` would need more than `max_ranges` intervals.
+fn morton_ranges_impl(
+    origin: [u32; 2],
+    level: u32,
+    min: [u32; 2],
+    max: [u32; 2],
+    max_ranges: usize,
+    out: &mut Vec<(MortonKey, MortonKey)>,
+) -> bool {
+    let size = 1u32 << level;
+    let [x, y] = origin;
+    let [x2, y2] = [x + size - 1, y + size - 1];
+    if x2 < min[0] || max[0] < x || y2 < min[1] || max[1] < y {
+        // disjoint
+        return true;
+    }
+    if min[0] <= x && min[1] <= y && x2 <= max[0] && y2 <= max[1] {
+        // the cell is fully contained, every key in it is part of the rectangle
+        let begin = MortonKey::morton2(x, y);
+        let end = (begin as u64 + (1u64 << (2 * level)) - 1) as u32;
+        let len = out.len();
+        match out.last_mut() {
+            Some((_, last)) if last.0 as u64 + 1 == begin as u64 => last.0 = end,
+            _ if len == max_ranges => return false,
+            _ => out.push((MortonKey(begin), MortonKey(end))),
+        }
+        return true;
     }
+    // partial overlap; level is at least 1 here as a 1x1 cell can't partially overlap
+    let half = size / 2;
+    let level = level - 1;
+    // children in Z-order: x is the least significant axis
+    morton_ranges_impl([x, y], level, min, max, max_ranges, out)
+        && morton_ranges_impl([x + half, y], level, min, max, max_ranges, out)
+        && morton_ranges_impl([x, y + half], level, min, max, max_ranges, out)
+        && morton_ranges_impl([x + half, y + half], level, min, max, max_ranges, out)
 }
@@ -0,0 +1,124 @@
+use super::morton_key::MortonKey;
+use super::MortonTable;
+use crate::{Point, Value};
+use std::ops::Range;
+
+/// Outcome of visiting a Z-order interval of a range query.
+pub(super) enum RangeStep {
+    /// Scan these indices of the table.
+    Scan(Range<usize>),
+    /// Visit `[min, litmax]` and `[bigmin, max]` instead of the interval.
+    Split([MortonKey; 2]),
+}
+
+/// Iterator over the items within a circle, see `MortonTable::iter_in_range`.
+///
+/// Flattens the litmax/bigmin recursion of `find_in_range` into an explicit stack of intervals.
+pub struct RangeIter<'a> {
+    table: &'a MortonTable,
+    center: Point,
+    radius_sq: u64,
+    // intervals left to visit, the next one on top
+    stack: Vec<(MortonKey, MortonKey)>,
+    // indices left to scan in the current interval
+    scan: Range<usize>,
+    // the staged items of the table, scanned after the intervals
+    staged: std::slice::Iter<'a, (MortonKey, Point, Value)>,
+}
+
+impl<'a> RangeIter<'a> {
+    pub(super) fn new(table: &'a MortonTable, center: &Point, radius: u32) -> Self {
+        let (min, max) = MortonTable::range_keys(center, radius);
+        Self {
+            table,
+            center: *center,
+            radius_sq: radius as u64 * radius as u64,
+            stack: vec![(min, max)],
+            scan: 0..0,
+            staged: table.staged.iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for RangeIter<'a> {
+    type Item = (Point, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for i in &mut self.scan {
+                let id = self.table.positions[i];
+                if self.center.dist_sq(&id) <= self.radius_sq {
+                    return Some((id, &self.table.values[i]));
+                }
+            }
+            let (min, max) = match self.stack.pop() {
+                Some(interval) => interval,
+                None => {
+                    let (center, radius_sq) = (self.center, self.radius_sq);
+                    return self
+                        .staged
+                        .find(|(_, id, _)| center.dist_sq(id) <= radius_sq)
+                        .map(|(_, id, value)| (*id, value));
+                }
+            };
+            match self.table.range_step(min, max) {
+                RangeStep::Split([litmax, bigmin]) => {
+                    // visit the lower half first to yield the items in Morton order
+                    self.stack.push((bigmin, max));
+                    self.stack.push((min, litmax));
+                }
+                RangeStep::Scan(range) => self.scan = range,
+            }
+        }
+    }
+}
+
+/// Iterator over the index ranges of `MortonTable::aabb_index_ranges`.
+pub(super) struct AabbRanges<'a> {
+    table: &'a MortonTable,
+    // Morton intervals left to visit, the next one on top
+    stack: Vec<(MortonKey, MortonKey)>,
+    // whether the intervals cover the box exactly, otherwise they are split by `range_step`
+    exact: bool,
+}
+
+impl<'a> AabbRanges<'a> {
+    /// Visit the exact decomposition of `morton_ranges`.
+    pub(super) fn exact(table: &'a MortonTable, mut ranges: Vec<(MortonKey, MortonKey)>) -> Self {
+        ranges.reverse();
+        Self {
+            table,
+            stack: ranges,
+            exact: true,
+        }
+    }
+
+    /// Split the interval of the box with the corners `min` and `max` like `find_in_range`.
+    pub(super) fn split(table: &'a MortonTable, min: MortonKey, max: MortonKey) -> Self {
+        Self {
+            table,
+            stack: vec![(min, max)],
+            exact: false,
+        }
+    }
+}
+
+impl<'a> Iterator for AabbRanges<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (min, max) = self.stack.pop()?;
+            if self.exact {
+                return Some(self.table.key_index_range(&min, &max));
+            }
+            match self.table.range_step(min, max) {
+                RangeStep::Split([litmax, bigmin]) => {
+                    self.stack.push((bigmin, max));
+                    self.stack.push((min, litmax));
+                }
+                RangeStep::Scan(range) => return Some(range),
+            }
+        }
+    }
+}
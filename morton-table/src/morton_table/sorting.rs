@@ -1,4 +1,20 @@
 use super::morton_key::MortonKey;
+use crate::Point;
+use rayon::prelude::*;
+
+/// Number of items above which `radix_sort` outperforms `sort_indirect`. In the `sort_keys`
+/// benchmark the radix sort is up to 1.5x slower at 2048 keys and 1.2x to 1.6x faster at 4096
+/// keys.
+pub const RADIX_SORT_THRESHOLD: usize = 4096;
+
+// partitions of at least this many items choose their pivot by the ninther
+const NINTHER_THRESHOLD: usize = 128;
+
+const RADIX_BITS: u32 = 8;
+const RADIX: usize = 1 << RADIX_BITS;
+const RADIX_PASSES: usize = 32 / RADIX_BITS as usize;
+// number of keys a single task of `radix_sort` counts and scatters
+const RADIX_CHUNK_LEN: usize = 1 << 12;
 
 /// Parallel Quicksort implementation to sort the 3 slices representing the Quadtree.
 pub fn sort<Point: Send, Value: Send>(
@@ -31,6 +47,36 @@ pub fn sort<Point: Send, Value: Send>(
     );
 }
 
+/// Sequential version of `sort`, for targets where the rayon thread pool is not worth it.
+///
+/// Recurses into the smaller partition only, so the depth of the stack is `O(log n)`.
+pub fn sort_sequential<Point, Value>(
+    mut keys: &mut [MortonKey],
+    mut positions: &mut [Point],
+    mut values: &mut [Value],
+) {
+    debug_assert_eq!(keys.len(), positions.len());
+    debug_assert_eq!(keys.len(), values.len());
+    while keys.len() >= 2 {
+        let pivot = sort_partition(keys, positions, values);
+        let (klo, khi) = std::mem::take(&mut keys).split_at_mut(pivot);
+        let (plo, phi) = std::mem::take(&mut positions).split_at_mut(pivot);
+        let (vlo, vhi) = std::mem::take(&mut values).split_at_mut(pivot);
+        let (khi, phi, vhi) = (&mut khi[1..], &mut phi[1..], &mut vhi[1..]);
+        if klo.len() < khi.len() {
+            sort_sequential(klo, plo, vlo);
+            keys = khi;
+            positions = phi;
+            values = vhi;
+        } else {
+            sort_sequential(khi, phi, vhi);
+            keys = klo;
+            positions = plo;
+            values = vlo;
+        }
+    }
+}
+
 /// Assumes that all 3 slices are equal in size.
 /// Assumes that the slices are not empty
 fn sort_partition<Pos, Row>(
@@ -46,30 +92,28 @@ fn sort_partition<Pos, Row>(
             positions.swap($i, $j);
             values.swap($i, $j);
         };
-    };
+    }
 
     let len = keys.len();
     let lim = len - 1;
 
-    let (pivot, pivot_ind) = {
-        use std::mem::swap;
+    let pivot_ind = if len >= NINTHER_THRESHOLD {
+        // Tukey's ninther: the median of the medians of three evenly spaced triplets.
+        // Much less likely to pick a bad pivot than the plain median of 3 on structured inputs,
+        // e.g. rebuilding a mostly sorted table.
+        let step = len / 8;
+        let mid = len / 2;
+        median_of_3(
+            keys,
+            median_of_3(keys, 0, step, 2 * step),
+            median_of_3(keys, mid - step, mid, mid + step),
+            median_of_3(keys, lim - 2 * step, lim - step, lim),
+        )
+    } else {
         // choose the median of the first, middle and last elements as the pivot
-
-        let mut first = 0;
-        let mut last = lim;
-        let mut median = len / 2;
-
-        if keys[last] < keys[median] {
-            swap(&mut median, &mut last);
-        }
-        if keys[last] < keys[first] {
-            swap(&mut last, &mut first);
-        }
-        if keys[median] < keys[first] {
-            swap(&mut median, &mut first);
-        }
-        (keys[median], median)
+        median_of_3(keys, 0, len / 2, lim)
     };
+    let pivot = keys[pivot_ind];
 
     swap!(pivot_ind, lim);
 
@@ -83,3 +127,202 @@ fn sort_partition<Pos, Row>(
     swap!(i, lim);
     i
 }
+
+/// Return the index of the median of the keys at `a`, `b` and `c`.
+fn median_of_3(keys: &[MortonKey], a: usize, b: usize, c: usize) -> usize {
+    use std::mem::swap;
+
+    let (mut first, mut median, mut last) = (a, b, c);
+    if keys[last] < keys[median] {
+        swap(&mut median, &mut last);
+    }
+    if keys[last] < keys[first] {
+        swap(&mut last, &mut first);
+    }
+    if keys[median] < keys[first] {
+        swap(&mut median, &mut first);
+    }
+    median
+}
+
+/// Parallel LSD radix sort of the 3 slices representing the Quadtree, 8 bits per pass.
+///
+/// Every pass counts the digits of each chunk of `RADIX_CHUNK_LEN` items in parallel, then the
+/// chunks scatter their items to disjoint parts of the output in parallel. The passes only move
+/// `(key, index)` pairs, the final permutation is applied to `positions` and `values` in a single
+/// gather.
+pub fn radix_sort<Point: Copy + Send + Sync, Value: Copy + Send + Sync>(
+    keys: &mut [MortonKey],
+    positions: &mut [Point],
+    values: &mut [Value],
+) {
+    debug_assert_eq!(keys.len(), positions.len());
+    debug_assert_eq!(keys.len(), values.len());
+    let len = keys.len();
+    if len < 2 {
+        return;
+    }
+    assert!(len <= u32::MAX as usize, "too many keys to sort");
+
+    let mut items = keys
+        .par_iter()
+        .enumerate()
+        .map(|(i, key)| (key.0, i as u32))
+        .collect::<Vec<_>>();
+    let mut buffer = vec![(0u32, 0u32); len];
+    for pass in 0..RADIX_PASSES {
+        let counts = items
+            .par_chunks(RADIX_CHUNK_LEN)
+            .map(|chunk| {
+                let mut counts = [0usize; RADIX];
+                for item in chunk {
+                    counts[digit(item.0, pass)] += 1;
+                }
+                counts
+            })
+            .collect::<Vec<_>>();
+        if (0..RADIX).any(|d| counts.iter().map(|c| c[d]).sum::<usize>() == len) {
+            // every key has the same digit, this pass would not move anything
+            continue;
+        }
+        // the items of a chunk with digit `d` go after the items with smaller digits and after
+        // the items of the earlier chunks with digit `d`, so the sort is stable
+        let mut offsets = vec![[0usize; RADIX]; counts.len()];
+        let mut sum = 0;
+        for d in 0..RADIX {
+            for (offsets, counts) in offsets.iter_mut().zip(counts.iter()) {
+                offsets[d] = sum;
+                sum += counts[d];
+            }
+        }
+        let out = ScatterPtr(buffer.as_mut_ptr());
+        items
+            .par_chunks(RADIX_CHUNK_LEN)
+            .zip(offsets.par_iter_mut())
+            .for_each(|(chunk, offsets)| {
+                for item in chunk {
+                    let d = digit(item.0, pass);
+                    // the offsets of the chunks are disjoint and within `buffer`
+                    unsafe { out.write(offsets[d], *item) };
+                    offsets[d] += 1;
+                }
+            });
+        std::mem::swap(&mut items, &mut buffer);
+    }
+
+    let sorted_positions = items
+        .par_iter()
+        .map(|(_, i)| positions[*i as usize])
+        .collect::<Vec<_>>();
+    let sorted_values = items
+        .par_iter()
+        .map(|(_, i)| values[*i as usize])
+        .collect::<Vec<_>>();
+    keys.par_iter_mut()
+        .zip(items.par_iter())
+        .for_each(|(key, (k, _))| key.0 = *k);
+    positions.copy_from_slice(&sorted_positions);
+    values.copy_from_slice(&sorted_values);
+}
+
+/// Sort the 3 slices representing the Quadtree by sorting a permutation of the keys, then applying
+/// it to the slices by following its cycles.
+///
+/// `sort` swaps the items `O(log n)` times each while partitioning, this swaps them at most once
+/// per item. Prefer it if `Value` is large, see the `sort_large_values` benchmark. Unlike
+/// `radix_sort` it needs no `Copy` bound and no buffer for the values.
+///
+/// `MortonTable` sorts tables of less than `RADIX_SORT_THRESHOLD` items with it, it is about 2x
+/// faster than `sort` on them in the `sort_keys` benchmark.
+pub fn sort_indirect<Point: Send, Value: Send>(
+    keys: &mut [MortonKey],
+    positions: &mut [Point],
+    values: &mut [Value],
+) {
+    debug_assert_eq!(keys.len(), positions.len());
+    debug_assert_eq!(keys.len(), values.len());
+    let len = keys.len();
+    if len < 2 {
+        return;
+    }
+    assert!(len <= u32::MAX as usize, "too many keys to sort");
+
+    let mut perm = (0..len as u32).collect::<Vec<_>>();
+    perm.par_sort_unstable_by_key(|i| keys[*i as usize]);
+    apply_permutation(&mut perm, |i, j| {
+        keys.swap(i, j);
+        positions.swap(i, j);
+        values.swap(i, j);
+    });
+}
+
+/// Sort `items` in place into the Morton order of the positions returned by `key`, e.g. to iterate
+/// over game entities in a cache friendly order without building a `MortonTable`.
+///
+/// The sort is not stable. Like `sort_indirect` it sorts a permutation in parallel, then swaps
+/// each item at most once. `key` is called once per item.
+///
+/// Panics if an axis of a position does not fit into 16 bits.
+pub fn morton_sort_by_key<T, F>(items: &mut [T], key: F)
+where
+    F: Fn(&T) -> Point,
+{
+    let len = items.len();
+    if len < 2 {
+        return;
+    }
+    assert!(len <= u32::MAX as usize, "too many items to sort");
+
+    let keys = items
+        .iter()
+        .map(|item| {
+            let p = key(item);
+            assert!(
+                p[0] <= 0xffff && p[1] <= 0xffff,
+                "{:?} does not fit into 16 bits",
+                p
+            );
+            MortonKey::new_u32(p[0], p[1])
+        })
+        .collect::<Vec<_>>();
+    let mut perm = (0..len as u32).collect::<Vec<_>>();
+    perm.par_sort_unstable_by_key(|i| keys[*i as usize]);
+    apply_permutation(&mut perm, |i, j| items.swap(i, j));
+}
+
+/// Move the item at `perm[i]` to `i` for every `i` by calling `swap` at most once per item.
+/// Leaves `perm` as the identity.
+fn apply_permutation<F>(perm: &mut [u32], mut swap: F)
+where
+    F: FnMut(usize, usize),
+{
+    for start in 0..perm.len() {
+        // the slot `i` holds the item that belongs to `start` until the cycle is closed
+        let mut i = start;
+        while perm[i] as usize != start {
+            let next = perm[i] as usize;
+            swap(i, next);
+            perm[i] = i as u32;
+            i = next;
+        }
+        perm[i] = i as u32;
+    }
+}
+
+#[inline(always)]
+fn digit(key: u32, pass: usize) -> usize {
+    ((key >> (pass as u32 * RADIX_BITS)) as usize) & (RADIX - 1)
+}
+
+/// Output of the parallel scatter of `radix_sort`, the tasks write to disjoint indices.
+struct ScatterPtr<T>(*mut T);
+
+unsafe impl<T: Send> Send for ScatterPtr<T> {}
+unsafe impl<T: Send> Sync for ScatterPtr<T> {}
+
+impl<T> ScatterPtr<T> {
+    /// Safety: `i` must be in bounds and no other task may access it concurrently.
+    unsafe fn write(&self, i: usize, value: T) {
+        self.0.add(i).write(value);
+    }
+}
@@ -99,7 +99,7 @@ fn get_by_id() {
     }
 
     for (p, e) in points.iter() {
-        table.insert(p.clone(), *e).unwrap();
+        table.insert(*p, *e).unwrap();
     }
 
     println!("{:?}\n{:?}", table.skiplist, table.keys);
@@ -143,7 +143,7 @@ fn from_iterator_inserts_correctly() {
         }
         let val = rng.next_u32();
         let val = Value(val);
-        points.insert(pos.clone(), val);
+        points.insert(pos, val);
         Some((pos, val))
     }));
     for (pos, val) in points {
@@ -173,3 +173,3007 @@ fn test_litmax_bigmin_x() {
     assert_eq!(litmax, MortonKey(63));
     assert_eq!(bigmin, MortonKey(98));
 }
+
+#[test]
+fn rebuild_restores_order_after_manual_mutation() {
+    let mut rng = rand::thread_rng();
+
+    let mut table = MortonTable::new();
+    let mut points = HashMap::with_capacity(128);
+    while points.len() < 128 {
+        let p = Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256));
+        points.insert(p, Value(rng.next_u32()));
+    }
+
+    // push directly into the fields, leaving the table unsorted
+    for (p, v) in points.iter() {
+        table.keys.push(MortonKey::new(p[0] as u16, p[1] as u16));
+        table.positions.push(*p);
+        table.values.push(*v);
+    }
+
+    table.rebuild();
+
+    for (p, v) in points {
+        assert_eq!(table.get_by_id(&p), Some(&v));
+    }
+}
+
+#[test]
+fn morton_ranges_single_cell() {
+    let ranges = morton_ranges([5, 7], [5, 7]);
+    let key = MortonKey::new(5, 7);
+    assert_eq!(ranges, vec![(key, key)]);
+}
+
+#[test]
+fn morton_ranges_3x3() {
+    let ranges = morton_ranges([0, 0], [2, 2]);
+    assert_eq!(
+        ranges,
+        vec![
+            (MortonKey(0), MortonKey(4)),
+            (MortonKey(6), MortonKey(6)),
+            (MortonKey(8), MortonKey(9)),
+            (MortonKey(12), MortonKey(12)),
+        ]
+    );
+}
+
+#[test]
+fn morton_ranges_full_quadrant() {
+    assert_eq!(
+        morton_ranges([0, 0], [7, 7]),
+        vec![(MortonKey(0), MortonKey(63))]
+    );
+    assert_eq!(
+        morton_ranges([8, 0], [15, 7]),
+        vec![(MortonKey(64), MortonKey(127))]
+    );
+}
+
+#[test]
+fn morton_ranges_cover_rect_exactly() {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..64 {
+        let min = [rng.gen_range(0, 64), rng.gen_range(0, 64)];
+        let max = [min[0] + rng.gen_range(0, 64), min[1] + rng.gen_range(0, 64)];
+        let ranges = morton_ranges(min, max);
+
+        let mut count = 0;
+        for (lo, hi) in ranges.iter() {
+            assert!(lo <= hi);
+            for k in lo.0..=hi.0 {
+                let [x, y] = MortonKey(k).as_point();
+                assert!(min[0] as u32 <= x && x <= max[0] as u32);
+                assert!(min[1] as u32 <= y && y <= max[1] as u32);
+                count += 1;
+            }
+        }
+        let expected = (max[0] - min[0] + 1) as usize * (max[1] - min[1] + 1) as usize;
+        assert_eq!(count, expected);
+        for w in ranges.windows(2) {
+            assert!(w[0].1 .0 + 1 < w[1].0 .0, "ranges should be merged");
+        }
+    }
+}
+
+#[test]
+fn find_in_aabb() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashSet::with_capacity(512);
+    while points.len() < 512 {
+        points.insert(Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256)));
+    }
+    let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(0))));
+
+    let min = Point::new(30, 70);
+    let max = Point::new(99, 130);
+    let mut res = Vec::new();
+    table.find_in_aabb(&min, &max, &mut res);
+
+    let res = res.into_iter().map(|(p, _)| p).collect::<HashSet<_>>();
+    let expected = points
+        .into_iter()
+        .filter(|p| min[0] <= p[0] && p[0] <= max[0] && min[1] <= p[1] && p[1] <= max[1])
+        .collect::<HashSet<_>>();
+    assert_eq!(res, expected);
+}
+
+#[test]
+fn try_morton_ranges_gives_up_above_the_limit() {
+    let ranges = morton_ranges([0, 0], [2, 2]);
+    assert_eq!(try_morton_ranges([0, 0], [2, 2], 4), Some(ranges));
+    assert_eq!(try_morton_ranges([0, 0], [2, 2], 3), None);
+    assert_eq!(
+        try_morton_ranges([0, 0], [7, 7], 1).map(|r| r.len()),
+        Some(1)
+    );
+}
+
+#[test]
+fn find_in_aabb_of_any_size() {
+    let mut rng = rand::thread_rng();
+
+    let points = (0..4096)
+        .map(|_| Point::new(rng.gen_range(0, 2048), rng.gen_range(0, 2048)))
+        .collect::<Vec<_>>();
+    let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(0))));
+
+    for _ in 0..64 {
+        let size = 1 << rng.gen_range(0, 11);
+        let min = Point::new(rng.gen_range(0, 2048), rng.gen_range(0, 2048));
+        let max = Point::new(
+            min[0] + rng.gen_range(0, size),
+            min[1] + rng.gen_range(0, size),
+        );
+        let inside =
+            |p: &Point| min[0] <= p[0] && p[0] <= max[0] && min[1] <= p[1] && p[1] <= max[1];
+
+        let mut res = Vec::new();
+        table.find_in_aabb(&min, &max, &mut res);
+        let mut found = res.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+        found.sort();
+        let mut expected = points.iter().cloned().filter(inside).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let region = table.extract_region(&min, &max);
+        assert_eq!(region.keys.len(), expected.len());
+        assert_eq!(table.contains_any_in_aabb(&min, &max), !expected.is_empty());
+    }
+}
+
+#[test]
+fn par_for_each_in_range_visits_every_query() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..512).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256)),
+            Value(i),
+        )
+    }));
+    let centers = (0..64)
+        .map(|_| Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256)))
+        .collect::<Vec<_>>();
+    let radius = 32;
+
+    let mut expected = 0;
+    let mut res = Vec::new();
+    for center in centers.iter() {
+        table.find_in_range(center, radius, &mut res);
+        expected += res.len();
+        res.clear();
+    }
+
+    let count = AtomicUsize::new(0);
+    table.par_for_each_in_range(&centers, radius, |_, _| {
+        count.fetch_add(1, Ordering::Relaxed);
+    });
+
+    assert_eq!(count.into_inner(), expected);
+}
+
+#[test]
+fn estimate_count_in_range_is_an_upper_bound() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..1024).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512)),
+            Value(i),
+        )
+    }));
+
+    let mut res = Vec::new();
+    for _ in 0..256 {
+        let center = Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512));
+        let radius = rng.gen_range(0, 64);
+
+        table.find_in_range(&center, radius, &mut res);
+        assert!(table.estimate_count_in_range(&center, radius) >= res.len());
+        res.clear();
+    }
+
+    assert_eq!(
+        table.estimate_count_in_range(&Point::new(256, 256), 256),
+        1024
+    );
+}
+
+#[test]
+fn cell_at_depth_round_trip() {
+    let mut rng = rand::thread_rng();
+
+    for depth in 0..=16 {
+        for _ in 0..64 {
+            let p = Point::new(rng.gen_range(0, 1 << 16), rng.gen_range(0, 1 << 16));
+            let cell = cell_at_depth(&p, depth);
+            let (min, max) = cell_bounds(cell, depth);
+
+            assert!(
+                min[0] <= p[0] && p[0] < max[0],
+                "{:?} {:?} {:?}",
+                min,
+                p,
+                max
+            );
+            assert!(
+                min[1] <= p[1] && p[1] < max[1],
+                "{:?} {:?} {:?}",
+                min,
+                p,
+                max
+            );
+            assert_eq!(max[0] - min[0], 1 << (16 - depth));
+            assert_eq!(cell_at_depth(&min, depth), cell);
+        }
+    }
+}
+
+#[test]
+fn cell_at_depth_quadrants() {
+    assert_eq!(cell_at_depth(&Point::new(1234, 4321), 0), 0);
+    assert_eq!(cell_at_depth(&Point::new(0, 0), 1), 0);
+    assert_eq!(cell_at_depth(&Point::new(0x8000, 0), 1), 1);
+    assert_eq!(cell_at_depth(&Point::new(0, 0x8000), 1), 2);
+    assert_eq!(cell_at_depth(&Point::new(0x8000, 0x8000), 1), 3);
+    assert_eq!(
+        cell_bounds(3, 1),
+        (Point::new(0x8000, 0x8000), Point::new(0x10000, 0x10000))
+    );
+}
+
+#[test]
+fn points_and_values_iterate_in_morton_order() {
+    let table = MortonTable::from_iterator(
+        [
+            (Point::new(3, 3), Value(3)),
+            (Point::new(0, 1), Value(2)),
+            (Point::new(1, 0), Value(1)),
+        ]
+        .iter()
+        .cloned(),
+    );
+
+    let points = table.points().cloned().collect::<Vec<_>>();
+    let values = table.values_iter().cloned().collect::<Vec<_>>();
+
+    assert_eq!(
+        points,
+        vec![Point::new(1, 0), Point::new(0, 1), Point::new(3, 3)]
+    );
+    assert_eq!(values, vec![Value(1), Value(2), Value(3)]);
+}
+
+#[test]
+fn find_in_range_limited_stops_at_limit() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..1024).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256)),
+            Value(i),
+        )
+    }));
+
+    let center = Point::new(128, 128);
+    let mut all = Vec::new();
+    table.find_in_range(&center, 64, &mut all);
+    assert!(all.len() > 4, "the test input is faulty");
+
+    let mut res = Vec::new();
+    table.find_in_range_limited(&center, 64, 4, &mut res);
+    assert_eq!(res.len(), 4);
+    for (p, _) in res.iter() {
+        assert!(center.dist_sq(p) <= 64 * 64);
+    }
+
+    res.clear();
+    table.find_in_range_limited(&center, 64, all.len() + 10, &mut res);
+    assert_eq!(res.len(), all.len());
+}
+
+#[test]
+fn point_morton_u32_round_trip() {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..(1 << 12) {
+        let p = Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15));
+        let packed = p.to_morton_u32();
+        assert_eq!(Point::from_morton_u32(packed), p);
+    }
+}
+
+#[test]
+fn extend_from_slice_inserts_correctly() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashMap::with_capacity(512);
+    while points.len() < 512 {
+        let p = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        points.insert(p, Value(rng.next_u32()));
+    }
+    let items = points.iter().map(|(p, v)| (*p, *v)).collect::<Vec<_>>();
+
+    let mut table = MortonTable::new();
+    table.extend_from_slice(&items[..256]);
+    table.extend_from_slice(&items[256..]);
+
+    assert_eq!(table.keys.len(), 512);
+    for (p, v) in points {
+        assert_eq!(table.get_by_id(&p), Some(&v));
+    }
+}
+
+#[test]
+fn insert_deferred_is_queryable_before_flush() {
+    let mut rng = rand::thread_rng();
+
+    let mut table = MortonTable::new();
+    let mut points = HashMap::with_capacity(256);
+    while points.len() < 256 {
+        let p = Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256));
+        if points.contains_key(&p) {
+            continue;
+        }
+        let v = Value(rng.next_u32());
+        table.insert_deferred(p, v).unwrap();
+        points.insert(p, v);
+    }
+
+    for (p, v) in points.iter() {
+        assert_eq!(table.get_by_id(p), Some(v));
+    }
+
+    table.flush();
+
+    for (p, v) in points.iter() {
+        assert_eq!(table.get_by_id(p), Some(v));
+    }
+}
+
+#[test]
+fn find_key_morton_past_the_last_skip() {
+    // 15 items result in a step of 1, leaving 7 items past the last item of the skiplist
+    let table = MortonTable::from_iterator((0..15).map(|i| (Point::new(i, i), Value(i))));
+
+    for i in 0..15 {
+        let key = MortonKey::new(i as u16, i as u16);
+        assert_eq!(table.find_key_morton(&key), Ok(i));
+    }
+}
+
+#[test]
+fn split_threshold_does_not_change_results() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashSet::with_capacity(2048);
+    while points.len() < 2048 {
+        points.insert(Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512)));
+    }
+    let items = points
+        .into_iter()
+        .map(|p| (p, Value(p[0] ^ p[1])))
+        .collect::<Vec<_>>();
+    let table = MortonTable::from_iterator(items.iter().cloned());
+
+    for threshold in [0, 1, 8, 64, 4096].iter() {
+        let tuned =
+            MortonTable::from_iterator(items.iter().cloned()).with_split_threshold(*threshold);
+
+        for _ in 0..64 {
+            let center = Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512));
+            let radius = rng.gen_range(0, 128);
+
+            let mut expected = Vec::new();
+            table.find_in_range(&center, radius, &mut expected);
+            let mut res = Vec::new();
+            tuned.find_in_range(&center, radius, &mut res);
+
+            expected.sort_by_key(|(p, v)| (p[0], p[1], v.0));
+            res.sort_by_key(|(p, v)| (p[0], p[1], v.0));
+            assert_eq!(res, expected);
+        }
+    }
+}
+
+#[test]
+fn extent() {
+    let mut table = MortonTable::new();
+    assert_eq!(table.extent(), None);
+
+    table.insert(Point::new(12, 3), Value(0)).unwrap();
+    assert_eq!(table.extent(), Some((Point::new(12, 3), Point::new(12, 3))));
+
+    table.extend(
+        [(Point::new(40, 8), Value(1)), (Point::new(5, 90), Value(2))]
+            .iter()
+            .cloned(),
+    );
+    assert_eq!(table.extent(), Some((Point::new(5, 3), Point::new(40, 90))));
+}
+
+#[test]
+fn delete() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashMap::with_capacity(256);
+    while points.len() < 256 {
+        let p = Point::new(rng.gen_range(0, 256), rng.gen_range(0, 256));
+        points.insert(p, Value(rng.next_u32()));
+    }
+    let mut table = MortonTable::from_iterator(points.iter().map(|(p, v)| (*p, *v)));
+
+    let mut points = points.into_iter().collect::<Vec<_>>();
+    while let Some((p, v)) = points.pop() {
+        assert_eq!(table.delete(&p), Some(v));
+        assert_eq!(table.delete(&p), None);
+        for (p, v) in points.iter() {
+            assert_eq!(table.get_by_id(p), Some(v));
+        }
+    }
+    assert!(table.keys.is_empty());
+    assert_eq!(table.delete(&Point::new(1 << 16, 0)), None);
+}
+
+#[test]
+fn without_skiplist_finds_the_same_items() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashMap::with_capacity(1024);
+    while points.len() < 1024 {
+        let p = Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512));
+        points.insert(p, Value(rng.next_u32()));
+    }
+    let mut table = MortonTable::without_skiplist();
+    table.extend(points.iter().map(|(p, v)| (*p, *v)));
+
+    assert_eq!(table.skipstep, 0);
+    for (p, v) in points.iter() {
+        assert_eq!(table.get_by_id(p), Some(v));
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[test]
+fn scalar_partition_matches_sse2() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..1024).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512)),
+            Value(i),
+        )
+    }));
+    for _ in 0..1024 {
+        let key = MortonKey::new(rng.gen_range(0, 600), rng.gen_range(0, 600));
+        let scalar = find_key_partition_scalar(table.skiplist(), &key);
+        if is_x86_feature_detected!("sse2") {
+            let sse2 = unsafe { find_key_partition_sse2(table.skiplist(), &key) };
+            assert_eq!(scalar, sse2);
+        }
+    }
+}
+
+#[test]
+fn get_entry_returns_the_stored_position() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashMap::with_capacity(128);
+    while points.len() < 128 {
+        let p = Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15));
+        points.insert(p, Value(rng.next_u32()));
+    }
+    let table = MortonTable::from_iterator(points.iter().map(|(p, v)| (*p, *v)));
+
+    for (p, v) in points.iter() {
+        assert_eq!(table.get_entry(p), Some((p, v)));
+    }
+    assert_eq!(table.get_entry(&Point::new(1 << 15, 0)), None);
+}
+
+#[test]
+fn extend_sized_reserves_capacity() {
+    let items = (0..100)
+        .map(|i| (Point::new(i, 100 - i), Value(i)))
+        .collect::<Vec<_>>();
+
+    let mut table = MortonTable::new();
+    table.extend_sized(items.iter().cloned());
+
+    assert!(table.keys.capacity() >= 100);
+    assert!(table.positions.capacity() >= 100);
+    assert!(table.values.capacity() >= 100);
+    for (p, v) in items.iter() {
+        assert_eq!(table.get_by_id(p), Some(v));
+    }
+}
+
+#[test]
+fn take_dirty_logs_changes() {
+    let mut table = MortonTable::new().with_change_tracking();
+
+    table.insert(Point::new(1, 2), Value(0)).unwrap();
+    table.insert(Point::new(3, 4), Value(1)).unwrap();
+    assert_eq!(table.take_dirty(), vec![Point::new(1, 2), Point::new(3, 4)]);
+    assert!(table.take_dirty().is_empty());
+
+    table.delete(&Point::new(1, 2));
+    table.delete(&Point::new(7, 7));
+    assert_eq!(table.take_dirty(), vec![Point::new(1, 2)]);
+
+    table.extend([(Point::new(5, 6), Value(2))].iter().cloned());
+    assert_eq!(table.take_dirty(), vec![Point::new(5, 6)]);
+
+    table.clear();
+    let mut cleared = table.take_dirty();
+    cleared.sort_by_key(|p| p.0);
+    assert_eq!(cleared, vec![Point::new(3, 4), Point::new(5, 6)]);
+}
+
+#[test]
+fn take_dirty_is_empty_without_tracking() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(1, 2), Value(0)).unwrap();
+    assert!(table.take_dirty().is_empty());
+}
+
+#[test]
+fn morton_key_order_is_z_order() {
+    // build the Z-curve of a 16x16 grid recursively: visit the quadrants in the order
+    // (0, 0), (1, 0), (0, 1), (1, 1)
+    fn z_curve(x: u16, y: u16, size: u16, out: &mut Vec<[u32; 2]>) {
+        if size == 1 {
+            out.push([x as u32, y as u32]);
+            return;
+        }
+        let half = size / 2;
+        z_curve(x, y, half, out);
+        z_curve(x + half, y, half, out);
+        z_curve(x, y + half, half, out);
+        z_curve(x + half, y + half, half, out);
+    }
+
+    let mut expected = Vec::with_capacity(256);
+    z_curve(0, 0, 16, &mut expected);
+
+    let mut keys = (0..16)
+        .flat_map(|x| (0..16).map(move |y| MortonKey::new(x, y)))
+        .collect::<Vec<_>>();
+    keys.sort();
+
+    let sorted = keys.iter().map(|k| k.as_point()).collect::<Vec<_>>();
+    assert_eq!(sorted, expected);
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(k.0, i as u32, "keys of a full grid should be contiguous");
+    }
+}
+
+#[test]
+fn nearest_into_matches_brute_force() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashSet::with_capacity(512);
+    while points.len() < 512 {
+        points.insert(Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024)));
+    }
+    let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(p[0] ^ p[1]))));
+
+    for _ in 0..256 {
+        let center = Point::new(rng.gen_range(0, 1200), rng.gen_range(0, 1200));
+        let nearest = table.nearest_into::<5>(&center);
+
+        let mut expected = points.iter().map(|p| center.dist_sq(p)).collect::<Vec<_>>();
+        expected.sort();
+        expected.truncate(5);
+
+        let dists = nearest
+            .iter()
+            .map(|n| {
+                let (p, v) = n.expect("the table has more than 5 items");
+                assert_eq!(v, Value(p[0] ^ p[1]));
+                center.dist_sq(&p)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(dists, expected, "center: {:?}", center);
+    }
+}
+
+#[test]
+fn nearest_into_small_tables() {
+    let table = MortonTable::new();
+    assert_eq!(table.nearest_into::<2>(&Point::new(3, 3)), [None, None]);
+
+    let table = MortonTable::from_iterator(
+        [
+            (Point::new(1, 1), Value(0)),
+            (Point::new(9000, 9000), Value(1)),
+        ]
+        .iter()
+        .cloned(),
+    );
+    assert_eq!(
+        table.nearest_into::<3>(&Point::new(0, 0)),
+        [
+            Some((Point::new(1, 1), Value(0))),
+            Some((Point::new(9000, 9000), Value(1))),
+            None
+        ]
+    );
+    assert_eq!(table.nearest_into::<0>(&Point::new(0, 0)), []);
+}
+
+#[test]
+fn defragment_drops_slack() {
+    let mut table = MortonTable::new();
+    for i in 0..256 {
+        table.insert(Point::new(i, i), Value(i)).unwrap();
+    }
+    for i in (0..256).step_by(2) {
+        table.delete(&Point::new(i, i));
+    }
+
+    table.defragment();
+
+    assert_eq!(table.keys.capacity(), 128);
+    assert_eq!(table.positions.capacity(), 128);
+    assert_eq!(table.values.capacity(), 128);
+    for i in (1..256).step_by(2) {
+        assert_eq!(table.get_by_id(&Point::new(i, i)), Some(&Value(i)));
+    }
+}
+
+#[test]
+fn range_keys() {
+    let (min, max) = MortonTable::range_keys(&Point::new(8, 8), 4);
+    assert_eq!(min, MortonKey::new(4, 4));
+    assert_eq!(max, MortonKey::new(12, 12));
+
+    // clamped to the bounds of the table
+    let (min, max) = MortonTable::range_keys(&Point::new(2, 0x7ff0), 5000);
+    assert_eq!(min, MortonKey::new(0, 0x7ff0 - 5000));
+    assert_eq!(max, MortonKey::new(5002, 0x7fff));
+}
+
+#[test]
+fn find_in_range_large_radius() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..256).map(|i| {
+        let p = Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15));
+        (p, Value(i))
+    }));
+
+    let mut res = Vec::new();
+    table.find_in_range(&Point::new(1 << 14, 1 << 14), 1 << 15, &mut res);
+    assert_eq!(res.len(), 256);
+}
+
+#[test]
+fn find_in_range_indices_matches_find_in_range() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..512).map(|i| {
+        let p = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        (p, Value(i))
+    }));
+
+    let mut expected = Vec::new();
+    let mut indices = Vec::new();
+    for _ in 0..64 {
+        let center = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        let radius = rng.gen_range(0, 256);
+
+        expected.clear();
+        indices.clear();
+        table.find_in_range(&center, radius, &mut expected);
+        table.find_in_range_indices(&center, radius, &mut indices);
+
+        let res = indices
+            .iter()
+            .map(|i| (table.positions[*i], &table.values[*i]))
+            .collect::<Vec<_>>();
+        assert_eq!(res, expected);
+    }
+}
+
+#[test]
+fn contains_any_in_aabb() {
+    let mut table = MortonTable::new();
+    assert!(!table.contains_any_in_aabb(&Point::new(0, 0), &Point::new(100, 100)));
+
+    table.insert(Point::new(10, 20), Value(1)).unwrap();
+    table.insert(Point::new(50, 50), Value(2)).unwrap();
+
+    // a box containing exactly one point, on its corners
+    assert!(table.contains_any_in_aabb(&Point::new(10, 20), &Point::new(10, 20)));
+    assert!(table.contains_any_in_aabb(&Point::new(0, 0), &Point::new(10, 20)));
+    assert!(table.contains_any_in_aabb(&Point::new(10, 20), &Point::new(40, 40)));
+    // empty boxes right next to the points
+    assert!(!table.contains_any_in_aabb(&Point::new(11, 0), &Point::new(49, 49)));
+    assert!(!table.contains_any_in_aabb(&Point::new(0, 0), &Point::new(10, 19)));
+    assert!(!table.contains_any_in_aabb(&Point::new(51, 0), &Point::new(0xffff, 0xffff)));
+    // inverted box
+    assert!(!table.contains_any_in_aabb(&Point::new(50, 50), &Point::new(10, 20)));
+}
+
+#[test]
+fn from_grid() {
+    let table = MortonTable::from_grid(64, 64, |x, y| {
+        if x % 3 == 0 {
+            None
+        } else {
+            Some(Value(x as u32 * 1000 + y as u32))
+        }
+    });
+
+    assert_eq!(table.keys.len(), 64 * 64 - 22 * 64);
+    for x in 0..64 {
+        for y in 0..64 {
+            let expected = if x % 3 == 0 {
+                None
+            } else {
+                Some(&Value(x * 1000 + y))
+            };
+            assert_eq!(table.get_by_id(&Point::new(x, y)), expected);
+        }
+    }
+    assert_eq!(table.get_by_id(&Point::new(64, 1)), None);
+
+    // not a power of two and not square
+    let table = MortonTable::from_grid(5, 3, |x, y| Some(Value(x as u32 * 10 + y as u32)));
+    assert_eq!(table.keys.len(), 15);
+    assert!(table.keys.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(table.get_by_id(&Point::new(4, 2)), Some(&Value(42)));
+    assert_eq!(table.get_by_id(&Point::new(2, 4)), None);
+}
+
+#[test]
+fn into_parts_round_trip() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..128).map(|i| {
+        let p = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        (p, Value(i))
+    }));
+    let expected = table.clone();
+
+    let (keys, positions, values) = table.into_parts();
+    let table = MortonTable::from_parts(keys, positions, values).unwrap();
+
+    assert_eq!(table.keys, expected.keys);
+    assert_eq!(table.positions, expected.positions);
+    assert_eq!(table.values, expected.values);
+    for p in expected.positions.iter() {
+        assert_eq!(table.get_by_id(p), expected.get_by_id(p));
+    }
+}
+
+#[test]
+fn from_parts_rejects_invalid_input() {
+    let keys = vec![MortonKey::new(1, 1), MortonKey::new(0, 1)];
+    let positions = vec![Point::new(1, 1), Point::new(0, 1)];
+    let values = vec![Value(0), Value(1)];
+
+    assert_eq!(
+        MortonTable::from_parts(keys.clone(), positions.clone(), vec![Value(0)]).err(),
+        Some(PartsError::LengthMismatch)
+    );
+    assert_eq!(
+        MortonTable::from_parts(keys.clone(), positions.clone(), values.clone()).err(),
+        Some(PartsError::NotSorted(1))
+    );
+    assert_eq!(
+        MortonTable::from_parts(
+            vec![MortonKey::new(0, 1), MortonKey::new(1, 1)],
+            positions,
+            values
+        )
+        .err(),
+        Some(PartsError::InvalidKey(0))
+    );
+}
+
+#[test]
+fn skiplist_grows_with_the_table() {
+    assert_eq!(skip_len(0), MIN_SKIP_LEN);
+    assert_eq!(skip_len(4096), 8);
+    assert_eq!(skip_len(8192), 16);
+    assert_eq!(skip_len(1 << 15), MAX_SKIP_LEN);
+
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashSet::with_capacity(1 << 15);
+    while points.len() < 1 << 15 {
+        points.insert(Point::new(rng.gen_range(0, 4096), rng.gen_range(0, 4096)));
+    }
+    let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(p[0] ^ p[1]))));
+    assert_eq!(table.skiplist().len(), MAX_SKIP_LEN);
+
+    for p in points.iter() {
+        assert_eq!(table.get_by_id(p), Some(&Value(p[0] ^ p[1])));
+    }
+    for _ in 0..1024 {
+        let key = MortonKey::new(rng.gen_range(0, 4200), rng.gen_range(0, 4200));
+        let scalar = find_key_partition_scalar(table.skiplist(), &key);
+        if is_x86_feature_detected!("sse2") {
+            let sse2 = unsafe { find_key_partition_sse2(table.skiplist(), &key) };
+            assert_eq!(scalar, sse2);
+        }
+        assert_eq!(table.find_key_morton(&key), table.keys.binary_search(&key));
+    }
+}
+
+#[test]
+fn iter_in_range_matches_find_in_range() {
+    let mut rng = rand::thread_rng();
+
+    let table = MortonTable::from_iterator((0..2048).map(|i| {
+        let p = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        (p, Value(i))
+    }));
+
+    let mut expected = Vec::new();
+    let mut limited = Vec::new();
+    for _ in 0..64 {
+        let center = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        let radius = rng.gen_range(0, 256);
+
+        expected.clear();
+        limited.clear();
+        table.find_in_range(&center, radius, &mut expected);
+        table.find_in_range_limited(&center, radius, 5, &mut limited);
+
+        let res = table.iter_in_range(&center, radius).collect::<Vec<_>>();
+        assert_eq!(res, expected);
+        assert_eq!(&limited[..], &expected[..expected.len().min(5)]);
+    }
+}
+
+#[test]
+fn position_of() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(3, 4), Value(1)).unwrap();
+    table.insert(Point::new(9, 2), Value(2)).unwrap();
+    table.insert(Point::new(1, 1), Value(2)).unwrap();
+
+    assert_eq!(table.position_of(&Value(1)), Some(Point::new(3, 4)));
+    // the first match in Morton order
+    assert_eq!(table.position_of(&Value(2)), Some(Point::new(1, 1)));
+    assert_eq!(table.position_of(&Value(3)), None);
+}
+
+#[test]
+fn extend_keeps_duplicates_extend_dedup_keeps_the_last_value() {
+    let items = [
+        (Point::new(1, 2), Value(1)),
+        (Point::new(5, 5), Value(2)),
+        (Point::new(1, 2), Value(3)),
+        (Point::new(1, 2), Value(4)),
+    ];
+
+    // plain `extend` stores every item
+    let mut table = MortonTable::new();
+    table.extend(items.iter().cloned());
+    assert_eq!(table.keys.len(), 4);
+
+    let mut table = MortonTable::new();
+    table.extend_dedup(items.iter().cloned());
+    assert_eq!(table.keys.len(), 2);
+    assert_eq!(table.get_by_id(&Point::new(1, 2)), Some(&Value(4)));
+    assert_eq!(table.get_by_id(&Point::new(5, 5)), Some(&Value(2)));
+
+    // overwrites items already in the table
+    table.extend_dedup(
+        [(Point::new(5, 5), Value(6)), (Point::new(0, 0), Value(7))]
+            .iter()
+            .cloned(),
+    );
+    assert_eq!(table.keys.len(), 3);
+    assert_eq!(table.get_by_id(&Point::new(5, 5)), Some(&Value(6)));
+    assert_eq!(table.get_by_id(&Point::new(0, 0)), Some(&Value(7)));
+    assert_eq!(table.delete(&Point::new(1, 2)), Some(Value(4)));
+    assert!(!table.contains_key(&Point::new(1, 2)));
+}
+
+#[test]
+fn extend_dedup_overwrites_staged_items() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(1, 1), Value(0)).unwrap();
+    table.insert_staged(Point::new(3, 4), Value(1)).unwrap();
+    table.insert_staged(Point::new(1, 2), Value(2)).unwrap();
+
+    table.extend_dedup(
+        [(Point::new(3, 4), Value(3)), (Point::new(1, 2), Value(4))]
+            .iter()
+            .cloned(),
+    );
+    table.compact();
+
+    assert_eq!(table.keys.len(), 3);
+    assert_eq!(table.get_by_id(&Point::new(3, 4)), Some(&Value(3)));
+    assert_eq!(table.get_by_id(&Point::new(1, 2)), Some(&Value(4)));
+    assert_eq!(table.get_by_id(&Point::new(1, 1)), Some(&Value(0)));
+}
+
+#[test]
+fn morton_key_le_bytes_round_trip() {
+    let key = MortonKey::new(0x1234, 0x0f0f);
+    let bytes = key.to_le_bytes();
+    assert_eq!(bytes, key.0.to_le_bytes());
+    assert_eq!(MortonKey::from_le_bytes(bytes), key);
+}
+
+#[cfg(feature = "bytemuck")]
+#[test]
+fn pack_keys_is_a_view_of_the_keys() {
+    let keys = [MortonKey(1), MortonKey(0x01020304)];
+    let bytes = pack_keys(&keys);
+    assert_eq!(bytes.len(), 8);
+    assert_eq!(&bytes[..4], &1u32.to_ne_bytes());
+    assert_eq!(&bytes[4..], &0x01020304u32.to_ne_bytes());
+}
+
+#[test]
+fn find_in_range_budgeted() {
+    let table = MortonTable::from_iterator(
+        (0..64).flat_map(|x| (0..64).map(move |y| (Point::new(x, y), Value(x * 64 + y)))),
+    );
+
+    let center = Point::new(32, 32);
+    let mut expected = Vec::new();
+    table.find_in_range(&center, 10, &mut expected);
+
+    let mut res = Vec::new();
+    assert!(table.find_in_range_budgeted(&center, 10, usize::MAX, &mut res));
+    assert_eq!(res, expected);
+
+    res.clear();
+    assert!(!table.find_in_range_budgeted(&center, 10, 20, &mut res));
+    assert!(res.len() <= 20);
+    // the partial result is a prefix of the full one
+    assert_eq!(&res[..], &expected[..res.len()]);
+
+    res.clear();
+    assert!(!table.find_in_range_budgeted(&center, 10, 0, &mut res));
+    assert!(res.is_empty());
+}
+
+#[test]
+fn clear_keeps_the_capacity_and_the_table_usable() {
+    let mut table = MortonTable::from_iterator((0..256).map(|i| (Point::new(i, i), Value(i))));
+    let capacity = table.keys.capacity();
+
+    table.clear();
+
+    assert_eq!(table.keys.capacity(), capacity);
+    assert_eq!(table.get_by_id(&Point::new(3, 3)), None);
+    let mut res = Vec::new();
+    table.find_in_range(&Point::new(3, 3), 10, &mut res);
+    assert!(res.is_empty());
+
+    table.extend((0..16).map(|i| (Point::new(i, 0), Value(i))));
+    assert_eq!(table.get_by_id(&Point::new(3, 0)), Some(&Value(3)));
+}
+
+#[test]
+fn clone_into_reuses_the_allocation() {
+    let mut rng = rand::thread_rng();
+
+    let src = MortonTable::from_iterator((0..1024).map(|i| {
+        let p = Point::new(rng.gen_range(0, 1024), rng.gen_range(0, 1024));
+        (p, Value(i))
+    }));
+    let mut dst = MortonTable::from_iterator((0..2048).map(|i| (Point::new(i, 0), Value(i))));
+    let ptr = dst.keys.as_ptr();
+
+    src.clone_into(&mut dst);
+
+    assert_eq!(dst.keys.as_ptr(), ptr);
+    assert_eq!(dst.keys, src.keys);
+    assert_eq!(dst.positions, src.positions);
+    assert_eq!(dst.values, src.values);
+    for p in src.positions.iter() {
+        assert_eq!(dst.get_by_id(p), src.get_by_id(p));
+    }
+    assert_eq!(dst.get_by_id(&Point::new(2000, 0)), None);
+}
+
+#[test]
+fn out_of_bounds_points_are_rejected_not_wrapped() {
+    let mut table = MortonTable::new();
+
+    // 40000 has bit 15 set, 70000 does not fit into 16 bits and would wrap to 4464
+    for p in [
+        Point::new(40000, 40000),
+        Point::new(70000, 1),
+        Point::new(1, 70000),
+    ]
+    .iter()
+    {
+        assert_eq!(table.insert(*p, Value(1)), Err(*p));
+        assert!(!table.contains_key(p));
+        assert_eq!(table.get_by_id(p), None);
+        assert_eq!(table.delete(p), None);
+    }
+    assert!(table.keys.is_empty());
+    assert!(!table.contains_key(&Point::new(4464, 1)));
+}
+
+#[test]
+#[should_panic]
+fn extend_panics_on_out_of_bounds_points() {
+    let mut table = MortonTable::new();
+    table.extend(std::iter::once((Point::new(70000, 1), Value(1))));
+}
+
+#[test]
+fn extract_region() {
+    let mut rng = rand::thread_rng();
+
+    let mut points = HashSet::with_capacity(1024);
+    while points.len() < 1024 {
+        points.insert(Point::new(rng.gen_range(0, 512), rng.gen_range(0, 512)));
+    }
+    let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(p[0] * 1000 + p[1]))));
+
+    let (min, max) = (Point::new(100, 37), Point::new(300, 200));
+    let region = table.extract_region(&min, &max);
+
+    assert!(region.keys.windows(2).all(|w| w[0] <= w[1]));
+    let inside = |p: &Point| min[0] <= p[0] && p[0] <= max[0] && min[1] <= p[1] && p[1] <= max[1];
+    assert_eq!(
+        region.keys.len(),
+        points.iter().filter(|p| inside(p)).count()
+    );
+    for p in points.iter() {
+        if inside(p) {
+            assert_eq!(region.get_by_id(p), Some(&Value(p[0] * 1000 + p[1])));
+        } else {
+            assert!(!region.contains_key(p));
+        }
+    }
+
+    let empty = table.extract_region(&Point::new(40000, 0), &Point::new(50000, 10));
+    assert!(empty.keys.is_empty());
+}
+
+#[test]
+fn histogram() {
+    let mut rng = rand::thread_rng();
+
+    let points = (0..1024)
+        .map(|_| Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15)))
+        .collect::<Vec<_>>();
+    let table = MortonTable::from_iterator(points.iter().map(|p| (*p, Value(0))));
+
+    let cell_bits = 12;
+    let hist = table.histogram(cell_bits).unwrap();
+    assert_eq!(hist.len(), 64);
+    assert_eq!(hist.iter().sum::<u32>(), 1024);
+
+    let mut expected = vec![0; 64];
+    for p in points.iter() {
+        let (x, y) = (p[0] >> cell_bits, p[1] >> cell_bits);
+        expected[MortonKey::new(x as u16, y as u16).0 as usize] += 1;
+    }
+    assert_eq!(hist, expected);
+
+    assert_eq!(table.histogram(15), Ok(vec![1024]));
+    let hist = table.histogram(MIN_HISTOGRAM_CELL_BITS).unwrap();
+    assert_eq!(hist.len(), 1 << 20);
+}
+
+#[test]
+fn histogram_rejects_invalid_cells() {
+    let table = MortonTable::new();
+    for bits in 0..MIN_HISTOGRAM_CELL_BITS {
+        assert_eq!(
+            table.histogram(bits),
+            Err(HistogramError::CellsTooSmall(bits))
+        );
+    }
+    assert_eq!(table.histogram(16), Err(HistogramError::CellsTooLarge(16)));
+}
+
+#[test]
+fn insert_unique() {
+    let mut table = MortonTable::new();
+
+    assert_eq!(table.insert_unique(Point::new(3, 4), Value(1)), Ok(()));
+    assert_eq!(table.insert_unique(Point::new(4, 3), Value(2)), Ok(()));
+    assert_eq!(
+        table.insert_unique(Point::new(3, 4), Value(3)),
+        Err(InsertError::Occupied(Value(1)))
+    );
+    assert_eq!(
+        table.insert_unique(Point::new(40000, 4), Value(3)),
+        Err(InsertError::OutOfBounds {
+            id: Point::new(40000, 4),
+            bounds: table.bounds()
+        })
+    );
+
+    assert_eq!(table.keys.len(), 2);
+    assert_eq!(table.get_by_id(&Point::new(3, 4)), Some(&Value(1)));
+    assert_eq!(table.get_by_id(&Point::new(4, 3)), Some(&Value(2)));
+}
+
+#[test]
+fn radix_sort_matches_quicksort() {
+    let mut rng = rand::thread_rng();
+
+    for len in [0, 1, 2, 100, 5000, 20_000].iter() {
+        let points = (0..*len)
+            .map(|_| Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15)))
+            .collect::<Vec<_>>();
+        let keys = points
+            .iter()
+            .map(|p| MortonKey::new(p[0] as u16, p[1] as u16))
+            .collect::<Vec<_>>();
+        let values = (0..*len).map(Value).collect::<Vec<_>>();
+
+        let (mut k1, mut p1, mut v1) = (keys.clone(), points.clone(), values.clone());
+        sorting::sort(&mut k1, &mut p1, &mut v1);
+        let (mut k2, mut p2, mut v2) = (keys.clone(), points.clone(), values.clone());
+        sorting::radix_sort(&mut k2, &mut p2, &mut v2);
+        let (mut k3, mut p3, mut v3) = (keys.clone(), points.clone(), values.clone());
+        sorting::sort_sequential(&mut k3, &mut p3, &mut v3);
+
+        assert_eq!(k1, k2);
+        assert_eq!(k1, k3);
+        for ((k, p), v) in k3.iter().zip(p3.iter()).zip(v3.iter()) {
+            assert_eq!(*k, MortonKey::new(p[0] as u16, p[1] as u16));
+            assert_eq!(*p, points[v.0 as usize]);
+        }
+        assert!(k2.windows(2).all(|w| w[0] <= w[1]));
+        // the positions and values moved along with their keys
+        for ((k, p), v) in k2.iter().zip(p2.iter()).zip(v2.iter()) {
+            assert_eq!(*k, MortonKey::new(p[0] as u16, p[1] as u16));
+            assert_eq!(*p, points[v.0 as usize]);
+        }
+    }
+}
+
+#[test]
+fn swap_values() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(1, 2), Value(1)).unwrap();
+    table.insert(Point::new(30, 40), Value(2)).unwrap();
+
+    assert!(table.swap_values(&Point::new(1, 2), &Point::new(30, 40)));
+    assert_eq!(table.get_by_id(&Point::new(1, 2)), Some(&Value(2)));
+    assert_eq!(table.get_by_id(&Point::new(30, 40)), Some(&Value(1)));
+
+    assert!(!table.swap_values(&Point::new(1, 2), &Point::new(5, 5)));
+    assert!(!table.swap_values(&Point::new(40000, 2), &Point::new(1, 2)));
+    assert_eq!(table.get_by_id(&Point::new(1, 2)), Some(&Value(2)));
+
+    // swapping with itself is a no-op
+    assert!(table.swap_values(&Point::new(1, 2), &Point::new(1, 2)));
+    assert_eq!(table.get_by_id(&Point::new(1, 2)), Some(&Value(2)));
+}
+
+#[test]
+fn keys_accessor() {
+    let table = MortonTable::from_iterator((0..32).map(|i| (Point::new(31 - i, i), Value(i))));
+
+    let keys = table.keys();
+    assert_eq!(keys.len(), 32);
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    for (key, p) in keys.iter().zip(table.points()) {
+        assert_eq!(key.as_point(), p.0);
+    }
+}
+
+#[test]
+fn find_in_expanding_range() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(100, 100), Value(1)).unwrap();
+    table.insert(Point::new(130, 100), Value(2)).unwrap();
+
+    let mut res = Vec::new();
+    let r = table.find_in_expanding_range(&Point::new(100, 110), 1, 1000, &mut res);
+    assert_eq!(r, 16);
+    assert_eq!(res, vec![(Point::new(100, 100), &Value(1))]);
+
+    // the last step is clamped to max_radius
+    res.clear();
+    let r = table.find_in_expanding_range(&Point::new(100, 140), 3, 40, &mut res);
+    assert_eq!(r, 40);
+    assert_eq!(res, vec![(Point::new(100, 100), &Value(1))]);
+
+    // nothing within max_radius
+    res.clear();
+    let r = table.find_in_expanding_range(&Point::new(1000, 1000), 0, 100, &mut res);
+    assert_eq!(r, 100);
+    assert!(res.is_empty());
+
+    // a start radius above max_radius is clamped
+    let r = table.find_in_expanding_range(&Point::new(100, 100), 50, 10, &mut res);
+    assert_eq!(r, 10);
+    assert_eq!(res.len(), 1);
+}
+
+#[test]
+fn morton_key_in_cell() {
+    let key = MortonKey::new(0x8000 + 3, 0x4000 + 5);
+
+    assert!(key.in_cell(0, 0));
+    // right half in x, bottom half in y
+    assert!(key.in_cell(1, 1));
+    assert!(!key.in_cell(0, 1));
+    assert!(!key.in_cell(3, 1));
+    // second level: x in [0x8000, 0xc000), y in [0x4000, 0x8000)
+    assert!(key.in_cell(0b01_10, 2));
+    assert!(!key.in_cell(0b01_11, 2));
+    // a single point
+    assert!(key.in_cell(key.0, 16));
+    assert!(!key.in_cell(key.0 + 1, 16));
+
+    let mut rng = rand::thread_rng();
+    for depth in 0..=16 {
+        for _ in 0..64 {
+            let p = Point::new(rng.gen_range(0, 1 << 16), rng.gen_range(0, 1 << 16));
+            let key = MortonKey::new_u32(p[0], p[1]);
+            let cell = cell_at_depth(&p, depth);
+            assert!(key.in_cell(cell, depth));
+
+            let (min, max) = cell_bounds(cell, depth);
+            assert!(MortonKey::new_u32(min[0], min[1]).in_cell(cell, depth));
+            assert!(MortonKey::new_u32(max[0] - 1, max[1] - 1).in_cell(cell, depth));
+            if depth > 0 {
+                assert!(!key.in_cell(cell ^ 1, depth));
+            }
+        }
+    }
+}
+
+#[test]
+fn sort_structured_inputs() {
+    let mut rng = rand::thread_rng();
+
+    let sorted = (0..4096u32).map(MortonKey).collect::<Vec<_>>();
+    let mut nearly_sorted = sorted.clone();
+    for k in nearly_sorted.iter_mut().step_by(64) {
+        *k = MortonKey(rng.gen_range(0, 4096));
+    }
+    let reversed = sorted.iter().rev().cloned().collect::<Vec<_>>();
+
+    for keys in [sorted, nearly_sorted, reversed].iter() {
+        let mut k = keys.clone();
+        let mut p = keys.iter().map(|k| Point(k.as_point())).collect::<Vec<_>>();
+        let mut v = keys.iter().map(|k| Value(k.0)).collect::<Vec<_>>();
+        sorting::sort(&mut k, &mut p, &mut v);
+
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(k, expected);
+        for ((k, p), v) in k.iter().zip(p.iter()).zip(v.iter()) {
+            assert_eq!(Point(k.as_point()), *p);
+            assert_eq!(k.0, v.0);
+        }
+
+        let mut k = keys.clone();
+        let mut p = keys.iter().map(|k| Point(k.as_point())).collect::<Vec<_>>();
+        let mut v = keys.iter().map(|k| Value(k.0)).collect::<Vec<_>>();
+        sorting::sort_sequential(&mut k, &mut p, &mut v);
+        assert_eq!(k, expected);
+        for ((k, p), v) in k.iter().zip(p.iter()).zip(v.iter()) {
+            assert_eq!(Point(k.as_point()), *p);
+            assert_eq!(k.0, v.0);
+        }
+    }
+}
+
+#[test]
+fn filter_copies_matching_items() {
+    let table = MortonTable::from_iterator(
+        (0..512).map(|i| (Point::new(i % 32 * 31, i / 32 * 61), Value(i))),
+    );
+
+    let even = table.filter(|_, v| v.0 % 2 == 0);
+
+    assert_eq!(table.keys.len(), 512);
+    assert_eq!(even.keys.len(), 256);
+    assert!(even.keys.windows(2).all(|w| w[0] <= w[1]));
+    for (p, v) in even.positions.iter().zip(even.values.iter()) {
+        assert_eq!(v.0 % 2, 0);
+        assert_eq!(even.get_by_id(p), Some(v));
+    }
+
+    let mut res = Vec::new();
+    even.find_in_range(&Point::new(512, 512), 300, &mut res);
+    let mut expected = Vec::new();
+    table.find_in_range(&Point::new(512, 512), 300, &mut expected);
+    expected.retain(|(_, v)| v.0 % 2 == 0);
+    assert_eq!(res, expected);
+
+    assert!(table.filter(|_, _| false).keys.is_empty());
+}
+
+#[test]
+fn closest_empty() {
+    let mut table = MortonTable::new();
+    assert_eq!(
+        table.closest_empty(&Point::new(5, 5)),
+        Some(Point::new(5, 5))
+    );
+
+    // pack [0, 10) x [0, 10)
+    table.extend((0..10).flat_map(|x| (0..10).map(move |y| (Point::new(x, y), Value(x * 10 + y)))));
+
+    let p = table.closest_empty(&Point::new(5, 5)).unwrap();
+    assert!(!table.contains_key(&p));
+    // the first gap is right outside of the packed region
+    let dist = (p[0] as i64 - 5).abs().max((p[1] as i64 - 5).abs());
+    assert_eq!(dist, 5);
+    assert!(p[0] == 10 || p[1] == 10);
+
+    // the corner of the map, there is nothing at negative coordinates
+    let p = table.closest_empty(&Point::new(0, 0)).unwrap();
+    assert!(p[0] == 10 || p[1] == 10, "{:?}", p);
+    assert!(p[0] <= 10 && p[1] <= 10, "{:?}", p);
+
+    // a gap inside of the region
+    table.delete(&Point::new(3, 4));
+    assert_eq!(
+        table.closest_empty(&Point::new(2, 2)),
+        Some(Point::new(3, 4))
+    );
+
+    // the opposite corner of the map
+    let max = table.bounds().1[0] - 1;
+    table.insert(Point::new(max, max), Value(0)).unwrap();
+    let p = table.closest_empty(&Point::new(max, max)).unwrap();
+    assert!(table.intersects(&p));
+    assert_eq!((max - p[0]).max(max - p[1]), 1);
+}
+
+#[test]
+fn closest_empty_out_of_bounds_center() {
+    let mut table = MortonTable::new();
+    let max = table.bounds().1[0] - 1;
+    assert_eq!(
+        table.closest_empty(&Point::new(u32::MAX, u32::MAX)),
+        Some(Point::new(max, max))
+    );
+    assert_eq!(
+        table.closest_empty(&Point::new(3, u32::MAX)),
+        Some(Point::new(3, max))
+    );
+
+    table.insert(Point::new(max, max), Value(0)).unwrap();
+    let p = table
+        .closest_empty(&Point::new(u32::MAX, u32::MAX))
+        .unwrap();
+    assert!(table.intersects(&p));
+    assert_eq!((max - p[0]).max(max - p[1]), 1);
+}
+
+#[test]
+fn neighbors_at_the_corner() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(0, 0), Value(0)).unwrap();
+    table.insert(Point::new(1, 0), Value(1)).unwrap();
+    table.insert(Point::new(1, 1), Value(2)).unwrap();
+
+    assert_eq!(
+        table.neighbors_4(&Point::new(0, 0)),
+        [None, Some(&Value(1)), None, None]
+    );
+    assert_eq!(
+        table.neighbors_8(&Point::new(0, 0)),
+        [
+            None,
+            None,
+            None,
+            None,
+            Some(&Value(1)),
+            None,
+            None,
+            Some(&Value(2))
+        ]
+    );
+
+    let max = table.bounds().1[0] - 1;
+    table.insert(Point::new(max, max - 1), Value(3)).unwrap();
+    assert_eq!(
+        table.neighbors_4(&Point::new(max, max)),
+        [None, None, Some(&Value(3)), None]
+    );
+    assert_eq!(table.neighbors_8(&Point::new(max, max))[1], Some(&Value(3)));
+}
+
+#[test]
+fn neighbors_match_get_by_id() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 64), rng.gen_range(0, 64)),
+            Value(i),
+        )
+    }));
+
+    for _ in 0..256 {
+        let p = Point::new(rng.gen_range(0, 65), rng.gen_range(0, 65));
+        let res = table.neighbors_8(&p);
+        let mut i = 0;
+        for dy in -1..=1i64 {
+            for dx in -1..=1i64 {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                let x = p[0] as i64 + dx;
+                let y = p[1] as i64 + dy;
+                let expected = if x < 0 || y < 0 {
+                    None
+                } else {
+                    table.get_by_id(&Point::new(x as u32, y as u32))
+                };
+                // the table may hold duplicates, compare the positions only
+                assert_eq!(
+                    res[i].is_some(),
+                    expected.is_some(),
+                    "{:?} {} {}",
+                    p,
+                    dx,
+                    dy
+                );
+                i += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn translate_evicts_out_of_bounds_items() {
+    let mut table = MortonTable::new();
+    let max = table.bounds().1[0] - 1;
+    table.extend(
+        vec![
+            (Point::new(0, 0), Value(0)),
+            (Point::new(5, 10), Value(1)),
+            (Point::new(100, 3), Value(2)),
+            (Point::new(max - 1, 20), Value(3)),
+        ]
+        .into_iter(),
+    );
+
+    let mut evicted = table.translate([2, -5]);
+    evicted.sort_by_key(|(_, v)| v.0);
+    assert_eq!(
+        evicted,
+        vec![
+            (Point::new(0, 0), Value(0)),
+            (Point::new(100, 3), Value(2)),
+            (Point::new(max - 1, 20), Value(3)),
+        ]
+    );
+    assert_eq!(table.keys.len(), 1);
+    assert_eq!(table.get_by_id(&Point::new(7, 5)), Some(&Value(1)));
+    assert_eq!(table.get_by_id(&Point::new(5, 10)), None);
+}
+
+#[test]
+fn translate_keeps_the_table_sorted() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000)),
+            Value(i),
+        )
+    }));
+    let before = table.positions.clone();
+
+    let evicted = table.translate([-100, 300]);
+    assert_eq!(evicted.len() + table.keys.len(), 2000);
+    assert!(evicted.iter().all(|(p, _)| p[0] < 100));
+    assert!(table.keys.windows(2).all(|w| w[0] <= w[1]));
+    for p in before.iter().filter(|p| p[0] >= 100) {
+        assert!(table.contains_key(&Point::new(p[0] - 100, p[1] + 300)));
+    }
+}
+
+#[test]
+fn try_find_in_range_rejects_invalid_queries() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(10, 10), Value(1)).unwrap();
+    let mut res = Vec::new();
+
+    assert_eq!(
+        table.try_find_in_range(&Point::new(12, 10), u32::MAX, &mut res),
+        Err(QueryError::RadiusTooLarge(u32::MAX))
+    );
+    let center = Point::new(table.bounds().1[0], 10);
+    assert_eq!(
+        table.try_find_in_range(&center, 5, &mut res),
+        Err(QueryError::CenterOutOfBounds {
+            center,
+            bounds: table.bounds()
+        })
+    );
+    assert!(res.is_empty());
+
+    assert_eq!(
+        table.try_find_in_range(&Point::new(12, 10), i32::MAX as u32, &mut res),
+        Ok(())
+    );
+    assert_eq!(res, vec![(Point::new(10, 10), &Value(1))]);
+}
+
+#[test]
+fn common_ancestor_of_keys() {
+    // adjacent points share every level but the last
+    let a = MortonKey::new(0, 0);
+    let b = MortonKey::new(1, 0);
+    assert_eq!(common_ancestor(a, b), (0, 15));
+
+    // opposite corners are only in the root
+    let a = MortonKey::new(0, 0);
+    let b = MortonKey::new(0xffff, 0xffff);
+    assert_eq!(common_ancestor(a, b), (0, 0));
+
+    // the same key is its own cell
+    let a = MortonKey::new(1234, 4321);
+    assert_eq!(common_ancestor(a, a), (a.0, 16));
+
+    // both in the top right quadrant
+    let a = MortonKey::new(0x8000, 0);
+    let b = MortonKey::new(0xffff, 0x7fff);
+    assert_eq!(common_ancestor(a, b), (1, 1));
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..256 {
+        let p = Point::new(rng.gen_range(0, 1 << 16), rng.gen_range(0, 1 << 16));
+        let q = Point::new(rng.gen_range(0, 1 << 16), rng.gen_range(0, 1 << 16));
+        let a = MortonKey::new_u32(p[0], p[1]);
+        let b = MortonKey::new_u32(q[0], q[1]);
+
+        let (cell, depth) = common_ancestor(a, b);
+        assert_eq!(common_ancestor(b, a), (cell, depth));
+        assert!(a.in_cell(cell, depth));
+        assert!(b.in_cell(cell, depth));
+        if depth < 16 {
+            // the children of the cell split the keys
+            assert_ne!(a.cell(depth + 1), b.cell(depth + 1));
+        }
+    }
+}
+
+#[test]
+fn dedup_by_combines_stacked_values() {
+    let mut table = MortonTable::new();
+    table.extend(
+        vec![
+            (Point::new(3, 3), Value(1)),
+            (Point::new(1, 2), Value(7)),
+            (Point::new(3, 3), Value(10)),
+            (Point::new(9, 9), Value(2)),
+            (Point::new(3, 3), Value(100)),
+        ]
+        .into_iter(),
+    );
+    assert_eq!(table.keys.len(), 5);
+
+    table.dedup_by(|acc, v| acc.0 += v.0);
+
+    assert_eq!(table.keys.len(), 3);
+    assert_eq!(table.positions.len(), 3);
+    assert_eq!(table.values.len(), 3);
+    assert_eq!(table.get_by_id(&Point::new(3, 3)), Some(&Value(111)));
+    assert_eq!(table.get_by_id(&Point::new(1, 2)), Some(&Value(7)));
+    assert_eq!(table.get_by_id(&Point::new(9, 9)), Some(&Value(2)));
+    assert!(table.keys.windows(2).all(|w| w[0] < w[1]));
+
+    let mut empty = MortonTable::new();
+    empty.dedup_by(|_, _| unreachable!());
+    assert!(empty.keys.is_empty());
+}
+
+#[test]
+fn find_in_ring_matches_filtered_range_query() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000)),
+            Value(i),
+        )
+    }));
+
+    let mut res = Vec::new();
+    let mut expected = Vec::new();
+    for _ in 0..64 {
+        let center = Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000));
+        let outer = rng.gen_range(0, 300);
+        let inner = rng.gen_range(0, outer + 1);
+
+        res.clear();
+        expected.clear();
+        table.find_in_ring(&center, inner, outer, &mut res);
+        table.find_in_range(&center, outer, &mut expected);
+        expected.retain(|(p, _)| p.dist_sq(&center) >= inner as u64 * inner as u64);
+        assert_eq!(res, expected);
+
+        // inner == 0 is the disc
+        res.clear();
+        table.find_in_ring(&center, 0, outer, &mut res);
+        expected.clear();
+        table.find_in_range(&center, outer, &mut expected);
+        assert_eq!(res, expected);
+    }
+}
+
+#[test]
+fn find_in_ring_thin() {
+    let mut table = MortonTable::new();
+    for (i, p) in [[10, 10], [13, 14], [15, 10], [10, 16], [14, 13]]
+        .iter()
+        .enumerate()
+    {
+        table.insert(Point(*p), Value(i as u32)).unwrap();
+    }
+
+    let mut res = Vec::new();
+    table.find_in_ring(&Point::new(10, 10), 5, 5, &mut res);
+    res.sort_by_key(|(_, v)| v.0);
+    assert_eq!(
+        res,
+        vec![
+            (Point::new(13, 14), &Value(1)),
+            (Point::new(15, 10), &Value(2)),
+            (Point::new(14, 13), &Value(4)),
+        ]
+    );
+}
+
+#[test]
+fn values_mut_updates_in_place() {
+    let mut table = MortonTable::from_iterator((0..64).map(|i| (Point::new(i, 63 - i), Value(i))));
+
+    for v in table.values_mut() {
+        v.0 += 100;
+    }
+
+    for i in 0..64 {
+        assert_eq!(
+            table.get_by_id(&Point::new(i, 63 - i)),
+            Some(&Value(i + 100))
+        );
+    }
+}
+
+#[test]
+fn binary_round_trip() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..1000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000)),
+            Value(i),
+        )
+    }));
+
+    let mut buf = Vec::new();
+    table.write_to(&mut buf).unwrap();
+    assert_eq!(buf.len(), 4 + 4 + 8 + 1000 * 12);
+
+    let res = MortonTable::read_from(&mut buf.as_slice()).unwrap();
+    assert_eq!(res.keys, table.keys);
+    assert_eq!(res.positions, table.positions);
+    assert_eq!(res.values, table.values);
+    for (p, v) in table.positions.iter().zip(table.values.iter()) {
+        assert!(res.contains_key(p));
+        assert!(res.get_by_id(p).is_some());
+        assert!(res.position_of(v).is_some());
+    }
+
+    let mut buf = Vec::new();
+    MortonTable::new().write_to(&mut buf).unwrap();
+    let res = MortonTable::read_from(&mut buf.as_slice()).unwrap();
+    assert!(res.keys.is_empty());
+}
+
+#[test]
+fn read_from_rejects_bad_input() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(1, 2), Value(3)).unwrap();
+    table.insert(Point::new(100, 2), Value(4)).unwrap();
+    let mut buf = Vec::new();
+    table.write_to(&mut buf).unwrap();
+
+    let mut bad_magic = buf.clone();
+    bad_magic[0] = b'X';
+    let err = MortonTable::read_from(&mut bad_magic.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut bad_version = buf.clone();
+    bad_version[4] = 2;
+    let err = MortonTable::read_from(&mut bad_version.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let truncated = &buf[..buf.len() - 1];
+    let err = MortonTable::read_from(&mut &truncated[..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    // swap the two positions
+    let mut unsorted = buf.clone();
+    unsorted[16..32].rotate_left(8);
+    let err = MortonTable::read_from(&mut unsorted.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut out_of_bounds = buf.clone();
+    out_of_bounds[16..20].copy_from_slice(&0x10000u32.to_le_bytes());
+    let err = MortonTable::read_from(&mut out_of_bounds.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn find_in_range_sorted_is_nearest_first() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000)),
+            Value(i),
+        )
+    }));
+
+    let mut res = Vec::new();
+    let mut expected = Vec::new();
+    for _ in 0..64 {
+        let center = Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000));
+        let radius = rng.gen_range(0, 200);
+
+        res.clear();
+        expected.clear();
+        table.find_in_range_sorted(&center, radius, &mut res);
+        table.find_in_range(&center, radius, &mut expected);
+
+        assert_eq!(res.len(), expected.len());
+        assert!(res
+            .windows(2)
+            .all(|w| w[0].0.dist_sq(&center) <= w[1].0.dist_sq(&center)));
+        expected.sort_by_key(|(p, _)| p.dist_sq(&center));
+        assert_eq!(res, expected);
+    }
+}
+
+#[test]
+fn iter_raster_is_row_major() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..1000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 100), rng.gen_range(0, 100)),
+            Value(i),
+        )
+    }));
+
+    let res = table.iter_raster().collect::<Vec<_>>();
+    assert_eq!(res.len(), 1000);
+    assert!(res
+        .windows(2)
+        .all(|w| (w[0].0[1], w[0].0[0]) <= (w[1].0[1], w[1].0[0])));
+
+    let mut expected = table
+        .positions
+        .iter()
+        .cloned()
+        .zip(table.values.iter())
+        .collect::<Vec<_>>();
+    expected.sort_by_key(|(p, v)| (p[1], p[0], v.0));
+    let mut res = res;
+    res.sort_by_key(|(p, v)| (p[1], p[0], v.0));
+    assert_eq!(res, expected);
+}
+
+#[test]
+fn split_at_key() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000)),
+            Value(i),
+        )
+    }));
+    let pivot = table.keys[700];
+    let expected = table.clone();
+
+    let (left, right) = table.split_at_key(pivot);
+
+    assert_eq!(left.keys.len() + right.keys.len(), 2000);
+    assert!(left.keys.iter().all(|k| *k < pivot));
+    assert!(right.keys.iter().all(|k| *k >= pivot));
+    assert_eq!(right.keys[0], pivot);
+    for (p, v) in expected.positions.iter().zip(expected.values.iter()) {
+        let half = if MortonKey::new(p[0] as u16, p[1] as u16) < pivot {
+            &left
+        } else {
+            &right
+        };
+        assert!(half.contains_key(p));
+        assert!(half.position_of(v).is_some());
+    }
+
+    let len = right.keys.len();
+    let (left, right) = right.split_at_key(MortonKey(0));
+    assert!(left.keys.is_empty());
+    assert!(left.get_by_id(&Point::new(0, 0)).is_none());
+    assert_eq!(right.keys.len(), len);
+}
+
+#[test]
+fn signed_world_coordinates() {
+    let mut table = MortonTable::with_origin([-1000, -1000]);
+    assert_eq!(table.to_local([-1000, -1000]), Some(Point::new(0, 0)));
+    assert_eq!(table.to_local([-1001, 0]), None);
+    assert_eq!(table.to_world(&Point::new(1000, 1005)), [0, 5]);
+
+    table.insert_world([-5, -5], Value(1)).unwrap();
+    table.insert_world([5, -5], Value(2)).unwrap();
+    table.insert_world([-1000, 20], Value(3)).unwrap();
+    assert_eq!(table.insert_world([-1001, 0], Value(4)), Err([-1001, 0]));
+
+    assert_eq!(table.get_world([-5, -5]), Some(&Value(1)));
+    assert_eq!(table.get_by_id(&Point::new(995, 995)), Some(&Value(1)));
+    assert_eq!(table.get_world([5, 5]), None);
+    assert_eq!(table.get_world([-2000, 5]), None);
+
+    let mut res = Vec::new();
+    table.find_in_range_world([0, -5], 5, &mut res);
+    res.sort_by_key(|(_, v)| v.0);
+    assert_eq!(res, vec![([-5, -5], &Value(1)), ([5, -5], &Value(2))]);
+
+    // the center is out of the bounds of the table but the circle is not
+    res.clear();
+    table.find_in_range_world([-1010, 20], 10, &mut res);
+    assert_eq!(res, vec![([-1000, 20], &Value(3))]);
+
+    res.clear();
+    table.find_in_range_world([-1011, 20], 10, &mut res);
+    assert!(res.is_empty());
+}
+
+#[test]
+fn find_in_range_world_matches_local_query() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::with_origin([-500, -500]);
+    for i in 0..1000 {
+        table
+            .insert_world(
+                [rng.gen_range(-500, 500), rng.gen_range(-500, 500)],
+                Value(i),
+            )
+            .unwrap();
+    }
+
+    let mut res = Vec::new();
+    let mut expected = Vec::new();
+    for _ in 0..64 {
+        let center = Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000));
+        let radius = rng.gen_range(0, 200);
+
+        res.clear();
+        expected.clear();
+        table.find_in_range_world(table.to_world(&center), radius, &mut res);
+        table.find_in_range(&center, radius, &mut expected);
+        let expected = expected
+            .iter()
+            .map(|(p, v)| (table.to_world(p), *v))
+            .collect::<Vec<_>>();
+        assert_eq!(res, expected);
+    }
+}
+
+#[cfg(feature = "rand")]
+#[test]
+fn sample_in_range_is_uniform() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::new();
+    assert!(table.sample(&mut rng).is_none());
+    assert!(table
+        .sample_in_range(&mut rng, &Point::new(10, 10), 5)
+        .is_none());
+
+    // a dense cluster far from the center and 4 items in range
+    table.extend((0..32).flat_map(|x| (0..32).map(move |y| (Point::new(x, y), Value(0)))));
+    for (i, p) in [[100, 100], [103, 100], [100, 96], [97, 97]]
+        .iter()
+        .enumerate()
+    {
+        table.insert(Point(*p), Value(i as u32 + 1)).unwrap();
+    }
+
+    let mut counts = [0; 5];
+    for _ in 0..4000 {
+        let (p, v) = table
+            .sample_in_range(&mut rng, &Point::new(100, 100), 5)
+            .unwrap();
+        assert!(p.dist_sq(&Point::new(100, 100)) <= 25);
+        counts[v.0 as usize] += 1;
+    }
+    assert_eq!(counts[0], 0);
+    for c in counts[1..].iter() {
+        assert!(800 < *c && *c < 1200, "{:?}", counts);
+    }
+
+    assert!(table
+        .sample_in_range(&mut rng, &Point::new(500, 500), 5)
+        .is_none());
+    let (p, v) = table.sample(&mut rng).unwrap();
+    assert_eq!(table.get_by_id(&p), Some(v));
+}
+
+#[test]
+fn bounding_circle() {
+    let mut table = MortonTable::new();
+    assert_eq!(table.bounding_circle(), None);
+
+    table.insert(Point::new(10, 10), Value(0)).unwrap();
+    assert_eq!(table.bounding_circle(), Some((Point::new(10, 10), 0)));
+
+    table.insert(Point::new(20, 10), Value(1)).unwrap();
+    assert_eq!(table.bounding_circle(), Some((Point::new(15, 10), 5)));
+
+    // collinear points
+    table.insert(Point::new(30, 10), Value(2)).unwrap();
+    assert_eq!(table.bounding_circle(), Some((Point::new(20, 10), 10)));
+
+    // the corners of a square and its center
+    let mut table = MortonTable::new();
+    for p in [[0, 0], [100, 0], [0, 100], [100, 100], [50, 50]].iter() {
+        table.insert(Point(*p), Value(0)).unwrap();
+    }
+    assert_eq!(table.bounding_circle(), Some((Point::new(50, 50), 71)));
+}
+
+#[test]
+fn bounding_circle_encloses_everything() {
+    let mut rng = rand::thread_rng();
+    for _ in 0..16 {
+        let table = MortonTable::from_iterator((0..500).map(|i| {
+            (
+                Point::new(rng.gen_range(1000, 3000), rng.gen_range(500, 1500)),
+                Value(i),
+            )
+        }));
+        let (center, radius) = table.bounding_circle().unwrap();
+
+        let mut res = Vec::new();
+        table.find_in_range(&center, radius, &mut res);
+        assert_eq!(res.len(), 500);
+        // never larger than the circle around the AABB
+        let (min, max) = table.extent().unwrap();
+        let half_diagonal = (min.dist_sq(&max) as f64).sqrt() / 2.0;
+        assert!(radius as f64 <= half_diagonal + 2.0);
+    }
+}
+
+#[test]
+fn incremental_skiplist_matches_rebuild() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::new();
+    let mut reference = MortonTable::new();
+    for i in 0..20000 {
+        let p = Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000));
+        if i % 2 == 0 {
+            table.insert(p, Value(i)).unwrap();
+        } else {
+            let _ = table.insert_unique(p, Value(i));
+        }
+        if i % 97 == 0 || i > 19900 {
+            reference.clone_from(&table);
+            reference.rebuild_skip_list();
+            assert_eq!(table.skipstep, reference.skipstep, "{}", i);
+            assert_eq!(table.skiplen, reference.skiplen, "{}", i);
+            assert_eq!(table.skiplist, reference.skiplist, "{}", i);
+        }
+    }
+    for p in table.positions.iter() {
+        assert!(table.contains_key(p));
+    }
+}
+
+#[test]
+fn find_in_polygon_triangle() {
+    let table = MortonTable::from_iterator(
+        (0..=20).flat_map(|x| (0..=20).map(move |y| (Point::new(x, y), Value(x * 100 + y)))),
+    );
+    let triangle = [Point::new(0, 0), Point::new(10, 0), Point::new(0, 10)];
+
+    let mut res = Vec::new();
+    table.find_in_polygon(&triangle, &mut res);
+    let mut reversed = Vec::new();
+    let rev = [triangle[2], triangle[1], triangle[0]];
+    table.find_in_polygon(&rev, &mut reversed);
+    assert_eq!(res, reversed);
+
+    let found = res.iter().map(|(p, _)| *p).collect::<HashSet<_>>();
+    // the corners and the points on the edges are inside
+    for p in [
+        [0, 0],
+        [10, 0],
+        [0, 10],
+        [5, 5],
+        [5, 0],
+        [0, 7],
+        [1, 1],
+        [3, 6],
+    ]
+    .iter()
+    {
+        assert!(found.contains(&Point(*p)), "{:?}", p);
+    }
+    for p in [[6, 5], [10, 1], [11, 0], [7, 7], [0, 11]].iter() {
+        assert!(!found.contains(&Point(*p)), "{:?}", p);
+    }
+    // x + y <= 10
+    assert_eq!(found.len(), 11 * 12 / 2);
+    for (p, v) in res.iter() {
+        assert!(p[0] + p[1] <= 10);
+        assert_eq!(v.0, p[0] * 100 + p[1]);
+    }
+}
+
+#[test]
+fn find_in_polygon_degenerate() {
+    let table = MortonTable::from_iterator(
+        (0..=20).flat_map(|x| (0..=20).map(move |y| (Point::new(x, y), Value(x * 100 + y)))),
+    );
+    let mut res = Vec::new();
+    table.find_in_polygon(&[], &mut res);
+    assert!(res.is_empty());
+
+    table.find_in_polygon(&[Point::new(3, 4)], &mut res);
+    assert_eq!(res, vec![(Point::new(3, 4), &Value(304))]);
+
+    res.clear();
+    table.find_in_polygon(&[Point::new(2, 2), Point::new(6, 6)], &mut res);
+    let mut found = res.iter().map(|(p, _)| p[0]).collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(found, vec![2, 3, 4, 5, 6]);
+    assert!(res.iter().all(|(p, _)| p[0] == p[1]));
+}
+
+#[test]
+fn morton_neighbors_match_decode_encode() {
+    let mut rng = rand::thread_rng();
+    let mut points = vec![
+        [0, 0],
+        [0xffff, 0xffff],
+        [0, 0xffff],
+        [0xffff, 0],
+        [0x7fff, 0x8000],
+    ];
+    for _ in 0..1000 {
+        points.push([rng.gen_range(0, 1 << 16), rng.gen_range(0, 1 << 16)]);
+    }
+    for [x, y] in points {
+        let key = MortonKey::new_u32(x, y);
+        let expected = [
+            x.checked_sub(1).map(|x| [x, y]),
+            Some([x + 1, y]).filter(|p| p[0] <= 0xffff),
+            y.checked_sub(1).map(|y| [x, y]),
+            Some([x, y + 1]).filter(|p| p[1] <= 0xffff),
+        ];
+        let res = morton_neighbors(key);
+        for (res, expected) in res.iter().zip(expected.iter()) {
+            assert_eq!(res.map(|k| k.as_point()), *expected, "{} {}", x, y);
+        }
+    }
+}
+
+#[test]
+fn map_values_passes_positions() {
+    let mut table = MortonTable::from_iterator((0..64).map(|i| (Point::new(i, 2 * i), Value(0))));
+
+    table.map_values(|p, v| v.0 = p[0] + p[1]);
+
+    for i in 0..64 {
+        assert_eq!(table.get_by_id(&Point::new(i, 2 * i)), Some(&Value(3 * i)));
+    }
+}
+
+#[test]
+fn par_extend_matches_extend() {
+    let mut rng = rand::thread_rng();
+    let items = (0..5000)
+        .map(|i| {
+            (
+                Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000)),
+                Value(i),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut expected = MortonTable::new();
+    expected.extend(items.iter().cloned());
+    let mut table = MortonTable::new().with_change_tracking();
+    table.par_extend(items.par_iter().cloned());
+
+    assert_eq!(table.keys, expected.keys);
+    assert_eq!(table.take_dirty().len(), items.len());
+    for (p, _) in items.iter() {
+        assert!(table.contains_key(p));
+    }
+
+    table.par_extend(vec![(Point::new(4001, 4001), Value(0))]);
+    assert!(table.contains_key(&Point::new(4001, 4001)));
+    assert_eq!(table.keys.len(), items.len() + 1);
+}
+
+#[test]
+#[should_panic]
+fn par_extend_panics_on_out_of_bounds_items() {
+    let mut table = MortonTable::new();
+    table.par_extend(vec![
+        (Point::new(1, 1), Value(0)),
+        (Point::new(1 << 16, 1), Value(1)),
+    ]);
+}
+
+#[test]
+fn find_in_range_metric() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000)),
+            Value(i),
+        )
+    }));
+
+    let mut res = Vec::new();
+    for _ in 0..32 {
+        let center = Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000));
+        let radius = rng.gen_range(0, 200);
+
+        for metric in [Metric::Euclidean, Metric::Manhattan, Metric::Chebyshev].iter() {
+            let in_range = |p: &Point| {
+                let dx = p[0].abs_diff(center[0]) as u64;
+                let dy = p[1].abs_diff(center[1]) as u64;
+                let r = radius as u64;
+                match metric {
+                    Metric::Euclidean => dx * dx + dy * dy <= r * r,
+                    Metric::Manhattan => dx + dy <= r,
+                    Metric::Chebyshev => dx.max(dy) <= r,
+                }
+            };
+            res.clear();
+            table.find_in_range_metric(&center, radius, *metric, &mut res);
+            let mut expected = table
+                .positions
+                .iter()
+                .cloned()
+                .zip(table.values.iter())
+                .filter(|(p, _)| in_range(p))
+                .collect::<Vec<_>>();
+            expected.sort_by_key(|(_, v)| v.0);
+            res.sort_by_key(|(_, v)| v.0);
+            assert_eq!(res, expected, "{:?}", metric);
+        }
+    }
+}
+
+#[test]
+fn insert_tracked_reports_shifted_items() {
+    let mut table = MortonTable::new();
+    let stats = table.insert_tracked(Point::new(10, 10), Value(0)).unwrap();
+    assert_eq!(stats.shifted, 0);
+    assert!(stats.skiplist_rebuilt);
+
+    table.insert(Point::new(20, 20), Value(1)).unwrap();
+    table.insert(Point::new(30, 30), Value(2)).unwrap();
+    // before every other item
+    let stats = table.insert_tracked(Point::new(0, 0), Value(3)).unwrap();
+    assert_eq!(stats.shifted, 3);
+    // after every other item
+    let stats = table.insert_tracked(Point::new(40, 40), Value(4)).unwrap();
+    assert_eq!(stats.shifted, 0);
+    let stats = table.insert_tracked(Point::new(15, 15), Value(5)).unwrap();
+    assert_eq!(stats.shifted, 3);
+
+    assert_eq!(
+        table.insert_tracked(Point::new(1 << 16, 0), Value(6)),
+        Err(Point::new(1 << 16, 0))
+    );
+
+    // a large table with a stable skiplist layout only updates the entries
+    let mut table = MortonTable::from_iterator((0..4000).map(|i| (Point::new(i, 0), Value(i))));
+    let stats = table.insert_tracked(Point::new(4000, 0), Value(0)).unwrap();
+    assert!(!stats.skiplist_rebuilt);
+}
+
+#[test]
+fn iter_cells_groups_items_by_cell() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..1000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000)),
+            Value(i),
+        )
+    }));
+
+    for depth in [0, 3, 6, 16] {
+        let mut count = 0;
+        let mut last = None;
+        for (cell, positions, values) in table.iter_cells(depth) {
+            assert!(!positions.is_empty());
+            assert_eq!(positions.len(), values.len());
+            // every cell is visited once, in Morton order
+            assert!(last < Some(cell));
+            last = Some(cell);
+            for p in positions {
+                assert_eq!(cell_at_depth(p, depth), cell);
+            }
+            count += positions.len();
+        }
+        assert_eq!(count, table.keys().len());
+    }
+    assert_eq!(table.iter_cells(0).count(), 1);
+
+    assert_eq!(MortonTable::new().iter_cells(4).count(), 0);
+}
+
+#[test]
+fn cell_counts_only_returns_occupied_cells() {
+    // two clusters in opposite corners
+    let table = MortonTable::from_iterator(
+        (0..10)
+            .flat_map(|x| (0..10).map(move |y| Point::new(x, y)))
+            .chain((0..5).map(|i| Point::new(30000 + i, 30000)))
+            .enumerate()
+            .map(|(i, p)| (p, Value(i as u32))),
+    );
+
+    let counts = table.cell_counts(4);
+    let far = cell_at_depth(&Point::new(30000, 30000), 4);
+    assert_eq!(counts, vec![(0, 100), (far, 5)]);
+    // matches the dense version
+    let hist = table.histogram(12).unwrap();
+    let nonempty = hist
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| **n != 0)
+        .map(|(i, n)| (i as u32, *n))
+        .collect::<Vec<_>>();
+    assert_eq!(counts, nonempty);
+
+    assert_eq!(table.cell_counts(0), vec![(0, 105)]);
+    assert!(table.cell_counts(16).iter().all(|(_, n)| *n == 1));
+    assert_eq!(table.cell_counts(16).len(), 105);
+    assert!(MortonTable::new().cell_counts(3).is_empty());
+}
+
+#[test]
+fn verify_contract_accepts_tables_built_by_the_api() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::new();
+    assert_eq!(table.verify_contract(), Ok(()));
+    for i in 0..5000 {
+        let p = Point::new(rng.gen_range(0, 3000), rng.gen_range(0, 3000));
+        table.insert(p, Value(i)).unwrap();
+        if i % 97 == 0 {
+            assert_eq!(table.verify_contract(), Ok(()));
+        }
+    }
+    assert_eq!(table.verify_contract(), Ok(()));
+    for p in table.positions.clone().iter().step_by(3) {
+        table.delete(p);
+    }
+    assert_eq!(table.verify_contract(), Ok(()));
+
+    table.insert_deferred(Point::new(1, 2), Value(0)).unwrap();
+    assert_eq!(
+        table.verify_contract(),
+        Err(ContractViolation::StaleSkipList)
+    );
+    table.flush();
+    assert_eq!(table.verify_contract(), Ok(()));
+
+    let mut table = MortonTable::without_skiplist();
+    table.extend((0..100).map(|i| (Point::new(i, i), Value(i))));
+    assert_eq!(table.verify_contract(), Ok(()));
+}
+
+#[test]
+fn verify_contract_reports_the_first_violation() {
+    let table =
+        MortonTable::from_iterator((0..5000).map(|i| (Point::new(i % 70, i / 70), Value(i))));
+
+    let mut t = table.clone();
+    t.values.pop();
+    assert_eq!(
+        t.verify_contract(),
+        Err(ContractViolation::Parts(PartsError::LengthMismatch))
+    );
+
+    let mut t = table.clone();
+    t.positions.swap(10, 11);
+    assert_eq!(
+        t.verify_contract(),
+        Err(ContractViolation::Parts(PartsError::InvalidKey(10)))
+    );
+
+    let mut t = table.clone();
+    t.keys.swap(10, 11);
+    t.positions.swap(10, 11);
+    assert_eq!(
+        t.verify_contract(),
+        Err(ContractViolation::Parts(PartsError::NotSorted(11)))
+    );
+
+    // sorted and consistent items, but the skiplist samples the old keys
+    let mut t = table.clone();
+    t.keys.drain(..5);
+    t.positions.drain(..5);
+    t.values.drain(..5);
+    assert_eq!(
+        t.verify_contract(),
+        Err(ContractViolation::SkipListEntry(0))
+    );
+
+    let mut t = table;
+    t.keys.truncate(100);
+    t.positions.truncate(100);
+    t.values.truncate(100);
+    assert_eq!(t.verify_contract(), Err(ContractViolation::SkipListLayout));
+    t.rebuild();
+    assert_eq!(t.verify_contract(), Ok(()));
+}
+
+#[test]
+fn find_in_range_reports_each_item_once() {
+    let mut rng = rand::thread_rng();
+    // few distinct positions, so most keys are shared by many items and the split points often
+    // fall into runs of equal keys
+    let items = (0..4000)
+        .map(|i| {
+            let p = Point::new(rng.gen_range(0, 64) * 8, rng.gen_range(0, 64) * 8);
+            (p, Value(i))
+        })
+        .collect::<Vec<_>>();
+    let mut table = MortonTable::from_iterator(items.iter().cloned());
+    table
+        .insert(Point::new(POS_MASK, POS_MASK), Value(4000))
+        .unwrap();
+
+    let mut res = Vec::new();
+    for _ in 0..1000 {
+        let center = Point::new(rng.gen_range(0, 600), rng.gen_range(0, 600));
+        let radius = rng.gen_range(0, 200);
+        res.clear();
+        table.find_in_range(&center, radius, &mut res);
+
+        let found = res.iter().map(|(p, v)| (*p, v.0)).collect::<HashSet<_>>();
+        assert_eq!(found.len(), res.len(), "duplicates in the output");
+        let expected = items
+            .iter()
+            .filter(|(p, _)| p.dist_sq(&center) <= radius as u64 * radius as u64)
+            .map(|(p, v)| (*p, v.0))
+            .collect::<HashSet<_>>();
+        assert!(
+            found == expected,
+            "missed items around {:?} radius {}",
+            center,
+            radius
+        );
+    }
+}
+
+#[test]
+fn sequential_table_matches_parallel() {
+    let mut rng = rand::thread_rng();
+    let items = (0..5000)
+        .map(|i| {
+            (
+                Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000)),
+                Value(i),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let parallel = MortonTable::from_iterator(items.iter().cloned());
+    let mut sequential = MortonTable::new();
+    sequential.set_parallel(false);
+    sequential.extend(items.iter().cloned());
+    assert_eq!(sequential.verify_contract(), Ok(()));
+    assert_eq!(sequential.keys, parallel.keys);
+
+    let entries = |t: &MortonTable| {
+        let mut res = t
+            .positions
+            .iter()
+            .zip(t.values.iter())
+            .map(|(p, v)| (*p, v.0))
+            .collect::<Vec<_>>();
+        res.sort();
+        res
+    };
+    assert_eq!(entries(&sequential), entries(&parallel));
+
+    // the setting is kept by derived tables
+    let region = sequential.extract_region(&Point::new(0, 0), &Point::new(2000, 2000));
+    assert!(!region.parallel);
+}
+
+#[test]
+fn closest_pair_matches_brute_force() {
+    let mut rng = rand::thread_rng();
+    assert!(MortonTable::new().closest_pair().is_none());
+    let single = MortonTable::from_iterator(std::iter::once((Point::new(3, 4), Value(0))));
+    assert!(single.closest_pair().is_none());
+
+    for _ in 0..200 {
+        let len = rng.gen_range(2, 80);
+        let side = rng.gen_range(1, 3000);
+        let mut table = MortonTable::new();
+        for i in 0..len {
+            let p = Point::new(rng.gen_range(0, side), rng.gen_range(0, side));
+            table.insert(p, Value(i)).unwrap();
+        }
+
+        let mut expected = u64::MAX;
+        for (i, a) in table.positions.iter().enumerate() {
+            for b in table.positions[i + 1..].iter() {
+                expected = expected.min(a.dist_sq(b));
+            }
+        }
+
+        let ((a, va), (b, vb)) = table.closest_pair().unwrap();
+        assert_eq!(a.dist_sq(&b), expected);
+        assert_ne!(va, vb, "an item was paired with itself");
+        assert!(table.contains_key(&a));
+        assert!(table.contains_key(&b));
+    }
+}
+
+#[test]
+fn closest_pair_far_apart_in_morton_order() {
+    // the closest pair straddles the center of the space, so the pair is at the opposite ends
+    // of the Morton order with many items between them
+    let mut items = (0..64)
+        .map(|i| (Point::new(i * 200 % 16000, i * 300 % 16000 + 50), Value(i)))
+        .collect::<Vec<_>>();
+    items.push((Point::new(16383, 16383), Value(100)));
+    items.push((Point::new(16384, 16384), Value(101)));
+    let table = MortonTable::from_iterator(items.into_iter());
+
+    let ((a, va), (b, vb)) = table.closest_pair().unwrap();
+    assert_eq!((a, *va), (Point::new(16383, 16383), Value(100)));
+    assert_eq!((b, *vb), (Point::new(16384, 16384), Value(101)));
+}
+
+#[test]
+fn min_grid_distance_is_chebyshev() {
+    let a = MortonKey::new(3, 10);
+    let b = MortonKey::new(7, 2);
+    assert_eq!(min_grid_distance(a, b), 8);
+    assert_eq!(min_grid_distance(b, a), 8);
+    assert_eq!(min_grid_distance(a, a), 0);
+    let far = MortonKey::new(0xffff, 0);
+    assert_eq!(min_grid_distance(MortonKey::new(0, 0xffff), far), 0xffff);
+}
+
+#[test]
+fn cell_min_distance_matches_brute_force() {
+    let mut rng = rand::thread_rng();
+    for _ in 0..200 {
+        let depth = rng.gen_range(10, 17);
+        let p = Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15));
+        // a cell near the point, so the distances stay small
+        let near = Point::new(
+            (p[0] + rng.gen_range(0, 200)).min(0xffff),
+            (p[1] + rng.gen_range(0, 200)).min(0xffff),
+        );
+        let cell = cell_at_depth(&near, depth);
+
+        let (min, max) = cell_bounds(cell, depth);
+        let cell_points = (min[0]..max[0])
+            .flat_map(|x| (min[1]..max[1]).map(move |y| Point::new(x, y)))
+            .collect::<Vec<_>>();
+        let expected = cell_points
+            .iter()
+            .map(|q| p[0].abs_diff(q[0]).max(p[1].abs_diff(q[1])))
+            .min()
+            .unwrap();
+        let d = cell_min_distance(cell, depth, &p);
+        assert_eq!(d, expected);
+        // a lower bound of the Euclidean distance
+        let euclidean_sq = cell_points.iter().map(|q| p.dist_sq(q)).min().unwrap();
+        assert!(d as u64 * d as u64 <= euclidean_sq);
+    }
+
+    let p = Point::new(1234, 4321);
+    assert_eq!(cell_min_distance(cell_at_depth(&p, 5), 5, &p), 0);
+    assert_eq!(cell_min_distance(0, 0, &p), 0);
+}
+
+#[test]
+fn staged_items_are_visible_to_queries() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::from_iterator(
+        (0..1000).map(|i| (Point::new(i % 40 * 5, i / 40 * 5), Value(i))),
+    );
+    let mut items = table
+        .positions
+        .iter()
+        .cloned()
+        .zip(table.values.iter().cloned())
+        .collect::<Vec<_>>();
+
+    for i in 0..STAGING_CAPACITY as u32 * 3 + 17 {
+        let p = Point::new(rng.gen_range(0, 300), rng.gen_range(0, 300));
+        table.insert_staged(p, Value(1000 + i)).unwrap();
+        items.push((p, Value(1000 + i)));
+
+        assert!(table.contains_key(&p));
+        assert!(table.get_by_id(&p).is_some());
+        // the staging buffer is merged once it is full
+        assert!(table.staged.len() < STAGING_CAPACITY);
+
+        if i % 50 == 0 {
+            let center = Point::new(rng.gen_range(0, 300), rng.gen_range(0, 300));
+            let radius = rng.gen_range(0, 100);
+            let mut res = Vec::new();
+            table.find_in_range(&center, radius, &mut res);
+            let mut found = res.iter().map(|(p, v)| (*p, v.0)).collect::<Vec<_>>();
+            found.sort();
+            let mut expected = items
+                .iter()
+                .filter(|(p, _)| p.dist_sq(&center) <= radius as u64 * radius as u64)
+                .map(|(p, v)| (*p, v.0))
+                .collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(found, expected);
+        }
+    }
+    assert!(!table.staged.is_empty());
+    assert_eq!(
+        table.insert_staged(Point::new(1 << 15, 0), Value(0)),
+        Err(Point::new(1 << 15, 0))
+    );
+
+    table.compact();
+    assert!(table.staged.is_empty());
+    assert_eq!(table.verify_contract(), Ok(()));
+    assert_eq!(table.keys.len(), items.len());
+    let mut merged = table
+        .positions
+        .iter()
+        .zip(table.values.iter())
+        .map(|(p, v)| (*p, v.0))
+        .collect::<Vec<_>>();
+    merged.sort();
+    let mut expected = items.iter().map(|(p, v)| (*p, v.0)).collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(merged, expected);
+}
+
+#[test]
+fn delete_and_clear_see_staged_items() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(1, 1), Value(1)).unwrap();
+    table.insert_staged(Point::new(2, 2), Value(2)).unwrap();
+    table.insert_staged(Point::new(0, 0), Value(0)).unwrap();
+
+    assert_eq!(table.delete(&Point::new(2, 2)), Some(Value(2)));
+    assert_eq!(table.keys.len(), 2);
+    assert_eq!(table.verify_contract(), Ok(()));
+
+    table.insert_staged(Point::new(3, 3), Value(3)).unwrap();
+    table.clear();
+    assert!(!table.contains_key(&Point::new(3, 3)));
+    let mut res = Vec::new();
+    table.find_in_range(&Point::new(0, 0), 100, &mut res);
+    assert!(res.is_empty());
+}
+
+#[test]
+fn queries_agree_on_staged_items() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::from_iterator(
+        (0..500).map(|i| (Point::new(i % 25 * 8, i / 25 * 8), Value(i))),
+    );
+    for i in 0..100 {
+        let p = Point::new(rng.gen_range(0, 200), rng.gen_range(0, 200));
+        table.insert_staged(p, Value(1000 + i)).unwrap();
+    }
+    let staged = Point::new(205, 99);
+    table.insert_staged(staged, Value(42)).unwrap();
+    let mut compacted = table.clone();
+    compacted.compact();
+    assert!(!table.staged.is_empty());
+
+    let sorted = |res: Vec<(Point, &Value)>| {
+        let mut res = res.into_iter().map(|(p, v)| (p, v.0)).collect::<Vec<_>>();
+        res.sort();
+        res
+    };
+    for _ in 0..20 {
+        let center = Point::new(rng.gen_range(0, 200), rng.gen_range(0, 200));
+        let radius = rng.gen_range(0, 60);
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+
+        table.find_in_range(&center, radius, &mut a);
+        let iterated = table.iter_in_range(&center, radius).collect::<Vec<_>>();
+        assert_eq!(iterated, a);
+        compacted.find_in_range(&center, radius, &mut b);
+        assert_eq!(sorted(a), sorted(b));
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        table.find_in_range_sorted(&center, radius, &mut a);
+        compacted.find_in_range_sorted(&center, radius, &mut b);
+        let dists = |res: &[(Point, &Value)]| {
+            res.iter()
+                .map(|(p, _)| p.dist_sq(&center))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(dists(&a), dists(&b));
+        assert_eq!(sorted(a), sorted(b));
+
+        for metric in [Metric::Euclidean, Metric::Chebyshev, Metric::Manhattan].iter() {
+            let (mut a, mut b) = (Vec::new(), Vec::new());
+            table.find_in_range_metric(&center, radius, *metric, &mut a);
+            compacted.find_in_range_metric(&center, radius, *metric, &mut b);
+            assert_eq!(sorted(a), sorted(b));
+        }
+
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        table.find_in_ring(&center, radius / 2, radius, &mut a);
+        compacted.find_in_ring(&center, radius / 2, radius, &mut b);
+        assert_eq!(sorted(a), sorted(b));
+
+        let nearest = |res: [Option<(Point, Value)>; 5]| {
+            res.iter()
+                .map(|item| item.map(|(p, _)| p.dist_sq(&center)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            nearest(table.nearest_into(&center)),
+            nearest(compacted.nearest_into(&center))
+        );
+    }
+
+    let mut res = Vec::new();
+    table.find_in_range_limited(&staged, 0, 10, &mut res);
+    assert_eq!(res, vec![(staged, &Value(42))]);
+    assert_eq!(table.get_entry(&staged), Some((&staged, &Value(42))));
+    assert_eq!(
+        table.insert_unique(staged, Value(0)),
+        Err(InsertError::Occupied(Value(42)))
+    );
+    assert_eq!(table.neighbors_4(&Point::new(204, 99))[1], Some(&Value(42)));
+    assert_eq!(
+        table
+            .extract_region(&Point::new(200, 90), &Point::new(210, 110))
+            .get_by_id(&staged),
+        Some(&Value(42))
+    );
+
+    let mut buf = Vec::new();
+    table.write_to(&mut buf).unwrap();
+    let read = MortonTable::read_from(&mut buf.as_slice()).unwrap();
+    assert_eq!(read.keys, compacted.keys);
+    assert_eq!(read.values, compacted.values);
+}
+
+#[test]
+fn mutators_see_staged_items() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(1, 1), Value(1)).unwrap();
+    table.insert_staged(Point::new(2, 2), Value(2)).unwrap();
+    table.insert_staged(Point::new(0, 0), Value(0)).unwrap();
+
+    let evicted = table.translate([-1, 3]);
+    assert_eq!(evicted, vec![(Point::new(0, 0), Value(0))]);
+    assert!(table.staged.is_empty());
+    assert_eq!(table.verify_contract(), Ok(()));
+    assert_eq!(table.get_by_id(&Point::new(0, 4)), Some(&Value(1)));
+    assert_eq!(table.get_by_id(&Point::new(1, 5)), Some(&Value(2)));
+    assert!(!table.contains_key(&Point::new(2, 2)));
+
+    table.insert_staged(Point::new(7, 7), Value(7)).unwrap();
+    assert!(table.swap_values(&Point::new(0, 4), &Point::new(7, 7)));
+    assert_eq!(table.get_by_id(&Point::new(7, 7)), Some(&Value(1)));
+
+    table.insert_staged(Point::new(8, 8), Value(8)).unwrap();
+    table.map_values(|_, v| v.0 += 10);
+    assert_eq!(table.get_by_id(&Point::new(8, 8)), Some(&Value(18)));
+
+    table.insert_staged(Point::new(8, 8), Value(2)).unwrap();
+    table.dedup_by(|a, b| a.0 += b.0);
+    assert_eq!(table.keys.len(), 4);
+    assert_eq!(table.get_by_id(&Point::new(8, 8)), Some(&Value(20)));
+}
+
+#[test]
+fn drain_in_range_returns_removed_items() {
+    let mut rng = rand::thread_rng();
+    let items = (0..3000)
+        .map(|i| {
+            (
+                Point::new(rng.gen_range(0, 500), rng.gen_range(0, 500)),
+                Value(i),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut table = MortonTable::from_iterator(items.iter().cloned()).with_change_tracking();
+    table.take_dirty();
+
+    let center = Point::new(250, 250);
+    let radius = 120;
+    let drained = table.drain_in_range(&center, radius);
+    assert!(
+        drained.windows(2).all(|w| w[0].0 <= w[1].0),
+        "not in Morton order"
+    );
+
+    let inside = |p: &Point| p.dist_sq(&center) <= radius as u64 * radius as u64;
+    let mut expected = items
+        .iter()
+        .filter(|(p, _)| inside(p))
+        .map(|(p, v)| (*p, v.0))
+        .collect::<Vec<_>>();
+    expected.sort();
+    let mut found = drained.iter().map(|(p, v)| (*p, v.0)).collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(found, expected);
+
+    assert_eq!(table.verify_contract(), Ok(()));
+    assert_eq!(table.keys.len(), items.len() - drained.len());
+    assert!(table.positions.iter().all(|p| !inside(p)));
+    assert_eq!(table.take_dirty().len(), drained.len());
+
+    assert!(table.drain_in_range(&center, radius).is_empty());
+    // staged items are drained too
+    table.insert_staged(center, Value(9999)).unwrap();
+    assert_eq!(
+        table.drain_in_range(&center, 0),
+        vec![(center, Value(9999))]
+    );
+}
+
+#[test]
+fn contains_bitset_matches_contains_key() {
+    let mut rng = rand::thread_rng();
+    let mut table = MortonTable::from_iterator((0..2000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 100), rng.gen_range(0, 100)),
+            Value(i),
+        )
+    }));
+    table.insert_staged(Point::new(150, 150), Value(0)).unwrap();
+
+    let mut points = (0..300)
+        .map(|_| Point::new(rng.gen_range(0, 160), rng.gen_range(0, 160)))
+        .collect::<Vec<_>>();
+    points.push(Point::new(150, 150));
+    points.push(Point::new(1 << 15, 0));
+    points.push(points[0]);
+
+    let bits = table.contains_bitset(&points);
+    assert_eq!(bits.len(), points.len().div_ceil(64));
+    for (i, p) in points.iter().enumerate() {
+        let bit = bits[i / 64] >> (i % 64) & 1 == 1;
+        assert_eq!(bit, table.contains_key(p), "{:?}", p);
+    }
+    // no bits past the end
+    assert_eq!(bits[bits.len() - 1] >> (points.len() % 64), 0);
+
+    assert!(table.contains_bitset(&[]).is_empty());
+}
+
+#[test]
+fn retain_in_range_only_filters_inside_the_circle() {
+    let mut rng = rand::thread_rng();
+    let items = (0..3000)
+        .map(|i| {
+            (
+                Point::new(rng.gen_range(0, 500), rng.gen_range(0, 500)),
+                Value(i),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut table = MortonTable::from_iterator(items.iter().cloned());
+
+    let center = Point::new(200, 300);
+    let radius = 150;
+    let inside = |p: &Point| p.dist_sq(&center) <= radius as u64 * radius as u64;
+    let mut calls = 0;
+    // keep the odd values
+    table.retain_in_range(&center, radius, |p, v| {
+        assert!(inside(p), "keep called for {:?}", p);
+        calls += 1;
+        v.0 % 2 == 1
+    });
+    assert_eq!(calls, items.iter().filter(|(p, _)| inside(p)).count());
+
+    let mut expected = items
+        .iter()
+        .filter(|(p, v)| !inside(p) || v.0 % 2 == 1)
+        .map(|(p, v)| (*p, v.0))
+        .collect::<Vec<_>>();
+    expected.sort();
+    let mut found = table
+        .positions
+        .iter()
+        .zip(table.values.iter())
+        .map(|(p, v)| (*p, v.0))
+        .collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(found, expected);
+    assert_eq!(table.verify_contract(), Ok(()));
+}
+
+#[test]
+fn sort_indirect_matches_quicksort() {
+    let mut rng = rand::thread_rng();
+
+    for len in [0, 1, 2, 100, 5000].iter() {
+        let points = (0..*len)
+            .map(|_| Point::new(rng.gen_range(0, 1 << 15), rng.gen_range(0, 1 << 15)))
+            .collect::<Vec<_>>();
+        let keys = points
+            .iter()
+            .map(|p| MortonKey::new(p[0] as u16, p[1] as u16))
+            .collect::<Vec<_>>();
+        // a large value that is not `Copy`
+        let values = (0..*len).map(|i| vec![i; 4]).collect::<Vec<_>>();
+
+        let mut expected = keys.clone();
+        expected.sort();
+        let (mut k, mut p, mut v) = (keys.clone(), points.clone(), values.clone());
+        sorting::sort_indirect(&mut k, &mut p, &mut v);
+
+        assert_eq!(k, expected);
+        // the positions and values moved along with their keys
+        for ((k, p), v) in k.iter().zip(p.iter()).zip(v.iter()) {
+            assert_eq!(*k, MortonKey::new(p[0] as u16, p[1] as u16));
+            assert_eq!(*p, points[v[0] as usize]);
+        }
+    }
+}
+
+#[test]
+fn find_in_range_multi_matches_single_queries() {
+    let mut rng = rand::thread_rng();
+    let table = MortonTable::from_iterator((0..3000).map(|i| {
+        (
+            Point::new(rng.gen_range(0, 2000), rng.gen_range(0, 2000)),
+            Value(i),
+        )
+    }));
+    let centers = (0..500)
+        .map(|_| Point::new(rng.gen_range(0, 2000), rng.gen_range(0, 2000)))
+        .collect::<Vec<_>>();
+
+    // stale results from a previous call are overwritten
+    let mut out = vec![vec![(Point::new(0, 0), &Value(0))]; 600];
+    table.find_in_range_multi(&centers, 100, &mut out);
+    assert_eq!(out.len(), centers.len());
+    let mut expected = Vec::new();
+    for (center, res) in centers.iter().zip(out.iter()) {
+        expected.clear();
+        table.find_in_range(center, 100, &mut expected);
+        assert_eq!(res, &expected);
+    }
+
+    table.find_in_range_multi(&[], 100, &mut out);
+    assert!(out.is_empty());
+}
+
+#[test]
+fn morton_sort_by_key_sorts_user_structs() {
+    #[derive(Debug, PartialEq)]
+    struct Entity {
+        pos: Point,
+        name: String,
+    }
+
+    let mut rng = rand::thread_rng();
+    for len in [0, 1, 2, 100, 3000].iter() {
+        let mut entities = (0..*len)
+            .map(|i| Entity {
+                pos: Point::new(rng.gen_range(0, 0x10000), rng.gen_range(0, 0x10000)),
+                name: format!("entity {}", i),
+            })
+            .collect::<Vec<_>>();
+        let mut expected = entities
+            .iter()
+            .map(|e| (e.pos.to_morton_u32(), e.name.clone()))
+            .collect::<Vec<_>>();
+        expected.sort();
+
+        sorting::morton_sort_by_key(&mut entities, |e| e.pos);
+
+        assert!(entities
+            .windows(2)
+            .all(|w| w[0].pos.to_morton_u32() <= w[1].pos.to_morton_u32()));
+        // the same entities, each still in one piece
+        let mut found = entities
+            .iter()
+            .map(|e| (e.pos.to_morton_u32(), e.name.clone()))
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+#[should_panic]
+fn morton_sort_by_key_rejects_large_positions() {
+    let mut points = vec![Point::new(1, 1), Point::new(0x10000, 0)];
+    sorting::morton_sort_by_key(&mut points, |p| *p);
+}
+
+#[test]
+fn find_in_range_wrapped_finds_items_across_the_edges() {
+    let mut table = MortonTable::new();
+    table.insert(Point::new(32767, 32767), Value(0)).unwrap();
+    table.insert(Point::new(3, 32766), Value(1)).unwrap();
+    table.insert(Point::new(32765, 4), Value(2)).unwrap();
+    table.insert(Point::new(10, 10), Value(3)).unwrap();
+    table.insert(Point::new(16000, 16000), Value(4)).unwrap();
+
+    let mut res = Vec::new();
+    table.find_in_range_wrapped(&Point::new(1, 1), 5, &mut res);
+    let mut values = res.iter().map(|(_, v)| v.0).collect::<Vec<_>>();
+    values.sort();
+    assert_eq!(values, vec![0, 1, 2]);
+    // the plain query does not wrap
+    res.clear();
+    table.find_in_range(&Point::new(1, 1), 5, &mut res);
+    assert!(res.is_empty());
+}
+
+#[test]
+fn find_in_range_wrapped_matches_brute_force() {
+    let mut rng = rand::thread_rng();
+    let size = POS_MASK + 1;
+    // cluster the items around the edges and corners
+    let items = (0..3000)
+        .map(|i| {
+            let axis = |rng: &mut ThreadRng| (rng.gen_range(0, 400) + size - 200) % size;
+            (Point::new(axis(&mut rng), axis(&mut rng)), Value(i))
+        })
+        .collect::<Vec<_>>();
+    let table = MortonTable::from_iterator(items.iter().cloned());
+
+    let wrapped_dist_sq = |a: &Point, b: &Point| {
+        let axis = |a: u32, b: u32| {
+            let d = a.abs_diff(b);
+            d.min(size - d) as u64
+        };
+        let [x, y] = [axis(a[0], b[0]), axis(a[1], b[1])];
+        x * x + y * y
+    };
+
+    let mut res = Vec::new();
+    for _ in 0..300 {
+        let center = Point::new(
+            (rng.gen_range(0, 400) + size - 200) % size,
+            (rng.gen_range(0, 400) + size - 200) % size,
+        );
+        let radius = rng.gen_range(0, 300);
+        res.clear();
+        table.find_in_range_wrapped(&center, radius, &mut res);
+
+        let found = res.iter().map(|(p, v)| (*p, v.0)).collect::<HashSet<_>>();
+        assert_eq!(found.len(), res.len(), "duplicates in the output");
+        let expected = items
+            .iter()
+            .filter(|(p, _)| wrapped_dist_sq(p, &center) <= radius as u64 * radius as u64)
+            .map(|(p, v)| (*p, v.0))
+            .collect::<HashSet<_>>();
+        assert!(found == expected, "{:?} {}", center, radius);
+    }
+
+    // a circle larger than the world finds every item once
+    res.clear();
+    table.find_in_range_wrapped(&Point::new(5, 5), size, &mut res);
+    assert_eq!(res.len(), items.len());
+}
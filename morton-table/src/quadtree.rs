@@ -1,46 +1,52 @@
-use crate::{Point, Value};
+use crate::codec::morton_encode_2d;
+use crate::morton_table::morton_key::MortonKey;
+use crate::morton_table::MortonTable;
+use crate::{bounds, Point, Value};
 use arrayvec::ArrayVec;
 
 const LEN_CHILDREN: usize = 16;
 
-type Children = Box<[Quadtree; 4]>;
+type Children<T> = Box<[Quadtree<T>; 4]>;
 
 #[derive(Debug, Clone)]
-pub enum Body {
-    Children(Children),
-    Items(Box<ArrayVec<[(Point, Value); LEN_CHILDREN]>>),
+pub enum Body<T> {
+    Children(Children<T>),
+    Items(Box<ArrayVec<[(Point, T); LEN_CHILDREN]>>),
+    /// Leaf of a node that is too small to be split, e.g. because many items share a coordinate.
+    Overflow(Vec<(Point, T)>),
 }
 
 #[derive(Debug, Clone)]
-pub struct Quadtree {
+pub struct Quadtree<T = Value> {
     // bounds as an AABB
     from: Point,
     to: Point,
 
     // public so I can flush the cache in benchmarks
-    pub body: Body,
+    pub body: Body<T>,
 }
 
-impl Default for Quadtree {
+impl<T> Default for Quadtree<T> {
     fn default() -> Self {
         Self::new(Point::new(0, 0), Point::new(0xffff, 0xffff))
     }
 }
 
-impl Quadtree {
+impl<T> Quadtree<T> {
     pub fn new(from: Point, to: Point) -> Self {
         assert!(from[0] <= to[0]);
         assert!(from[1] <= to[1]);
         Self {
             from,
             to,
-            body: Body::Items(Box::new(Default::default())),
+            body: Body::Items(Box::default()),
         }
     }
 
     pub fn clear(&mut self) {
         match &mut self.body {
             Body::Items(items) => items.clear(),
+            Body::Overflow(items) => items.clear(),
             Body::Children(children) => {
                 for child in children.iter_mut() {
                     child.clear();
@@ -50,36 +56,115 @@ impl Quadtree {
     }
     pub fn from_iterator<It>(it: It) -> Self
     where
-        It: Iterator<Item = (Point, Value)>,
+        It: Iterator<Item = (Point, T)>,
     {
+        let values = it.collect::<Vec<_>>();
         // calculate the minimum bounding box to speed up queries by having a more balanced tree
-        let mut min = [0xeeee, 0xeeee];
-        let mut max = [0, 0];
-        let values = it
-            .map(|(p, v)| {
-                min[0] = min[0].min(p[0]);
-                min[1] = min[1].min(p[1]);
-                max[0] = max[0].max(p[0]);
-                max[1] = max[1].max(p[1]);
-                (p, v)
-            })
-            .collect::<Vec<_>>();
-        let mut tree = Self::new(Point(min), Point(max));
+        let mut tree = match bounds::tight_bounds(&values) {
+            Some((min, max)) => Self::new(min, max),
+            None => Self::default(),
+        };
         tree.extend(values.into_iter());
         tree
     }
 
+    /// Bulk insert the items. Panics if an item is out of the bounds of the tree.
+    ///
+    /// The items are sorted in Morton order, then distributed top down: a full leaf is split
+    /// once and the items are partitioned between its children, instead of splitting and
+    /// reinserting for every item that overflows a leaf.
+    ///
+    /// Nodes always split at their center, so the shape of the tree only depends on the items
+    /// inserted and not on their order. Extending an empty tree also leaves the items of every
+    /// leaf in Morton order, regardless of the input order.
     pub fn extend<It>(&mut self, it: It)
     where
-        It: Iterator<Item = (Point, Value)>,
+        It: Iterator<Item = (Point, T)>,
     {
-        for (p, v) in it {
-            self.insert(p, v).unwrap();
+        let mut items = it.collect::<Vec<_>>();
+        if let Some((p, _)) = items.iter().find(|(p, _)| !self.intersects(p)) {
+            panic!("Point {:?} is out of the bounds of the tree", p);
+        }
+        // stable sort, so items of the same position keep their order
+        items.sort_by_cached_key(|(p, _)| morton_order(p));
+        self.bulk_insert(items);
+    }
+
+    /// The items must be in the bounds of this node.
+    fn bulk_insert(&mut self, mut items: Vec<(Point, T)>) {
+        match &mut self.body {
+            Body::Items(leaf) if leaf.len() + items.len() <= LEN_CHILDREN => {
+                leaf.extend(items);
+            }
+            Body::Items(_) => {
+                if self.can_split() {
+                    self.split();
+                } else {
+                    self.overflow();
+                }
+                self.bulk_insert(items);
+            }
+            Body::Overflow(leaf) => leaf.append(&mut items),
+            Body::Children(children) => {
+                let mut parts: [Vec<(Point, T)>; 4] = Default::default();
+                for (p, v) in items {
+                    // the first child that accepts the point, same as `insert`
+                    let i = children
+                        .iter()
+                        .position(|c| c.intersects(&p))
+                        .expect("All insertions failed");
+                    parts[i].push((p, v));
+                }
+                for (child, part) in children.iter_mut().zip(parts.iter_mut()) {
+                    if !part.is_empty() {
+                        child.bulk_insert(std::mem::take(part));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of levels of the tree, 1 if the root is a leaf.
+    pub fn depth(&self) -> usize {
+        match &self.body {
+            Body::Children(children) => 1 + children.iter().map(|c| c.depth()).max().unwrap_or(0),
+            _ => 1,
+        }
+    }
+
+    /// Number of `(internal, leaf)` nodes of the tree.
+    pub fn node_count(&self) -> (usize, usize) {
+        match &self.body {
+            Body::Children(children) => children.iter().fold((1, 0), |(i, l), c| {
+                let (ci, cl) = c.node_count();
+                (i + ci, l + cl)
+            }),
+            _ => (0, 1),
+        }
+    }
+
+    /// Bytes of memory used by the tree, including the root itself.
+    ///
+    /// Internal nodes own a box of their 4 children, bounded leaves a box of a full `ArrayVec`
+    /// regardless of how many items they hold, and overflowing leaves the capacity of their
+    /// `Vec`.
+    pub fn memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.heap_bytes()
+    }
+
+    fn heap_bytes(&self) -> usize {
+        match &self.body {
+            Body::Children(children) => {
+                std::mem::size_of::<[Self; 4]>()
+                    + children.iter().map(|c| c.heap_bytes()).sum::<usize>()
+            }
+            Body::Items(_) => std::mem::size_of::<ArrayVec<[(Point, T); LEN_CHILDREN]>>(),
+            Body::Overflow(items) => items.capacity() * std::mem::size_of::<(Point, T)>(),
         }
     }
 
     /// Returns `Err` if the insertion failed.
-    pub fn insert(&mut self, point: Point, value: Value) -> Result<(), Point> {
+    pub fn insert(&mut self, point: Point, value: T) -> Result<(), Point> {
         if !self.intersects(&point) {
             // point is out of bounds
             return Err(point);
@@ -87,18 +172,27 @@ impl Quadtree {
 
         match &mut self.body {
             Body::Items(items) => {
-                if let Ok(_) = items.try_push((point, value)) {
+                let (point, value) = match items.try_push((point, value)) {
                     // there was capacity left in this node. We're done.
-                    return Ok(());
+                    Ok(()) => return Ok(()),
+                    Err(err) => err.element(),
+                };
+                if self.can_split() {
+                    self.split();
+                } else {
+                    self.overflow();
                 }
-                self.split();
-                return self.insert(point, value);
+                self.insert(point, value)
+            }
+            Body::Overflow(items) => {
+                items.push((point, value));
+                Ok(())
             }
             Body::Children(children) => {
                 for c in children.iter_mut() {
-                    if let Ok(()) = c.insert(point, value) {
-                        // Return when we found a child that can accept this node.
-                        return Ok(());
+                    if c.intersects(&point) {
+                        // Insert into the first child that can accept this node.
+                        return c.insert(point, value);
                     }
                 }
 
@@ -111,20 +205,24 @@ impl Quadtree {
     }
 
     pub fn intersects(&self, point: &Point) -> bool {
-        let [x, y] = **point;
-
-        self.from[0] <= x && self.from[1] <= y && x <= self.to[0] && y <= self.to[1]
+        bounds::contains(&self.from, &self.to, point)
     }
 
     pub fn intersects_aabb(&self, from: &Point, to: &Point) -> bool {
-        // separating axis test
-        if self.to[0] < from[0] || self.from[0] > to[0] {
-            return false;
-        }
-        if self.to[1] < from[1] || self.from[1] > to[1] {
-            return false;
-        }
-        true
+        bounds::intersects(&self.from, &self.to, from, to)
+    }
+
+    fn can_split(&self) -> bool {
+        bounds::can_split(&self.from, &self.to)
+    }
+
+    /// Turn a full leaf that can't be split into an unbounded one.
+    fn overflow(&mut self) {
+        let items = match &mut self.body {
+            Body::Items(items) => items.drain(..).collect(),
+            _ => unreachable!("Trying to overflow a node that's not a bounded leaf"),
+        };
+        self.body = Body::Overflow(items);
     }
 
     fn split(&mut self) {
@@ -172,21 +270,18 @@ impl Quadtree {
         }
     }
 
-    pub fn find_in_range<'a>(
-        &'a self,
-        center: &Point,
-        radius: u32,
-        out: &mut Vec<&'a (Point, Value)>,
-    ) {
-        // calculat ethe bounding box of the circle
+    pub fn find_in_range<'a>(&'a self, center: &Point, radius: u32, out: &mut Vec<&'a (Point, T)>) {
+        // calculate the bounding box of the circle
+        // saturate at the edges of the coordinate space, the bounds of the tree are enforced by
+        // `intersects_aabb`
         let aabb = [
             Point::new(
-                center[0].checked_sub(radius).unwrap_or(0),
-                center[1].checked_sub(radius).unwrap_or(0),
+                center[0].saturating_sub(radius),
+                center[1].saturating_sub(radius),
             ),
             Point::new(
-                center[0].checked_add(radius).unwrap_or(0xffff),
-                center[1].checked_add(radius).unwrap_or(0xffff),
+                center[0].saturating_add(radius),
+                center[1].saturating_add(radius),
             ),
         ];
 
@@ -198,82 +293,149 @@ impl Quadtree {
         center: &Point,
         radius: u32,
         aabb: &[Point; 2],
-        out: &mut Vec<&'a (Point, Value)>,
+        out: &mut Vec<&'a (Point, T)>,
     ) {
         if !self.intersects_aabb(&aabb[0], &aabb[1]) {
             // if the node does not contain the aabb, then it can't intersect this circle either
             return;
         }
 
-        match &self.body {
-            Body::Items(items) => {
-                // insert all items that are within the circle
-                for p in items.iter() {
-                    if p.0.dist(center) <= radius {
-                        out.push(p);
-                    }
-                }
-            }
+        let items: &'a [(Point, T)] = match &self.body {
+            Body::Items(items) => items,
+            Body::Overflow(items) => items,
             Body::Children(children) => {
                 // if the node has children then repeat the procedure for all children
                 for child in children.iter() {
                     child.find_in_range_impl(center, radius, aabb, out);
                 }
+                return;
+            }
+        };
+        // insert all items that are within the circle
+        let radius_sq = radius as u64 * radius as u64;
+        for p in items.iter() {
+            if p.0.dist_sq(center) <= radius_sq {
+                out.push(p);
             }
         }
     }
 
-    pub fn get_by_id<'a>(&'a self, point: &Point) -> Option<&'a Value> {
+    pub fn get_by_id<'a>(&'a self, point: &Point) -> Option<&'a T> {
         if !self.intersects(point) {
             return None;
         }
 
-        match &self.body {
-            Body::Items(items) => {
-                for p in items.iter() {
-                    if p.0 == *point {
-                        return Some(&p.1);
-                    }
-                }
-            }
+        let items: &'a [(Point, T)] = match &self.body {
+            Body::Items(items) => items,
+            Body::Overflow(items) => items,
             Body::Children(children) => {
                 for child in children.iter() {
                     if let Some(v) = child.get_by_id(point) {
                         return Some(v);
                     }
                 }
+                return None;
+            }
+        };
+        for p in items.iter() {
+            if p.0 == *point {
+                return Some(&p.1);
             }
         }
         None
     }
 
-    pub fn contains_key(&self, point: &Point) -> bool {
+    pub fn get_by_id_mut<'a>(&'a mut self, point: &Point) -> Option<&'a mut T> {
         if !self.intersects(point) {
-            return false;
+            return None;
         }
-        match &self.body {
-            Body::Items(items) => {
-                // if this node contains this point then we're done
-                for p in items.iter() {
-                    if p.0 == *point {
-                        return true;
+
+        let items: &'a mut [(Point, T)] = match &mut self.body {
+            Body::Items(items) => items,
+            Body::Overflow(items) => items,
+            Body::Children(children) => {
+                for child in children.iter_mut() {
+                    if let Some(v) = child.get_by_id_mut(point) {
+                        return Some(v);
                     }
                 }
+                return None;
             }
+        };
+        items
+            .iter_mut()
+            .find(|p| p.0 == *point)
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, point: &Point) -> bool {
+        if !self.intersects(point) {
+            return false;
+        }
+        let items: &[(Point, T)] = match &self.body {
+            Body::Items(items) => items,
+            Body::Overflow(items) => items,
             Body::Children(children) => {
                 // this node did not contain the key
                 // check the children, if any
-                for child in children.iter() {
-                    if child.contains_key(point) {
-                        return true;
-                    }
+                return children.iter().any(|child| child.contains_key(point));
+            }
+        };
+        // if this node contains this point then we're done
+        items.iter().any(|p| p.0 == *point)
+    }
+}
+
+impl Quadtree<Value> {
+    /// Copy the items into a `MortonTable`. Panics if an item is out of the bounds of the table.
+    ///
+    /// The leaves are visited depth first, visiting the children of a node in Z-order, so the
+    /// items come out roughly in Morton order. Roughly, because the nodes split at the center
+    /// of their bounds, which are not aligned to the cells of the Z curve. The table skips its
+    /// sort if the items come out sorted, but in practice they don't: for 2^15 random items
+    /// loaded by `from_iterator` about 89% of the neighbouring items are in order, yet almost
+    /// every item is out of its final place. The conversion costs about as much as building the
+    /// table from the items in random order, see the `quadtree_to_morton_table` benchmark.
+    pub fn to_morton_table(&self) -> MortonTable {
+        let mut items = Vec::new();
+        self.collect_z_order(&mut items);
+        let mut table = MortonTable::new();
+        if let Some((p, _)) = items.iter().find(|(p, _)| !table.intersects(p)) {
+            panic!("Point {:?} is out of the bounds of the table", p);
+        }
+        table.keys = items
+            .iter()
+            .map(|(p, _)| MortonKey::new(p[0] as u16, p[1] as u16))
+            .collect();
+        table.positions = items.iter().map(|(p, _)| *p).collect();
+        table.values = items.iter().map(|(_, v)| *v).collect();
+        // skips the sort if the items came out sorted
+        table.rebuild();
+        table
+    }
+
+    fn collect_z_order(&self, out: &mut Vec<(Point, Value)>) {
+        match &self.body {
+            Body::Items(items) => out.extend_from_slice(items),
+            Body::Overflow(items) => out.extend_from_slice(items),
+            Body::Children(children) => {
+                // see `split` for the layout of the children
+                for i in [3, 0, 2, 1].iter() {
+                    children[*i].collect_z_order(out);
                 }
             }
         }
-        false
     }
 }
 
+/// Sort key ordering points along the Z curve of the whole 32 bit coordinate space.
+fn morton_order(p: &Point) -> u64 {
+    let [x, y] = **p;
+    let hi = morton_encode_2d((x >> 16) as u16, (y >> 16) as u16);
+    let lo = morton_encode_2d(x as u16, y as u16);
+    (hi as u64) << 32 | lo as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -321,4 +483,257 @@ mod tests {
             assert_eq!(found, Some(&p.1),);
         }
     }
+
+    #[test]
+    fn find_in_range_beyond_16_bits() {
+        let from = Point::new(0x1_0000, 0x1_0000);
+        let to = Point::new(0x2_0000, 0x2_0000);
+        let mut table = Quadtree::new(from, to);
+
+        for i in 0..64 {
+            let p = Point::new(0x1_8000 + i, 0x1_8000 + i);
+            table.insert(p, Value(i)).unwrap();
+        }
+
+        let mut res = Vec::new();
+        table.find_in_range(&Point::new(0x1_8000, 0x1_8000), 128, &mut res);
+
+        assert_eq!(res.len(), 64);
+    }
+
+    #[test]
+    fn find_in_range_saturates_at_the_edge() {
+        let from = Point::new(u32::MAX - 0xff, u32::MAX - 0xff);
+        let to = Point::new(u32::MAX, u32::MAX);
+        let mut table = Quadtree::new(from, to);
+
+        for i in 0..16 {
+            let p = Point::new(u32::MAX - i, u32::MAX - i);
+            table.insert(p, Value(i)).unwrap();
+        }
+
+        let mut res = Vec::new();
+        table.find_in_range(&Point::new(u32::MAX - 2, u32::MAX - 2), 32, &mut res);
+
+        assert_eq!(res.len(), 16);
+    }
+
+    #[test]
+    fn non_copy_payload() {
+        let mut table = Quadtree::new(Point::new(0, 0), Point::new(128, 128));
+
+        for i in 0..64 {
+            let p = Point::new(i * 2, 128 - i * 2);
+            table.insert(p, format!("{}", i)).unwrap();
+        }
+
+        for i in 0..64 {
+            let found = table.get_by_id(&Point::new(i * 2, 128 - i * 2));
+            assert_eq!(found, Some(&format!("{}", i)));
+        }
+    }
+
+    #[test]
+    fn coincident_points_do_not_split_forever() {
+        let mut table = Quadtree::new(Point::new(0, 0), Point::new(128, 128));
+
+        for i in 0..100 {
+            table.insert(Point::new(5, 5), Value(i)).unwrap();
+        }
+        table.insert(Point::new(6, 5), Value(100)).unwrap();
+
+        let mut res = Vec::new();
+        table.find_in_range(&Point::new(5, 5), 0, &mut res);
+        assert_eq!(res.len(), 100);
+
+        assert_eq!(table.get_by_id(&Point::new(6, 5)), Some(&Value(100)));
+        assert!(table.contains_key(&Point::new(5, 5)));
+    }
+
+    #[test]
+    fn get_by_id_mut() {
+        let mut table = Quadtree::new(Point::new(0, 0), Point::new(128, 128));
+
+        for i in 0..64 {
+            table.insert(Point::new(i * 2, i), Value(i)).unwrap();
+        }
+
+        for i in 0..64 {
+            let value = table.get_by_id_mut(&Point::new(i * 2, i)).unwrap();
+            value.0 += 1000;
+        }
+        assert_eq!(table.get_by_id_mut(&Point::new(1, 1)), None);
+
+        for i in 0..64 {
+            let found = table.get_by_id(&Point::new(i * 2, i));
+            assert_eq!(found, Some(&Value(i + 1000)));
+        }
+    }
+
+    #[test]
+    fn shape_does_not_depend_on_insertion_order() {
+        let mut rng = rand::thread_rng();
+
+        let mut items = (0..2048)
+            .map(|i| {
+                // clustered around the origin
+                let p = Point::new(rng.gen_range(0, 64), rng.gen_range(0, 64));
+                (p, Value(i))
+            })
+            .collect::<Vec<_>>();
+        items.push((Point::new(0x1ffff, 0x1ffff), Value(9999)));
+
+        let mut bulk = Quadtree::new(Point::new(0, 0), Point::new(0x20000, 0x20000));
+        bulk.extend(items.iter().cloned());
+
+        let mut one_by_one = Quadtree::new(Point::new(0, 0), Point::new(0x20000, 0x20000));
+        for (p, v) in items.iter().rev() {
+            one_by_one.insert(*p, *v).unwrap();
+        }
+
+        assert_eq!(bulk.depth(), one_by_one.depth());
+        assert_eq!(bulk.node_count(), one_by_one.node_count());
+        assert!(bulk.depth() > 1);
+        for (p, _) in items.iter() {
+            assert!(bulk.contains_key(p));
+        }
+
+        let center = Point::new(32, 32);
+        for radius in [0, 5, 20, 0x30000].iter() {
+            let mut expected = Vec::new();
+            let mut res = Vec::new();
+            one_by_one.find_in_range(&center, *radius, &mut expected);
+            bulk.find_in_range(&center, *radius, &mut res);
+            expected.sort_by_key(|(p, v)| (morton_order(p), v.0));
+            res.sort_by_key(|(p, v)| (morton_order(p), v.0));
+            assert_eq!(res, expected);
+        }
+    }
+
+    #[test]
+    fn extend_keeps_the_leaves_in_morton_order() {
+        let mut rng = rand::thread_rng();
+
+        let mut items = (0..1000)
+            .map(|i| {
+                let p = Point::new(rng.gen_range(0, 1000), rng.gen_range(0, 1000));
+                (p, Value(i))
+            })
+            .collect::<Vec<_>>();
+
+        let mut a = Quadtree::new(Point::new(0, 0), Point::new(1000, 1000));
+        a.extend(items.iter().cloned());
+
+        items.shuffle(&mut rng);
+        let mut b = Quadtree::new(Point::new(0, 0), Point::new(1000, 1000));
+        b.extend(items.iter().cloned());
+
+        let mut a_items = Vec::new();
+        let mut b_items = Vec::new();
+        a.collect_z_order(&mut a_items);
+        b.collect_z_order(&mut b_items);
+        assert_eq!(a_items.len(), items.len());
+        // `b` only differs in the order of items sharing a position
+        let key = |(p, _): &(Point, Value)| morton_order(p);
+        assert_eq!(
+            a_items.iter().map(key).collect::<Vec<_>>(),
+            b_items.iter().map(key).collect::<Vec<_>>()
+        );
+
+        // extend a non-empty tree, splitting the existing leaves
+        let more = (0..100)
+            .map(|i| (Point::new(i * 3, 999 - i), Value(1000 + i)))
+            .collect::<Vec<_>>();
+        b.extend(more.iter().cloned());
+        for (p, _) in items.iter().chain(more.iter()) {
+            assert!(b.contains_key(p));
+        }
+        let mut res = Vec::new();
+        b.find_in_range(&Point::new(500, 500), 0x10000, &mut res);
+        assert_eq!(res.len(), items.len() + more.len());
+    }
+
+    #[test]
+    #[should_panic]
+    fn extend_out_of_bounds() {
+        let mut tree = Quadtree::new(Point::new(0, 0), Point::new(128, 128));
+        tree.extend(std::iter::once((Point::new(129, 0), Value(0))));
+    }
+
+    #[test]
+    fn from_iterator_empty() {
+        let tree = Quadtree::<Value>::from_iterator(std::iter::empty());
+        assert_eq!(tree.from, Point::new(0, 0));
+        assert_eq!(tree.to, Point::new(0xffff, 0xffff));
+        assert!(!tree.contains_key(&Point::new(0, 0)));
+    }
+
+    #[test]
+    fn from_iterator_large_coordinates() {
+        let items = [
+            (Point::new(0x10000, 0xf000), Value(0)),
+            (Point::new(0x12345, 0x20000), Value(1)),
+            (Point::new(0xfffff, 0xffff0), Value(2)),
+        ];
+        let tree = Quadtree::from_iterator(items.iter().cloned());
+
+        assert_eq!(tree.from, Point::new(0x10000, 0xf000));
+        assert_eq!(tree.to, Point::new(0xfffff, 0xffff0));
+        for (p, v) in items.iter() {
+            assert_eq!(tree.get_by_id(p), Some(v));
+        }
+    }
+    #[test]
+    fn to_morton_table() {
+        let mut rng = rand::thread_rng();
+
+        let items = (0..2048)
+            .map(|i| {
+                let p = Point::new(rng.gen_range(0, 4000), rng.gen_range(0, 4000));
+                (p, Value(i))
+            })
+            .collect::<Vec<_>>();
+        let tree = Quadtree::from_iterator(items.iter().cloned());
+        let table = tree.to_morton_table();
+
+        assert_eq!(table.keys().len(), items.len());
+        assert!(table.keys().windows(2).all(|w| w[0] <= w[1]));
+        for (p, _) in items.iter() {
+            assert!(table.contains_key(p));
+        }
+
+        let mut expected = Vec::new();
+        let mut res = Vec::new();
+        tree.find_in_range(&Point::new(2000, 2000), 500, &mut expected);
+        table.find_in_range(&Point::new(2000, 2000), 500, &mut res);
+        let expected = expected.into_iter().cloned().collect::<HashSet<_>>();
+        let res = res
+            .into_iter()
+            .map(|(p, v)| (p, *v))
+            .collect::<HashSet<_>>();
+        assert_eq!(res, expected);
+    }
+    #[test]
+    fn memory_bytes() {
+        use std::mem::size_of;
+
+        let node = size_of::<Quadtree>();
+        let leaf = size_of::<ArrayVec<[(Point, Value); LEN_CHILDREN]>>();
+
+        let mut tree = Quadtree::new(Point::new(0, 0), Point::new(128, 128));
+        assert_eq!(tree.node_count(), (0, 1));
+        assert_eq!(tree.memory_bytes(), node + leaf);
+
+        for i in 0..=LEN_CHILDREN as u32 {
+            tree.insert(Point::new(i * 7, i * 7), Value(i)).unwrap();
+        }
+        let (internal, leaves) = tree.node_count();
+        assert!(internal >= 1);
+        assert_eq!(internal * 4 + 1, internal + leaves);
+        // every node but the root is boxed with its siblings
+        assert_eq!(
+            tree.memory_bytes(),
+            (internal + leaves) * node + leaves * leaf
+        );
+    }
 }